@@ -0,0 +1,350 @@
+use crate::config::Config;
+use crate::document::{commit, commit_with_files, content_hash, DatabaseDoc};
+use crate::failpoint;
+use crate::filetype::FileType;
+use crate::store::{StagedFile, StagedRemoval};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, FromRow, Sqlite, SqliteConnection, SqlitePool};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// What an attached file is to the document it belongs to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentRole {
+    /// Supplementary material, appendices and errata.
+    #[default]
+    Supplement,
+    Slides,
+    Dataset,
+    Code,
+    Other,
+}
+
+impl AttachmentRole {
+    /// Name stored in the `role` column.
+    pub fn name(&self) -> String {
+        return self
+            .to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default();
+    }
+}
+
+impl std::fmt::Display for AttachmentRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}", self.name());
+    }
+}
+
+impl TryFrom<String> for AttachmentRole {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        return Self::from_str(&value, true)
+            .map_err(|_| anyhow::anyhow!("Unknown attachment role: {:?}", value));
+    }
+}
+
+/// An attachment of a document in a `doc add from-toml` file.
+#[derive(Debug, Hash, Deserialize, Serialize)]
+pub struct AttachmentFile {
+    #[serde(default)]
+    pub role: AttachmentRole,
+    /// Original filename to record instead of the file name of `path`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub filename: String,
+    pub path: PathBuf,
+}
+
+/// Which attachment of a document to use: an attachment id, or the first attachment with a
+/// role.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttachmentSelector {
+    Id(u32),
+    Role(AttachmentRole),
+}
+
+impl std::str::FromStr for AttachmentSelector {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse::<u32>() {
+            return Ok(Self::Id(id));
+        }
+        return AttachmentRole::try_from(s.to_string()).map(Self::Role);
+    }
+}
+
+/// An additional file stored with a document, such as slides or a dataset archive.
+#[derive(FromRow, Debug)]
+pub struct Attachment {
+    pub id: u32,
+    pub doc_id: u32,
+    #[sqlx(try_from = "String")]
+    pub role: AttachmentRole,
+    pub uuid: String,
+    /// Name of the file when it was attached.
+    pub filename: String,
+    pub mime: String,
+    pub content_hash: String,
+}
+
+impl std::fmt::Display for Attachment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(
+            f,
+            "{:>5}  {:10} {} ({})",
+            self.id, self.role, self.filename, self.mime
+        );
+    }
+}
+
+impl Attachment {
+    /// Name of the stored copy in the store directory.  The extension of the original file
+    /// is kept so openers configured by extension apply.
+    pub fn stored_name(&self) -> String {
+        return match Path::new(&self.filename).extension() {
+            Some(ext) => format!("{}.{}", self.uuid, ext.to_string_lossy()),
+            None => self.uuid.clone(),
+        };
+    }
+
+    pub fn stored_path(&self) -> anyhow::Result<PathBuf> {
+        let path = Config::get().stored_file(&self.stored_name());
+        if !path.is_file() {
+            return Err(anyhow::anyhow!(
+                "Attachment is not stored: {:?}",
+                self.filename
+            ));
+        }
+        return Ok(path);
+    }
+
+    pub async fn from_id<'e, E>(id: u32, executor: E) -> anyhow::Result<Option<Self>>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        return Ok(sqlx::query_as::<_, Self>(
+            r#"
+            SELECT * FROM attachments
+            WHERE id=?1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(executor)
+        .await?);
+    }
+
+    /// Every attachment of a document, in the order they were attached.
+    pub async fn for_doc<'e, E>(doc_id: u32, executor: E) -> anyhow::Result<Vec<Self>>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        return Ok(sqlx::query_as::<_, Self>(
+            r#"
+            SELECT * FROM attachments
+            WHERE doc_id=?1
+            ORDER BY id
+            "#,
+        )
+        .bind(doc_id)
+        .fetch_all(executor)
+        .await?);
+    }
+
+    /// Every attachment in the library.
+    pub async fn get_all(pool: &SqlitePool) -> anyhow::Result<Vec<Self>> {
+        return Ok(sqlx::query_as::<_, Self>(
+            r#"
+            SELECT * FROM attachments
+            ORDER BY id
+            "#,
+        )
+        .fetch_all(pool)
+        .await?);
+    }
+
+    /// The attachment of `doc` picked by `selector`.
+    pub async fn select(
+        doc: &DatabaseDoc,
+        selector: &AttachmentSelector,
+        pool: &SqlitePool,
+    ) -> anyhow::Result<Self> {
+        let found = Self::for_doc(doc.id, pool)
+            .await?
+            .into_iter()
+            .find(|a| match selector {
+                AttachmentSelector::Id(id) => a.id == *id,
+                AttachmentSelector::Role(role) => a.role == *role,
+            });
+        return found.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Document {} has no attachment matching {:?}",
+                doc.id,
+                selector
+            )
+        });
+    }
+
+    /// Copy the file at `path` into the store and attach it to `doc`.  The record and the
+    /// stored file are added together or not at all.
+    pub async fn from_insert(
+        doc: &DatabaseDoc,
+        path: &Path,
+        role: AttachmentRole,
+        pool: &SqlitePool,
+    ) -> anyhow::Result<Self> {
+        let mut tx = pool.begin().await?;
+        let (attachment, staged) = Self::insert_staged(doc.id, path, role, "", &mut tx).await?;
+        commit_with_files(tx, staged.into_iter().collect(), "attach:commit", pool).await?;
+        log::info!(
+            "Attachment {:?} stored as {:?}",
+            path,
+            Config::get().stored_file(&attachment.stored_name())
+        );
+        return Ok(attachment);
+    }
+
+    /// Attach the file at `path` to the document `doc_id` in the transaction of `conn`, with
+    /// the file copied into the store under a temporary name that must be persisted before
+    /// the transaction commits.  A file that is already attached to the document is returned
+    /// without a staged file.  `filename` is recorded instead of the name of `path` when set.
+    pub async fn insert_staged(
+        doc_id: u32,
+        path: &Path,
+        role: AttachmentRole,
+        filename: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<(Self, Option<StagedFile>)> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!(
+                "Path does not reference a file: {:?}",
+                path
+            ));
+        }
+        let hash = content_hash(path)?;
+        if let Some(existing) = Self::for_doc(doc_id, &mut *conn)
+            .await?
+            .into_iter()
+            .find(|a| a.content_hash == hash)
+        {
+            log::warn!(
+                "{:?} is already attached to document {} as {:?}",
+                path,
+                doc_id,
+                existing.filename
+            );
+            return Ok((existing, None));
+        }
+        let filename = match filename {
+            "" => path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            filename => filename.to_string(),
+        };
+        let id = sqlx::query(
+            r#"
+            INSERT INTO attachments (doc_id, role, uuid, filename, mime, content_hash)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "#,
+        )
+        .bind(doc_id)
+        .bind(role.name())
+        .bind(Uuid::new_v4().to_string())
+        .bind(&filename)
+        .bind(mime_type(Path::new(&filename)))
+        .bind(&hash)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+        failpoint::check("attach:row")?;
+        let attachment = match Self::from_id(id as u32, &mut *conn).await? {
+            Some(attachment) => attachment,
+            None => return Err(anyhow::anyhow!("Failed to create Attachment after insert.")),
+        };
+        let stored_path = Config::get().stored_file(&attachment.stored_name());
+        let staged = StagedFile::copy(path, &stored_path)?;
+        return Ok((attachment, Some(staged)));
+    }
+
+    /// Fail if the document the attachment belongs to is in the trash, where its files are
+    /// only handled by the `doc trash` commands.
+    pub async fn ensure_not_trashed<'e, E>(&self, executor: E) -> anyhow::Result<()>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        return match DatabaseDoc::from_id(self.doc_id, executor).await? {
+            Some(doc) => doc.ensure_not_trashed(),
+            None => Err(anyhow::anyhow!(
+                "Document {} of attachment {} does not exist",
+                self.doc_id,
+                self.id
+            )),
+        };
+    }
+
+    /// Remove the attachment record and its stored file.  The file is only deleted once the
+    /// record is, and is put back if removing the record fails.
+    pub async fn delete(self, pool: &SqlitePool) -> anyhow::Result<()> {
+        let mut tx = pool.begin().await?;
+        self.ensure_not_trashed(&mut *tx).await?;
+        sqlx::query(
+            r#"
+            DELETE FROM attachments
+            WHERE id=?1
+            "#,
+        )
+        .bind(self.id)
+        .execute(&mut *tx)
+        .await?;
+        failpoint::check("detach:row")?;
+
+        let asset_path = Config::get().stored_file(&self.stored_name());
+        let removal = if asset_path.exists() {
+            Some(StagedRemoval::new(&asset_path)?)
+        } else {
+            log::warn!("Could not delete {:?}: file is missing", asset_path);
+            None
+        };
+        commit(tx, "detach:commit", pool).await?;
+        if let Some(removal) = removal {
+            removal.finish();
+        }
+        log::info!("Attachment {} deleted: {:?}", self.id, self.filename);
+        return Ok(());
+    }
+}
+
+/// MIME type of a file, guessed from its extension.
+pub fn mime_type(path: &Path) -> String {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if let Some(file_type) = FileType::from_extension(&ext) {
+        return file_type.mime().to_string();
+    }
+    let mime = match ext.as_str() {
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "zst" => "application/zstd",
+        "ppt" => "application/vnd.ms-powerpoint",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "odp" => "application/vnd.oasis.opendocument.presentation",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+        "mp4" => "video/mp4",
+        "py" => "text/x-python",
+        "ipynb" => "application/x-ipynb+json",
+        _ => "application/octet-stream",
+    };
+    return mime.to_string();
+}
@@ -0,0 +1,586 @@
+use crate::document::{commit, normalized_key};
+use crate::failpoint;
+use sqlx::{Executor, FromRow, Sqlite, SqliteConnection, SqlitePool};
+
+/// A personal name split into BibTeX name parts.  Corporate names such as
+/// `{World Health Organization}` are kept whole in `last`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Name {
+    pub first: String,
+    /// Surname, including particles such as `van` or `de la`.
+    pub last: String,
+    /// Generational suffix such as `Jr.`.
+    pub suffix: String,
+}
+
+impl Name {
+    /// Parse a single name written `Last, First`, `Last, Suffix, First` or `First Last`.
+    pub fn parse(name: &str) -> Option<Self> {
+        let parts: Vec<String> = split_top_level(name, |c| c == ',')
+            .into_iter()
+            .map(|p| p.trim().to_string())
+            .collect();
+        let name = match parts.as_slice() {
+            [] => return None,
+            [full] => {
+                let words = split_top_level(full, char::is_whitespace)
+                    .into_iter()
+                    .filter(|w| !w.is_empty())
+                    .collect::<Vec<&str>>();
+                let (last, first) = match words.split_last() {
+                    Some((last, first)) => (*last, first),
+                    None => return None,
+                };
+                // Lowercase particles before the surname belong to it: `Ludwig van Beethoven`,
+                // unless the whole name is lowercase and the particles cannot be told apart
+                let particles = match words.first() {
+                    Some(w) if w.starts_with(char::is_uppercase) => first
+                        .iter()
+                        .rev()
+                        .take_while(|w| w.starts_with(char::is_lowercase))
+                        .count(),
+                    _ => 0,
+                };
+                let split = first.len() - particles;
+                let mut last_words = first[split..].to_vec();
+                last_words.push(last);
+                Self {
+                    first: unbrace(&first[..split].join(" ")),
+                    last: unbrace(&last_words.join(" ")),
+                    suffix: String::new(),
+                }
+            }
+            [last, first] => Self {
+                first: unbrace(first),
+                last: unbrace(last),
+                suffix: String::new(),
+            },
+            [last, suffix, first, ..] => Self {
+                first: unbrace(first),
+                last: unbrace(last),
+                suffix: unbrace(suffix),
+            },
+        };
+        if name.last.is_empty() {
+            return None;
+        }
+        return Some(name);
+    }
+
+    /// Parse a list of names separated by `and`, as in a BibTeX author field.
+    pub fn parse_list(names: &str) -> Vec<Self> {
+        let words = split_top_level(names, char::is_whitespace);
+        let mut current = Vec::new();
+        let mut parsed = Vec::new();
+        for word in words.into_iter().filter(|w| !w.is_empty()) {
+            if word.eq_ignore_ascii_case("and") {
+                parsed.extend(Self::parse(&current.join(" ")));
+                current.clear();
+            } else {
+                current.push(word);
+            }
+        }
+        parsed.extend(Self::parse(&current.join(" ")));
+        return parsed;
+    }
+
+    /// The name as written in a BibTeX author field: `Last, First` or `Last, Suffix, First`.
+    pub fn to_bibtex(&self) -> String {
+        let mut name = if self.first.is_empty() && self.last.contains(char::is_whitespace) {
+            format!("{{{}}}", self.last)
+        } else {
+            self.last.clone()
+        };
+        if !self.suffix.is_empty() {
+            name.push_str(", ");
+            name.push_str(&self.suffix);
+        }
+        if !self.first.is_empty() {
+            name.push_str(", ");
+            name.push_str(&self.first);
+        }
+        return name;
+    }
+
+    /// Key used to tell whether two names are spellings of the same author record.
+    pub fn key(&self) -> String {
+        return normalized_key(&self.to_bibtex());
+    }
+}
+
+impl std::fmt::Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}", self.to_bibtex());
+    }
+}
+
+/// Split `value` on characters matching `sep` that are not inside braces.
+fn split_top_level(value: &str, sep: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            c if depth == 0 && sep(c) => {
+                parts.push(&value[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    return parts;
+}
+
+fn unbrace(value: &str) -> String {
+    let value = value.trim();
+    return match value.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+        Some(inner) if !inner.contains(['{', '}']) => inner.trim().to_string(),
+        _ => value.to_string(),
+    };
+}
+
+/// An author record.  Documents link to authors in order through `document_authors`.
+#[derive(FromRow, Debug, Clone)]
+pub struct DatabaseAuthor {
+    pub id: u32,
+    pub first: String,
+    pub last: String,
+    pub suffix: String,
+}
+
+impl std::fmt::Display for DatabaseAuthor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "id {}: {}", self.id, self.name());
+    }
+}
+
+/// An author record with the number of documents linked to it.
+#[derive(FromRow, Debug)]
+pub struct AuthorCount {
+    #[sqlx(flatten)]
+    pub author: DatabaseAuthor,
+    pub documents: u32,
+}
+
+impl std::fmt::Display for AuthorCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{} ({} documents)", self.author, self.documents);
+    }
+}
+
+impl DatabaseAuthor {
+    pub fn name(&self) -> Name {
+        return Name {
+            first: self.first.clone(),
+            last: self.last.clone(),
+            suffix: self.suffix.clone(),
+        };
+    }
+
+    pub async fn from_id(id: u32, pool: &SqlitePool) -> anyhow::Result<Option<Self>> {
+        return Ok(sqlx::query_as::<_, Self>(
+            r#"
+            SELECT id, first, last, suffix FROM authors
+            WHERE id=?1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?);
+    }
+
+    /// The author record with this name, compared by normalized key, created if it does not
+    /// exist yet.  An existing record keeps the spelling it was created with.
+    pub async fn from_name(name: &Name, conn: &mut SqliteConnection) -> anyhow::Result<Self> {
+        let key = name.key();
+        if let Some(author) = Self::from_key(&key, &mut *conn).await? {
+            return Ok(author);
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO authors (first, last, suffix, name_key)
+            VALUES (?1, ?2, ?3, ?4)
+            "#,
+        )
+        .bind(&name.first)
+        .bind(&name.last)
+        .bind(&name.suffix)
+        .bind(&key)
+        .execute(&mut *conn)
+        .await?;
+        return match Self::from_key(&key, &mut *conn).await? {
+            Some(author) => Ok(author),
+            None => Err(anyhow::anyhow!("Failed to add author: {}", name)),
+        };
+    }
+
+    /// The author record spelled exactly like this name, created if it does not exist yet.
+    /// Used before name keys were recorded; `backfill_name_keys` merges the records whose
+    /// names only differ in case or spacing.
+    async fn from_exact_name(name: &Name, conn: &mut SqliteConnection) -> anyhow::Result<Self> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO authors (first, last, suffix)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(&name.first)
+        .bind(&name.last)
+        .bind(&name.suffix)
+        .execute(&mut *conn)
+        .await?;
+        return Ok(sqlx::query_as::<_, Self>(
+            r#"
+            SELECT id, first, last, suffix FROM authors
+            WHERE first=?1 AND last=?2 AND suffix=?3
+            "#,
+        )
+        .bind(&name.first)
+        .bind(&name.last)
+        .bind(&name.suffix)
+        .fetch_one(&mut *conn)
+        .await?);
+    }
+
+    async fn from_key<'e, E>(key: &str, executor: E) -> anyhow::Result<Option<Self>>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        return Ok(sqlx::query_as::<_, Self>(
+            r#"
+            SELECT id, first, last, suffix FROM authors
+            WHERE name_key=?1
+            ORDER BY id
+            "#,
+        )
+        .bind(key)
+        .fetch_optional(executor)
+        .await?);
+    }
+
+    /// Authors of a document, in order.
+    pub async fn for_doc(doc_id: u32, pool: &SqlitePool) -> anyhow::Result<Vec<Self>> {
+        return Ok(sqlx::query_as::<_, Self>(
+            r#"
+            SELECT authors.id, authors.first, authors.last, authors.suffix
+            FROM document_authors
+            JOIN authors ON authors.id = document_authors.author_id
+            WHERE document_authors.doc_id=?1
+            ORDER BY document_authors.position
+            "#,
+        )
+        .bind(doc_id)
+        .fetch_all(pool)
+        .await?);
+    }
+
+    /// Every author record with its number of documents, ordered by name.
+    pub async fn get_all(pool: &SqlitePool) -> anyhow::Result<Vec<AuthorCount>> {
+        return Ok(sqlx::query_as::<_, AuthorCount>(
+            r#"
+            SELECT
+                authors.id,
+                authors.first,
+                authors.last,
+                authors.suffix,
+                COUNT(document_authors.doc_id) AS documents
+            FROM authors
+            LEFT JOIN document_authors ON document_authors.author_id = authors.id
+            GROUP BY authors.id
+            ORDER BY authors.name_key, authors.id
+            "#,
+        )
+        .fetch_all(pool)
+        .await?);
+    }
+
+    /// Replace this author with `into` in every document, then delete this author.  Used to
+    /// combine spelling variants of the same person.
+    pub async fn merge(self, into: &DatabaseAuthor, pool: &SqlitePool) -> anyhow::Result<()> {
+        if self.id == into.id {
+            return Ok(());
+        }
+        log::info!("Merging author {} into {}", self, into);
+        let mut tx = pool.begin().await?;
+        // Documents listing both keep the position of `into`
+        sqlx::query(
+            r#"
+            DELETE FROM document_authors
+            WHERE author_id=?1 AND doc_id IN (
+                SELECT doc_id FROM document_authors WHERE author_id=?2
+            )
+            "#,
+        )
+        .bind(self.id)
+        .bind(into.id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE document_authors
+            SET author_id=?2
+            WHERE author_id=?1
+            "#,
+        )
+        .bind(self.id)
+        .bind(into.id)
+        .execute(&mut *tx)
+        .await?;
+        failpoint::check("merge:row")?;
+        sqlx::query(
+            r#"
+            DELETE FROM authors
+            WHERE id=?1
+            "#,
+        )
+        .bind(self.id)
+        .execute(&mut *tx)
+        .await?;
+        commit(tx, "merge:commit", pool).await?;
+        return Ok(());
+    }
+}
+
+/// Replace the author links of a document with `names`, in order.
+pub async fn set_doc_authors(
+    doc_id: u32,
+    names: &[Name],
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+        DELETE FROM document_authors
+        WHERE doc_id=?1
+        "#,
+    )
+    .bind(doc_id)
+    .execute(&mut *conn)
+    .await?;
+    for (position, name) in names.iter().enumerate() {
+        let author = DatabaseAuthor::from_name(name, &mut *conn).await?;
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO document_authors (doc_id, author_id, position)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(doc_id)
+        .bind(author.id)
+        .bind(position as u32)
+        .execute(&mut *conn)
+        .await?;
+    }
+    return Ok(());
+}
+
+/// Parse the free-text author field of documents that have no author links yet into
+/// author records.  Run before the free-text column is dropped, when authors have no name
+/// keys yet.
+pub async fn backfill_authors(pool: &SqlitePool) -> anyhow::Result<usize> {
+    let rows: Vec<(u32, String)> = sqlx::query_as(
+        r#"
+        SELECT id, author FROM documents
+        WHERE COALESCE(author, '') <> ''
+        AND id NOT IN (SELECT doc_id FROM document_authors)
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut conn = pool.acquire().await?;
+    for (id, author) in rows.iter() {
+        for (position, name) in Name::parse_list(author).iter().enumerate() {
+            let author = DatabaseAuthor::from_exact_name(name, &mut conn).await?;
+            sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO document_authors (doc_id, author_id, position)
+                VALUES (?1, ?2, ?3)
+                "#,
+            )
+            .bind(id)
+            .bind(author.id)
+            .bind(position as u32)
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+    log::info!("Linked authors of {} documents", rows.len());
+    return Ok(rows.len());
+}
+
+/// Compute the name key of authors written before name keys were recorded, merging authors
+/// whose names only differ in case or spacing.
+pub async fn backfill_name_keys(pool: &SqlitePool) -> anyhow::Result<usize> {
+    let authors = sqlx::query_as::<_, DatabaseAuthor>(
+        r#"
+        SELECT id, first, last, suffix FROM authors
+        WHERE name_key IS NULL
+        ORDER BY id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    for author in authors.iter() {
+        let key = author.name().key();
+        if let Some(existing) = DatabaseAuthor::from_key(&key, pool).await? {
+            author.clone().merge(&existing, pool).await?;
+            continue;
+        }
+        sqlx::query(
+            r#"
+            UPDATE authors
+            SET name_key=?1
+            WHERE id=?2
+            "#,
+        )
+        .bind(&key)
+        .bind(author.id)
+        .execute(pool)
+        .await?;
+    }
+    return Ok(authors.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{count, document, library};
+    use crate::document::{DatabaseDoc, DuplicatePolicy};
+
+    fn name(first: &str, last: &str, suffix: &str) -> Name {
+        return Name {
+            first: first.to_string(),
+            last: last.to_string(),
+            suffix: suffix.to_string(),
+        };
+    }
+
+    #[test]
+    fn parses_last_first_and_first_last() {
+        assert_eq!(
+            Name::parse("Doe, Jane Ann"),
+            Some(name("Jane Ann", "Doe", ""))
+        );
+        assert_eq!(
+            Name::parse("Jane Ann Doe"),
+            Some(name("Jane Ann", "Doe", ""))
+        );
+        assert_eq!(Name::parse(" Doe "), Some(name("", "Doe", "")));
+        assert_eq!(Name::parse(""), None);
+        assert_eq!(Name::parse("Jane,"), Some(name("", "Jane", "")));
+    }
+
+    #[test]
+    fn particles_belong_to_the_surname() {
+        let beethoven = Some(name("Ludwig", "van Beethoven", ""));
+        assert_eq!(Name::parse("Ludwig van Beethoven"), beethoven);
+        assert_eq!(Name::parse("van Beethoven, Ludwig"), beethoven);
+        assert_eq!(
+            Name::parse("Maria de la Cruz"),
+            Some(name("Maria", "de la Cruz", ""))
+        );
+        // Without capitals the particles cannot be told apart from given names
+        assert_eq!(Name::parse("jane doe"), Some(name("jane", "doe", "")));
+    }
+
+    #[test]
+    fn parses_suffix() {
+        let king = name("Martin Luther", "King", "Jr.");
+        assert_eq!(Name::parse("King, Jr., Martin Luther"), Some(king.clone()));
+        assert_eq!(king.to_bibtex(), "King, Jr., Martin Luther");
+    }
+
+    #[test]
+    fn braced_names_are_kept_whole() {
+        let who = name("", "World Health Organization", "");
+        assert_eq!(
+            Name::parse("{World Health Organization}"),
+            Some(who.clone())
+        );
+        assert_eq!(who.to_bibtex(), "{World Health Organization}");
+        assert_eq!(
+            Name::parse_list("{Barnes and Noble} AND Doe, Jane and {World Health Organization}"),
+            vec![
+                name("", "Barnes and Noble", ""),
+                name("Jane", "Doe", ""),
+                who,
+            ]
+        );
+        assert_eq!(
+            Name::parse("{Barnes, Noble}, Jane"),
+            Some(name("Jane", "Barnes, Noble", ""))
+        );
+        assert_eq!(Name::parse_list(""), Vec::<Name>::new());
+    }
+
+    async fn author_named(last: &str, pool: &SqlitePool) -> DatabaseAuthor {
+        return DatabaseAuthor::get_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|a| a.author)
+            .find(|a| a.last == last)
+            .unwrap();
+    }
+
+    /// A library with a document by Doe and Roe and one by Roe only.
+    async fn two_authors(name: &str) -> (SqlitePool, DatabaseDoc, DatabaseDoc) {
+        let pool = library(name).await;
+        let mut both = document(&format!("{}-both", name));
+        both.author = "Doe, Jane and Roe, John".to_string();
+        let both = DatabaseDoc::from_insert(both, DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        let mut roe = document(&format!("{}-roe", name));
+        roe.author = "Roe, John".to_string();
+        let roe = DatabaseDoc::from_insert(roe, DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        return (pool, both, roe);
+    }
+
+    #[tokio::test]
+    async fn merge_moves_documents_and_removes_author() {
+        let (pool, both, roe) = two_authors("merge").await;
+        let doe = author_named("Doe", &pool).await;
+        author_named("Roe", &pool)
+            .await
+            .merge(&doe, &pool)
+            .await
+            .unwrap();
+        assert_eq!(count("authors", &pool).await, 1);
+        for doc in [both, roe] {
+            let authors = DatabaseAuthor::for_doc(doc.id, &pool).await.unwrap();
+            assert_eq!(authors.len(), 1);
+            assert_eq!(authors[0].id, doe.id);
+        }
+    }
+
+    async fn merge_fails_at(failpoint: &'static str) {
+        let (pool, both, roe) = two_authors(failpoint).await;
+        let doe = author_named("Doe", &pool).await;
+        failpoint::arm(failpoint);
+        let result = author_named("Roe", &pool).await.merge(&doe, &pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        assert_eq!(count("authors", &pool).await, 2);
+        assert_eq!(
+            DatabaseAuthor::for_doc(both.id, &pool).await.unwrap().len(),
+            2
+        );
+        let roe_authors = DatabaseAuthor::for_doc(roe.id, &pool).await.unwrap();
+        assert_eq!(roe_authors[0].last, "Roe");
+    }
+
+    #[tokio::test]
+    async fn merge_rolls_back_when_row_fails() {
+        merge_fails_at("merge:row").await;
+    }
+
+    #[tokio::test]
+    async fn merge_rolls_back_when_commit_fails() {
+        merge_fails_at("merge:commit").await;
+    }
+}
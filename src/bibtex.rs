@@ -0,0 +1,941 @@
+use crate::author::{DatabaseAuthor, Name};
+use crate::doctype::{parse_month, DocType, ExtraFields};
+use crate::document::{DatabaseDoc, DocList, Document, DocumentBuilder};
+use crate::filetype::FILE_TYPES;
+use crate::tag::TagInputList;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A single BibTeX entry, e.g. `@article{smith2020deep, title = {...}}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BibtexEntry {
+    pub entry_type: String,
+    pub key: String,
+    /// Field names and unescaped values, in output order.  `author` and `editor` keep the
+    /// braces around corporate names, as `Name::to_bibtex` writes them.
+    pub fields: Vec<(String, String)>,
+}
+
+impl BibtexEntry {
+    /// Entry for a document record, with the author field written from the structured
+    /// `authors` of the document.
+    pub fn from_doc(doc: &DatabaseDoc, authors: &[Name], key: &str) -> Self {
+        let entry_type = entry_type(doc);
+        let mut fields = Vec::new();
+        if !authors.is_empty() {
+            fields.push((
+                "author".to_string(),
+                authors
+                    .iter()
+                    .map(|a| a.to_bibtex())
+                    .collect::<Vec<String>>()
+                    .join(" and "),
+            ));
+        }
+        fields.push(("title".to_string(), doc.title.clone()));
+        // Plain BibTeX has no entry types for standards and web pages, which are written as
+        // `@misc` entries that say where they are published
+        match doc.doc_type {
+            DocType::Standard => {
+                let howpublished = [&doc.extra.institution, &doc.extra.issue]
+                    .iter()
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" ");
+                fields.push(("howpublished".to_string(), howpublished));
+            }
+            DocType::Webpage => {
+                fields.push((
+                    "howpublished".to_string(),
+                    format!("\\url{{{}}}", doc.extra.url),
+                ));
+            }
+            _ => {}
+        }
+        if !doc.publication.is_empty() {
+            fields.push(("journal".to_string(), doc.publication.clone()));
+        }
+        if doc.volume != 0 {
+            fields.push(("volume".to_string(), doc.volume.to_string()));
+        }
+        for (name, value) in doc.extra.set_fields() {
+            let name = match name {
+                "issue" => "number",
+                "institution" if doc.doc_type == DocType::Thesis => "school",
+                "institution" if doc.doc_type == DocType::Standard => "organization",
+                "genre" => "type",
+                name => name,
+            };
+            fields.push((name.to_string(), value));
+        }
+        if doc.year != 0 {
+            fields.push(("year".to_string(), doc.year.to_string()));
+        }
+        if !doc.doi.is_empty() {
+            fields.push(("doi".to_string(), doc.doi.clone()));
+        }
+        let tags = TagInputList::from(doc.tags.as_str());
+        if !tags.tag_values().is_empty() {
+            fields.push(("keywords".to_string(), tags.tag_values().join(", ")));
+        }
+        return Self {
+            entry_type: entry_type.to_string(),
+            key: key.to_string(),
+            fields,
+        };
+    }
+
+    pub fn field(&self, name: &str) -> Option<&str> {
+        return self
+            .fields
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .filter(|v| !v.is_empty());
+    }
+
+    /// Convert the entry to a document for insertion.  The document file is taken from the
+    /// `file` field, or else looked up as `<citation key>.<ext>` in `files_dir` for each
+    /// supported file type.
+    pub fn to_document(&self, files_dir: &Path) -> anyhow::Result<Document> {
+        let title = match self.field("title") {
+            Some(title) => title,
+            None => return Err(anyhow::anyhow!("Entry {} has no title", self.key)),
+        };
+        let path = self.find_file(files_dir)?;
+        let year = self
+            .field("year")
+            .or_else(|| self.field("date"))
+            .map(leading_number)
+            .unwrap_or(0);
+        let doi = self
+            .field("doi")
+            .map(|d| {
+                d.trim_start_matches("https://doi.org/")
+                    .trim_start_matches("http://dx.doi.org/")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        let tags = self
+            .field("keywords")
+            .map(|k| {
+                k.split([',', ';'])
+                    .map(|t| t.trim())
+                    .filter(|t| !t.is_empty())
+                    .collect::<Vec<&str>>()
+                    .join(",")
+            })
+            .unwrap_or_default();
+        return DocumentBuilder::new(title, &path.to_string_lossy())
+            .author(self.field("author").unwrap_or(""))
+            .publication(
+                self.field("journal")
+                    .or_else(|| self.field("journaltitle"))
+                    .unwrap_or(""),
+            )
+            .volume(self.field("volume").map(leading_number).unwrap_or(0))
+            .year(year)
+            .doi(&doi)
+            .tags(&tags)
+            .doc_type(self.doc_type())
+            .extra(self.extra_fields())
+            .build()
+            .map_err(|e| anyhow::anyhow!("Entry {}: {}", self.key, e));
+    }
+
+    /// Document type of the entry.  Standards and web pages exported as `@misc` are told
+    /// apart by their organization and by a `howpublished` that gives their URL.
+    fn doc_type(&self) -> DocType {
+        let doc_type = doc_type(&self.entry_type);
+        if doc_type != DocType::Misc {
+            return doc_type;
+        }
+        if self.field("organization").is_some() {
+            return DocType::Standard;
+        }
+        if self.field("url").is_some() && self.field("howpublished") == self.field("url") {
+            return DocType::Webpage;
+        }
+        return DocType::Misc;
+    }
+
+    fn extra_fields(&self) -> ExtraFields {
+        let text = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| self.field(name))
+                .unwrap_or("")
+                .to_string()
+        };
+        let month = match self.field("month").map(parse_month) {
+            Some(Ok(month)) => month,
+            Some(Err(e)) => {
+                log::warn!("Entry {}: {}", self.key, e);
+                0
+            }
+            None => 0,
+        };
+        // Theses without a `type` field are described by their entry type
+        let genre = match (self.field("type"), self.entry_type.to_lowercase().as_str()) {
+            (Some(genre), _) => genre.to_string(),
+            (None, "phdthesis") => "PhD thesis".to_string(),
+            (None, "mastersthesis") => "Master's thesis".to_string(),
+            (None, _) => String::new(),
+        };
+        return ExtraFields {
+            issue: text(&["number", "issue"]),
+            pages: text(&["pages"]),
+            publisher: text(&["publisher"]),
+            edition: text(&["edition"]),
+            isbn: text(&["isbn"]),
+            url: text(&["url"]),
+            institution: text(&["institution", "school", "organization"]),
+            genre,
+            editor: text(&["editor"]),
+            booktitle: text(&["booktitle"]),
+            month,
+            abstract_: text(&["abstract"]),
+        };
+    }
+
+    fn find_file(&self, files_dir: &Path) -> anyhow::Result<PathBuf> {
+        if let Some(file) = self.field("file") {
+            // JabRef and Mendeley write `description:path:type` and separate files with `;`
+            for item in file.split(';') {
+                let parts: Vec<&str> = item.split(':').collect();
+                let candidate = match parts.len() {
+                    3 => parts[1],
+                    _ => item,
+                };
+                let candidate = PathBuf::from(candidate.trim());
+                let path = if candidate.is_absolute() {
+                    candidate
+                } else {
+                    files_dir.join(candidate)
+                };
+                if path.is_file() {
+                    return Ok(path);
+                }
+                log::debug!("Entry {}: file {:?} not found", self.key, path);
+            }
+        }
+        for file_type in FILE_TYPES.iter() {
+            for ext in file_type.extensions() {
+                let path = files_dir.join(format!("{}.{}", self.key, ext));
+                if path.is_file() {
+                    return Ok(path);
+                }
+            }
+        }
+        return Err(anyhow::anyhow!(
+            "Entry {}: no document file found from its file field or at {:?}",
+            self.key,
+            files_dir.join(format!("{}.<ext>", self.key))
+        ));
+    }
+}
+
+impl std::fmt::Display for BibtexEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "@{}{{{},", self.entry_type, self.key)?;
+        for (name, value) in self.fields.iter() {
+            // DOIs and URLs are identifiers, not text, and must not be escaped
+            let value = match name.as_str() {
+                "doi" | "url" => value.clone(),
+                "howpublished" if value.starts_with("\\url{") => value.clone(),
+                "author" | "editor" => escape_names(value),
+                _ => escape(value),
+            };
+            writeln!(f, "  {:<9} = {{{}}},", name, value)?;
+        }
+        return writeln!(f, "}}");
+    }
+}
+
+/// BibTeX entry type written for a document.
+fn entry_type(doc: &DatabaseDoc) -> &'static str {
+    return match doc.doc_type {
+        DocType::Article => "article",
+        DocType::Book => "book",
+        DocType::Thesis if doc.extra.genre.to_lowercase().contains("master") => "mastersthesis",
+        DocType::Thesis => "phdthesis",
+        DocType::Conference => "inproceedings",
+        DocType::Standard => "misc",
+        DocType::Report => "techreport",
+        DocType::Webpage => "misc",
+        DocType::Misc => "misc",
+    };
+}
+
+/// Document type of a BibTeX or BibLaTeX entry type.
+fn doc_type(entry_type: &str) -> DocType {
+    return match entry_type.to_lowercase().as_str() {
+        "article" => DocType::Article,
+        "book" | "inbook" | "booklet" | "mvbook" => DocType::Book,
+        "phdthesis" | "mastersthesis" | "thesis" => DocType::Thesis,
+        "inproceedings" | "conference" => DocType::Conference,
+        "standard" => DocType::Standard,
+        "techreport" | "report" => DocType::Report,
+        "online" | "electronic" | "www" => DocType::Webpage,
+        _ => DocType::Misc,
+    };
+}
+
+/// Citation key of the form `<first author surname><year><first title word>`, reduced to
+/// lowercase ASCII so it is stable across exports and safe in LaTeX.
+pub fn citation_key(doc: &DatabaseDoc) -> String {
+    let surname = Name::parse_list(&doc.author)
+        .into_iter()
+        .next()
+        .map(|name| {
+            name.last
+                .split_whitespace()
+                .last()
+                .unwrap_or("")
+                .to_string()
+        })
+        .unwrap_or_default();
+    let title_word = doc
+        .title
+        .split_whitespace()
+        .map(key_part)
+        .find(|w| w.len() > 3 && !STOP_WORDS.contains(&w.as_str()))
+        .unwrap_or_default();
+    let mut key = key_part(&surname);
+    if key.is_empty() {
+        key.push_str("anon");
+    }
+    if doc.year != 0 {
+        key.push_str(&doc.year.to_string());
+    }
+    key.push_str(&title_word);
+    return key;
+}
+
+/// Citation keys for every document in `docs`.  Documents in the library that would share a
+/// key are told apart with `a`, `b`, ... suffixes assigned in order of document id, so a
+/// document's key does not depend on which other documents are exported with it.
+pub async fn citation_keys(docs: &DocList, pool: &SqlitePool) -> anyhow::Result<Vec<String>> {
+    let library = DocList::get_all(pool).await?;
+    let keys = assign_keys(&library);
+    return Ok(docs
+        .iter()
+        .map(|doc| match keys.get(&doc.id) {
+            Some(key) => key.clone(),
+            None => citation_key(doc),
+        })
+        .collect());
+}
+
+/// Unique citation keys for `docs`, keyed by document id.
+pub fn assign_keys(docs: &[DatabaseDoc]) -> HashMap<u32, String> {
+    let mut by_key: HashMap<String, Vec<u32>> = HashMap::new();
+    for doc in docs.iter() {
+        by_key.entry(citation_key(doc)).or_default().push(doc.id);
+    }
+    let mut keys = HashMap::new();
+    for (key, mut ids) in by_key.into_iter() {
+        if ids.len() == 1 {
+            keys.insert(ids[0], key);
+            continue;
+        }
+        ids.sort();
+        for (position, id) in ids.into_iter().enumerate() {
+            keys.insert(id, format!("{}{}", key, suffix(position)));
+        }
+    }
+    return keys;
+}
+
+/// Render `docs` as a BibTeX database.
+pub async fn to_bibtex(docs: &DocList, pool: &SqlitePool) -> anyhow::Result<String> {
+    let mut entries = Vec::new();
+    for (doc, key) in docs.iter().zip(citation_keys(docs, pool).await?) {
+        let authors = DatabaseAuthor::for_doc(doc.id, pool)
+            .await?
+            .iter()
+            .map(|a| a.name())
+            .collect::<Vec<Name>>();
+        entries.push(BibtexEntry::from_doc(doc, &authors, &key).to_string());
+    }
+    return Ok(entries.join("\n"));
+}
+
+/// Escape a list of names, keeping the braces that group corporate names.
+fn escape_names(value: &str) -> String {
+    return Name::parse_list(value)
+        .into_iter()
+        .map(|name| {
+            Name {
+                first: escape(&name.first),
+                last: escape(&name.last),
+                suffix: escape(&name.suffix),
+            }
+            .to_bibtex()
+        })
+        .collect::<Vec<String>>()
+        .join(" and ");
+}
+
+/// Escape characters that have a special meaning in LaTeX.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '{' | '}' | '&' | '%' | '$' | '#' | '_' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            _ => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+const STOP_WORDS: &[&str] = &[
+    "about", "above", "after", "from", "into", "over", "than", "that", "their", "there", "these",
+    "this", "those", "through", "towards", "under", "using", "what", "when", "where", "which",
+    "with", "within", "without",
+];
+
+fn key_part(value: &str) -> String {
+    return value
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+}
+
+fn suffix(mut position: usize) -> String {
+    let mut suffix = String::new();
+    loop {
+        suffix.insert(0, (b'a' + (position % 26) as u8) as char);
+        if position < 26 {
+            break;
+        }
+        position = position / 26 - 1;
+    }
+    return suffix;
+}
+
+/// Parse a BibTeX or BibLaTeX database.  `@string` macros are expanded, `@comment` and
+/// `@preamble` blocks and text outside of entries are ignored.
+pub fn parse(input: &str) -> anyhow::Result<Vec<BibtexEntry>> {
+    return BibtexParser::new(input).parse();
+}
+
+struct BibtexParser {
+    chars: Vec<char>,
+    pos: usize,
+    strings: HashMap<String, String>,
+}
+
+impl BibtexParser {
+    fn new(input: &str) -> Self {
+        let strings = [
+            ("jan", "January"),
+            ("feb", "February"),
+            ("mar", "March"),
+            ("apr", "April"),
+            ("may", "May"),
+            ("jun", "June"),
+            ("jul", "July"),
+            ("aug", "August"),
+            ("sep", "September"),
+            ("oct", "October"),
+            ("nov", "November"),
+            ("dec", "December"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        return Self {
+            chars: input.chars().collect(),
+            pos: 0,
+            strings,
+        };
+    }
+
+    fn parse(mut self) -> anyhow::Result<Vec<BibtexEntry>> {
+        let mut entries = Vec::new();
+        while self.skip_to_entry() {
+            self.pos += 1;
+            let entry_type = self.identifier()?.to_lowercase();
+            self.skip_whitespace();
+            let close = match self.next_char() {
+                Some('{') => '}',
+                Some('(') => ')',
+                _ => return Err(self.error("expected '{' or '(' after entry type")),
+            };
+            match entry_type.as_str() {
+                "comment" | "preamble" => self.skip_block(close)?,
+                "string" => {
+                    for (name, value) in self.fields(close)? {
+                        self.strings.insert(name, value);
+                    }
+                }
+                _ => {
+                    let key = self.citation_key()?;
+                    let fields = self
+                        .fields(close)?
+                        .into_iter()
+                        .map(|(name, value)| {
+                            let value = match name.as_str() {
+                                "author" | "editor" => unescape_names(&value),
+                                _ => unescape(&value),
+                            };
+                            (name, value)
+                        })
+                        .collect();
+                    entries.push(BibtexEntry {
+                        entry_type,
+                        key,
+                        fields,
+                    });
+                }
+            }
+        }
+        return Ok(entries);
+    }
+
+    fn skip_to_entry(&mut self) -> bool {
+        while let Some(c) = self.peek() {
+            if c == '@' {
+                return true;
+            }
+            self.pos += 1;
+        }
+        return false;
+    }
+
+    fn citation_key(&mut self) -> anyhow::Result<String> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c == ',' {
+                let key: String = self.chars[start..self.pos].iter().collect();
+                self.pos += 1;
+                return Ok(key.trim().to_string());
+            }
+            if c == '}' || c == ')' || c == '=' {
+                break;
+            }
+            self.pos += 1;
+        }
+        return Err(self.error("expected ',' after citation key"));
+    }
+
+    fn fields(&mut self, close: char) -> anyhow::Result<Vec<(String, String)>> {
+        let mut fields = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    return Ok(fields);
+                }
+                Some(',') => {
+                    self.pos += 1;
+                    continue;
+                }
+                Some(_) => {}
+                None => return Err(self.error("unterminated entry")),
+            }
+            let name = self.identifier()?.to_lowercase();
+            self.skip_whitespace();
+            if self.next_char() != Some('=') {
+                return Err(self.error(&format!("expected '=' after field {:?}", name)));
+            }
+            let value = self.value()?;
+            fields.push((name, value));
+        }
+    }
+
+    fn value(&mut self) -> anyhow::Result<String> {
+        let mut value = String::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('{') => {
+                    self.pos += 1;
+                    value.push_str(&self.delimited('}')?);
+                }
+                Some('"') => {
+                    self.pos += 1;
+                    value.push_str(&self.delimited('"')?);
+                }
+                Some(c) if c.is_ascii_digit() => {
+                    while let Some(c) = self.peek().filter(|c| c.is_ascii_digit()) {
+                        value.push(c);
+                        self.pos += 1;
+                    }
+                }
+                Some(_) => {
+                    let name = self.identifier()?.to_lowercase();
+                    match self.strings.get(&name) {
+                        Some(expanded) => value.push_str(expanded),
+                        None => return Err(self.error(&format!("undefined string {:?}", name))),
+                    }
+                }
+                None => return Err(self.error("expected field value")),
+            }
+            self.skip_whitespace();
+            if self.peek() == Some('#') {
+                self.pos += 1;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Read up to the unnested `end`, keeping inner braces for `unescape` to handle.
+    fn delimited(&mut self, end: char) -> anyhow::Result<String> {
+        let mut depth = 0;
+        let mut value = String::new();
+        while let Some(c) = self.next_char() {
+            match c {
+                '\\' => {
+                    value.push(c);
+                    if let Some(next) = self.next_char() {
+                        value.push(next);
+                    }
+                    continue;
+                }
+                c if c == end && depth == 0 => return Ok(value),
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            value.push(c);
+        }
+        return Err(self.error("unterminated field value"));
+    }
+
+    fn skip_block(&mut self, close: char) -> anyhow::Result<()> {
+        let open = if close == '}' { '{' } else { '(' };
+        let mut depth = 0;
+        while let Some(c) = self.next_char() {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                if depth == 0 {
+                    return Ok(());
+                }
+                depth -= 1;
+            }
+        }
+        return Err(self.error("unterminated block"));
+    }
+
+    fn identifier(&mut self) -> anyhow::Result<String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || "_-:.+/".contains(c) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if start == self.pos {
+            return Err(self.error("expected identifier"));
+        }
+        return Ok(self.chars[start..self.pos].iter().collect());
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.pos).copied();
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        return c;
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count()
+            + 1;
+        return anyhow::anyhow!("BibTeX parse error on line {}: {}", line, message);
+    }
+}
+
+/// Unescape each part of the names in a raw `author` or `editor` field.  The names are split
+/// first, since unescaping drops the braces that keep `{Barnes and Noble}` a single name.
+fn unescape_names(value: &str) -> String {
+    // An unescaped `~` is a space that may separate name parts
+    let mut spaced = String::with_capacity(value.len());
+    let mut escaped = false;
+    for c in value.chars() {
+        spaced.push(if c == '~' && !escaped { ' ' } else { c });
+        escaped = c == '\\' && !escaped;
+    }
+    return Name::parse_list(&spaced)
+        .into_iter()
+        .map(|name| {
+            Name {
+                first: unescape(&name.first),
+                last: unescape(&name.last),
+                suffix: unescape(&name.suffix),
+            }
+            .to_bibtex()
+        })
+        .collect::<Vec<String>>()
+        .join(" and ");
+}
+
+/// Convert a raw field value to plain text: drop grouping braces, resolve escaped special
+/// characters and common accent commands, and collapse whitespace.
+pub fn unescape(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut plain = String::with_capacity(value.len());
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '{' | '}' => i += 1,
+            '~' => {
+                plain.push(' ');
+                i += 1;
+            }
+            '\\' if i + 1 < chars.len() => {
+                let c = chars[i + 1];
+                if "{}&%$#_\\ ".contains(c) {
+                    plain.push(c);
+                    i += 2;
+                    continue;
+                }
+                // Letter accents (\c, \v, ...) need a separator so \cite is not read as one
+                let separated = !c.is_ascii_alphabetic()
+                    || chars.get(i + 2).is_some_and(|n| *n == '{' || *n == ' ');
+                if let Some(accent) = accent_mark(c).filter(|_| separated) {
+                    // e.g. \"o, \"{o} or {\"o}
+                    let mut j = i + 2;
+                    while j < chars.len() && (chars[j] == '{' || chars[j] == ' ') {
+                        j += 1;
+                    }
+                    if j < chars.len() && chars[j].is_alphabetic() {
+                        plain.push(chars[j]);
+                        plain.push(accent);
+                        i = j + 1;
+                        continue;
+                    }
+                }
+                // Named command such as \textbackslash{} or \emph{...}
+                let mut j = i + 1;
+                while j < chars.len() && chars[j].is_ascii_alphabetic() {
+                    j += 1;
+                }
+                let command: String = chars[i + 1..j].iter().collect();
+                match command.as_str() {
+                    "textbackslash" => plain.push('\\'),
+                    "textasciitilde" => plain.push('~'),
+                    "textasciicircum" => plain.push('^'),
+                    "ss" => plain.push('ß'),
+                    "o" => plain.push('ø'),
+                    "O" => plain.push('Ø'),
+                    "aa" => plain.push('å'),
+                    "AA" => plain.push('Å'),
+                    "ae" => plain.push('æ'),
+                    "AE" => plain.push('Æ'),
+                    "l" => plain.push('ł'),
+                    "L" => plain.push('Ł'),
+                    _ => {}
+                }
+                // Drop the space that terminates a named command
+                if j < chars.len() && chars[j] == ' ' && j > i + 1 {
+                    j += 1;
+                }
+                i = j.max(i + 1);
+            }
+            c => {
+                plain.push(c);
+                i += 1;
+            }
+        }
+    }
+    return plain.split_whitespace().collect::<Vec<&str>>().join(" ");
+}
+
+fn leading_number(value: &str) -> u16 {
+    let digits: String = value
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    return digits.parse().unwrap_or(0);
+}
+
+fn accent_mark(command: char) -> Option<char> {
+    return match command {
+        '\'' => Some('\u{301}'),
+        '`' => Some('\u{300}'),
+        '^' => Some('\u{302}'),
+        '"' => Some('\u{308}'),
+        '~' => Some('\u{303}'),
+        '=' => Some('\u{304}'),
+        '.' => Some('\u{307}'),
+        'c' => Some('\u{327}'),
+        'v' => Some('\u{30c}'),
+        'u' => Some('\u{306}'),
+        'H' => Some('\u{30b}'),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{assert_same_document, authors, database_doc, PdfFile};
+
+    fn parse_one(input: &str) -> BibtexEntry {
+        let entries = parse(input).unwrap();
+        assert_eq!(entries.len(), 1, "{:?}", entries);
+        return entries[0].clone();
+    }
+
+    /// Directory and citation key that find `file` by the key lookup of `to_document`.
+    fn key_of(file: &Path) -> (PathBuf, String) {
+        let dir = file.parent().unwrap().to_path_buf();
+        let key = file.file_stem().unwrap().to_string_lossy().to_string();
+        return (dir, key);
+    }
+
+    #[test]
+    fn parses_brace_and_quote_values() {
+        let entry = parse_one(
+            r#"
+            Text before the entry is ignored.
+            @Article{doe2020,
+              Title = {The {RNA} World \& {\"O}zt{\"u}rk's {Cats}},
+              journal = "A {"}Quoted{"} Journal",
+              volume = 12,
+              pages = {101--115}
+            }
+            "#,
+        );
+        assert_eq!(entry.entry_type, "article");
+        assert_eq!(entry.key, "doe2020");
+        assert_eq!(
+            entry.field("title"),
+            Some("The RNA World & O\u{308}ztu\u{308}rk's Cats")
+        );
+        assert_eq!(entry.field("journal"), Some("A \"Quoted\" Journal"));
+        assert_eq!(entry.field("volume"), Some("12"));
+        assert_eq!(entry.field("pages"), Some("101--115"));
+    }
+
+    #[test]
+    fn expands_strings_and_concatenation() {
+        let entries = parse(
+            r#"
+            @comment{ @article{ignored, title = {Not an entry}} }
+            @string{jfs = "Feline Studies"}
+            @STRING(vol = {12})
+            @article(doe2020,
+              title = "Cats",
+              journal = "Journal of " # jfs # {, Series B},
+              volume = vol,
+              month = mar,
+            )
+            "#,
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(
+            entry.field("journal"),
+            Some("Journal of Feline Studies, Series B")
+        );
+        assert_eq!(entry.field("volume"), Some("12"));
+        assert_eq!(entry.field("month"), Some("March"));
+        assert!(parse("@article{key, journal = undefined}").is_err());
+        assert!(parse("@article{key, title = {Unterminated}").is_err());
+    }
+
+    #[test]
+    fn keeps_corporate_names_whole() {
+        let entry = parse_one(
+            r#"@book{who2020,
+              author = {{World Health Organization} and {Barnes and Noble} and
+                        M{\"u}ller, J{\"o}rg and Ludwig~van~Beethoven},
+              editor = {{Cat Fanciers' Association}},
+              title = {Cats},
+            }"#,
+        );
+        let names = Name::parse_list(entry.field("author").unwrap());
+        let name = |first: &str, last: &str| Name {
+            first: first.to_string(),
+            last: last.to_string(),
+            suffix: String::new(),
+        };
+        assert_eq!(
+            names,
+            vec![
+                name("", "World Health Organization"),
+                name("", "Barnes and Noble"),
+                name("Jo\u{308}rg", "Mu\u{308}ller"),
+                name("Ludwig", "van Beethoven"),
+            ]
+        );
+        assert_eq!(entry.field("editor"), Some("{Cat Fanciers' Association}"));
+    }
+
+    #[test]
+    fn finds_file_from_file_field_or_citation_key() {
+        let file = PdfFile::new("bibtex-file-field");
+        let entry = parse_one(&format!(
+            "@misc{{nokey, title = {{Cats}}, file = {{:missing.pdf:PDF;Full text:{}:PDF}}}}",
+            file.display()
+        ));
+        let doc = entry.to_document(Path::new("/nonexistent")).unwrap();
+        assert_eq!(doc.path, *file);
+
+        let file = PdfFile::new("bibtex-key");
+        let (dir, key) = key_of(&file);
+        let entry = parse_one(&format!("@misc{{{}, title = {{Cats}}}}", key));
+        assert_eq!(entry.to_document(&dir).unwrap().path, *file);
+
+        let entry = parse_one("@misc{bibtex-no-file, title = {Cats}}");
+        assert!(entry.to_document(&dir).is_err());
+    }
+
+    #[test]
+    fn keywords_become_tags() {
+        let file = PdfFile::new("bibtex-keywords");
+        let (dir, key) = key_of(&file);
+        let entry = parse_one(&format!(
+            "@article{{{}, title = {{Cats}}, keywords = {{Machine Learning; cats,, Dogs}}}}",
+            key
+        ));
+        let doc = entry.to_document(&dir).unwrap();
+        assert_eq!(doc.tags, "machine learning,cats,dogs");
+    }
+
+    #[test]
+    fn export_round_trips_through_parse() {
+        let file = PdfFile::new("bibtex-round-trip");
+        let (dir, key) = key_of(&file);
+        let mut doc = database_doc(DocType::Article);
+        doc.title = "Cats & {Dogs}: 100% of_them".to_string();
+        // BibTeX has no paragraphs; whitespace in field values is collapsed
+        doc.extra.abstract_ = "We survey cats.".to_string();
+        let mut authors = authors();
+        authors.extend(Name::parse_list("{Barnes and Noble}"));
+        let text = BibtexEntry::from_doc(&doc, &authors, &key).to_string();
+        let imported = parse_one(&text).to_document(&dir).unwrap();
+        assert_same_document(&doc, &authors, &imported, &file);
+    }
+}
@@ -0,0 +1,785 @@
+use crate::author::{DatabaseAuthor, Name};
+use crate::config::Config;
+use crate::document::DatabaseDoc;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Styles compiled into the binary, by name.
+const BUILTIN_STYLES: &[(&str, &str)] = &[
+    ("apa", include_str!("../styles/apa.toml")),
+    ("ieee", include_str!("../styles/ieee.toml")),
+    ("chicago", include_str!("../styles/chicago.toml")),
+    ("mla", include_str!("../styles/mla.toml")),
+];
+
+/// Fields a template can refer to.
+const CITE_FIELDS: &[&str] = &[
+    "authors",
+    "editor",
+    "title",
+    "year",
+    "month",
+    "month_abbr",
+    "publication",
+    "volume",
+    "issue",
+    "pages",
+    "publisher",
+    "edition",
+    "isbn",
+    "url",
+    "institution",
+    "genre",
+    "booktitle",
+    "doi",
+    "doi_id",
+    "number",
+];
+
+const MONTHS: &[&str] = &[
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Month abbreviations of reference lists; only May is short enough to be kept whole.
+const MONTH_ABBRS: &[&str] = &[
+    "Jan.", "Feb.", "Mar.", "Apr.", "May", "Jun.", "Jul.", "Aug.", "Sep.", "Oct.", "Nov.", "Dec.",
+];
+
+/// Markup of formatted citations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum CitationFormat {
+    /// Plain text; italics are dropped.
+    #[default]
+    Text,
+    Html,
+    Markdown,
+}
+
+/// How a single name is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NameForm {
+    /// `Doe, J. A.`
+    #[default]
+    LastInitials,
+    /// `J. A. Doe`
+    InitialsLast,
+    /// `Doe, Jane A.`
+    LastFirst,
+    /// `Jane A. Doe`
+    FirstLast,
+}
+
+/// Rules for writing a list of names.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NameRules {
+    pub form: NameForm,
+    /// Form of the first name in the list, when it differs from `form`.
+    pub first_form: Option<NameForm>,
+    /// Between names in a list of three or more.
+    pub delimiter: String,
+    /// Between the names of a list of exactly two.
+    pub delimiter_two: String,
+    /// Before the last name of a list of three or more.
+    pub last_delimiter: String,
+    /// Lists of this many names or more are shortened.  Zero never shortens.
+    pub et_al_min: usize,
+    /// Number of names kept in a shortened list.
+    pub et_al_use_first: usize,
+    /// Shortened lists end with an ellipsis and the last name instead of `et_al`.
+    pub et_al_use_last: bool,
+    pub et_al: String,
+    /// Between the kept names and `et_al`.
+    pub et_al_delimiter: String,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        return Self {
+            form: NameForm::default(),
+            first_form: None,
+            delimiter: ", ".to_string(),
+            delimiter_two: " and ".to_string(),
+            last_delimiter: ", and ".to_string(),
+            et_al_min: 0,
+            et_al_use_first: 1,
+            et_al_use_last: false,
+            et_al: "et al.".to_string(),
+            et_al_delimiter: " ".to_string(),
+        };
+    }
+}
+
+impl NameRules {
+    pub fn format_list(&self, names: &[Name]) -> String {
+        let shorten = self.et_al_min > 0 && names.len() >= self.et_al_min;
+        let kept = if shorten {
+            &names[..self.et_al_use_first.clamp(1, names.len())]
+        } else {
+            names
+        };
+        let formatted = kept
+            .iter()
+            .enumerate()
+            .map(|(i, name)| match (i, self.first_form) {
+                (0, Some(form)) => format_name(name, form),
+                _ => format_name(name, self.form),
+            })
+            .collect::<Vec<String>>();
+        if shorten && self.et_al_use_last {
+            let last = format_name(&names[names.len() - 1], self.form);
+            return format!(
+                "{}{}. . . {}",
+                formatted.join(&self.delimiter),
+                self.delimiter,
+                last
+            );
+        }
+        if shorten {
+            return format!(
+                "{}{}{}",
+                formatted.join(&self.delimiter),
+                self.et_al_delimiter,
+                self.et_al
+            );
+        }
+        return match formatted.as_slice() {
+            [] => String::new(),
+            [one] => one.clone(),
+            [first, second] => format!("{}{}{}", first, self.delimiter_two, second),
+            [rest @ .., last] => format!(
+                "{}{}{}",
+                rest.join(&self.delimiter),
+                self.last_delimiter,
+                last
+            ),
+        };
+    }
+}
+
+/// A citation style, read from a TOML style file.  Each document type has a template; types
+/// without one use the `default` template.
+///
+/// ```toml
+/// name = "Example"
+/// punctuation_in_quote = true
+///
+/// [names]
+/// form = "initials-last"
+/// et_al_min = 4
+///
+/// [templates]
+/// default = '[{authors}, ]{title:quoted}[, {publisher}][, {year|"n.d."}].'
+/// ```
+///
+/// In a template `{field}` is replaced by a field value; `{a|b}` takes the first of the fields
+/// that is set, and a quoted alternative such as `"n.d."` is used as literal text.  A field is
+/// only written once per citation, so a title used in place of missing authors is not repeated.
+/// `:italic` and `:quoted` after the fields set the emphasis.  Text in `[...]` is left out when
+/// none of the fields inside it are set.  `\` escapes the next character.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CitationStyle {
+    pub name: String,
+    /// Commas and periods after a quoted title go inside the closing quote, as in US usage.
+    #[serde(default)]
+    pub punctuation_in_quote: bool,
+    #[serde(default)]
+    pub names: NameRules,
+    pub templates: HashMap<String, String>,
+}
+
+impl CitationStyle {
+    /// A built-in style by name, else a `<name>.toml` file in the configured styles directory,
+    /// else a style file at the path `name`.
+    pub fn load(name: &str) -> anyhow::Result<Self> {
+        if let Some((_, source)) = BUILTIN_STYLES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            return Self::parse(source);
+        }
+        let configured = Config::get().styles_dir.join(format!("{}.toml", name));
+        let path = if configured.is_file() {
+            configured
+        } else {
+            Path::new(name).to_path_buf()
+        };
+        if !path.is_file() {
+            return Err(anyhow::anyhow!(
+                "Unknown citation style {:?}: expected one of {} or a style file",
+                name,
+                BUILTIN_STYLES
+                    .iter()
+                    .map(|(n, _)| *n)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ));
+        }
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Could not read style file {:?}: {}", path, e))?;
+        return Self::parse(&source)
+            .map_err(|e| anyhow::anyhow!("Invalid style file {:?}: {}", path, e));
+    }
+
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let style: Self = toml::from_str(source)?;
+        if !style.templates.contains_key("default") {
+            return Err(anyhow::anyhow!(
+                "Style {:?} has no default template",
+                style.name
+            ));
+        }
+        for (doc_type, template) in style.templates.iter() {
+            parse_template(template)
+                .map_err(|e| anyhow::anyhow!("Template {:?}: {}", doc_type, e))?;
+        }
+        return Ok(style);
+    }
+
+    /// Citation of `doc`, the `number`th entry of a reference list.
+    pub fn format(
+        &self,
+        doc: &DatabaseDoc,
+        authors: &[Name],
+        number: usize,
+        format: CitationFormat,
+    ) -> anyhow::Result<String> {
+        let template = self
+            .templates
+            .get(&doc.doc_type.name())
+            .or_else(|| self.templates.get("default"))
+            .ok_or_else(|| anyhow::anyhow!("Style {:?} has no default template", self.name))?;
+        let nodes = parse_template(template)?;
+        let values = self.field_values(doc, authors, number);
+        let mut used = HashSet::new();
+        let (atoms, _) = render(&nodes, &values, &mut used);
+        return Ok(self.write(&atoms, format));
+    }
+
+    fn field_values(
+        &self,
+        doc: &DatabaseDoc,
+        authors: &[Name],
+        number: usize,
+    ) -> HashMap<&'static str, String> {
+        let extra = &doc.extra;
+        let (month, month_abbr) = match extra.month {
+            1..=12 => (
+                MONTHS[extra.month as usize - 1],
+                MONTH_ABBRS[extra.month as usize - 1],
+            ),
+            _ => ("", ""),
+        };
+        let doi = if doc.doi.starts_with("10.") {
+            format!("https://doi.org/{}", doc.doi)
+        } else {
+            doc.doi.clone()
+        };
+        let number_or_empty = |n: u32| match n {
+            0 => String::new(),
+            n => n.to_string(),
+        };
+        let values = [
+            ("authors", self.names.format_list(authors)),
+            (
+                "editor",
+                self.names.format_list(&Name::parse_list(&extra.editor)),
+            ),
+            ("title", doc.title.clone()),
+            ("year", number_or_empty(doc.year as u32)),
+            ("month", month.to_string()),
+            ("month_abbr", month_abbr.to_string()),
+            ("publication", doc.publication.clone()),
+            ("volume", number_or_empty(doc.volume as u32)),
+            ("issue", extra.issue.clone()),
+            (
+                "pages",
+                extra
+                    .pages
+                    .replace("--", "\u{2013}")
+                    .replace('-', "\u{2013}"),
+            ),
+            ("publisher", extra.publisher.clone()),
+            ("edition", ordinal(&extra.edition)),
+            ("isbn", extra.isbn.clone()),
+            ("url", extra.url.clone()),
+            ("institution", extra.institution.clone()),
+            ("genre", extra.genre.clone()),
+            ("booktitle", extra.booktitle.clone()),
+            ("doi", doi),
+            ("doi_id", doc.doi.clone()),
+            ("number", number.to_string()),
+        ];
+        return values
+            .into_iter()
+            .map(|(name, value)| (name, value.trim().to_string()))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+    }
+
+    /// Write rendered text in the output markup.  Periods and commas after a period, question
+    /// mark or exclamation mark are dropped, and with
+    /// `punctuation_in_quote` a comma or period after a quoted value moves inside the quotes.
+    fn write(&self, atoms: &[Atom], format: CitationFormat) -> String {
+        let mut out = String::new();
+        let mut last = ' ';
+        let mut open_quote = false;
+        for atom in atoms.iter() {
+            let mut text = atom.text.as_str();
+            if (text.starts_with('.') && last == '.')
+                || (text.starts_with(['.', ',']) && matches!(last, '?' | '!'))
+            {
+                text = &text[1..];
+            }
+            if open_quote {
+                if self.punctuation_in_quote && text.starts_with([',', '.']) {
+                    out.push_str(&text[..1]);
+                    text = &text[1..];
+                }
+                out.push('\u{201d}');
+                open_quote = false;
+            }
+            if text.is_empty() {
+                continue;
+            }
+            let escaped = escape(text, format);
+            match (atom.emphasis, format) {
+                (Emphasis::Quoted, _) => {
+                    out.push('\u{201c}');
+                    out.push_str(&escaped);
+                    open_quote = true;
+                }
+                (Emphasis::Italic, CitationFormat::Html) => {
+                    out.push_str(&format!("<i>{}</i>", escaped));
+                }
+                (Emphasis::Italic, CitationFormat::Markdown) => {
+                    out.push_str(&format!("*{}*", escaped));
+                }
+                (Emphasis::Link, CitationFormat::Html) if text.starts_with("http") => {
+                    out.push_str(&format!("<a href=\"{}\">{}</a>", escaped, escaped));
+                }
+                (Emphasis::Link, CitationFormat::Markdown) if text.starts_with("http") => {
+                    out.push_str(&format!("<{}>", text));
+                }
+                _ => out.push_str(&escaped),
+            }
+            last = text.chars().last().unwrap_or(last);
+        }
+        if open_quote {
+            out.push('\u{201d}');
+        }
+        let collapsed = out
+            .split(' ')
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<&str>>();
+        return collapsed.join(" ");
+    }
+}
+
+/// Formatted citations of the documents with `ids`, numbered in the order given.
+pub async fn cite(
+    ids: &[u32],
+    style: &CitationStyle,
+    format: CitationFormat,
+    pool: &SqlitePool,
+) -> anyhow::Result<Vec<String>> {
+    let mut citations = Vec::new();
+    for (i, id) in ids.iter().enumerate() {
+        let doc = match DatabaseDoc::from_id(*id, pool).await? {
+            Some(doc) => doc,
+            None => return Err(anyhow::anyhow!("Document with id {} does not exist", id)),
+        };
+        doc.ensure_not_trashed()?;
+        let authors = DatabaseAuthor::for_doc(doc.id, pool)
+            .await?
+            .iter()
+            .map(|a| a.name())
+            .collect::<Vec<Name>>();
+        let citation = style.format(&doc, &authors, i + 1, format)?;
+        citations.push(match format {
+            CitationFormat::Html => format!("<p>{}</p>", citation),
+            _ => citation,
+        });
+    }
+    return Ok(citations);
+}
+
+fn format_name(name: &Name, form: NameForm) -> String {
+    if name.first.is_empty() {
+        return name.last.clone();
+    }
+    let mut formatted = match form {
+        NameForm::LastInitials => format!("{}, {}", name.last, initials(&name.first)),
+        NameForm::InitialsLast => format!("{} {}", initials(&name.first), name.last),
+        NameForm::LastFirst => format!("{}, {}", name.last, name.first),
+        NameForm::FirstLast => format!("{} {}", name.first, name.last),
+    };
+    if !name.suffix.is_empty() {
+        formatted.push_str(", ");
+        formatted.push_str(&name.suffix);
+    }
+    return formatted;
+}
+
+/// Initials of given names: `Jean-Paul Ann` becomes `J.-P. A.`
+fn initials(first: &str) -> String {
+    return first
+        .split_whitespace()
+        .map(|word| {
+            word.split('-')
+                .filter_map(|part| part.chars().find(|c| c.is_alphabetic()))
+                .map(|c| format!("{}.", c.to_uppercase()))
+                .collect::<Vec<String>>()
+                .join("-")
+        })
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ");
+}
+
+/// `3` becomes `3rd`; editions that are not plain numbers are kept.
+fn ordinal(edition: &str) -> String {
+    let n = match edition.trim().parse::<u32>() {
+        Ok(n) => n,
+        Err(_) => return edition.to_string(),
+    };
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    return format!("{}{}", n, suffix);
+}
+
+fn escape(text: &str, format: CitationFormat) -> String {
+    return match format {
+        CitationFormat::Text => text.to_string(),
+        CitationFormat::Html => text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;"),
+        CitationFormat::Markdown => {
+            let mut escaped = String::new();
+            for c in text.chars() {
+                if matches!(c, '\\' | '*' | '_' | '[' | ']' | '<' | '>' | '`') {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        }
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Emphasis {
+    Plain,
+    Italic,
+    Quoted,
+    /// URLs and DOIs, linked in HTML and Markdown.
+    Link,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Field {
+        /// Field names, or literal text for quoted alternatives.
+        alternatives: Vec<Alternative>,
+        emphasis: Emphasis,
+    },
+    Group(Vec<Node>),
+}
+
+#[derive(Debug)]
+enum Alternative {
+    Field(String),
+    Literal(String),
+}
+
+#[derive(Debug)]
+struct Atom {
+    text: String,
+    emphasis: Emphasis,
+}
+
+fn parse_template(template: &str) -> anyhow::Result<Vec<Node>> {
+    return parse_nodes(&mut template.chars(), false);
+}
+
+fn parse_nodes(chars: &mut std::str::Chars, in_group: bool) -> anyhow::Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None if in_group => return Err(anyhow::anyhow!("unclosed '['")),
+            None => break,
+        };
+        if matches!(c, '[' | ']' | '{') && !text.is_empty() {
+            nodes.push(Node::Text(std::mem::take(&mut text)));
+        }
+        match c {
+            '\\' => text.extend(chars.next()),
+            '[' => nodes.push(Node::Group(parse_nodes(chars, true)?)),
+            ']' if in_group => return Ok(nodes),
+            ']' => return Err(anyhow::anyhow!("unmatched ']'")),
+            '{' => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => return Err(anyhow::anyhow!("unclosed '{{'")),
+                    }
+                }
+                nodes.push(parse_field(&spec)?);
+            }
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        nodes.push(Node::Text(text));
+    }
+    return Ok(nodes);
+}
+
+fn parse_field(spec: &str) -> anyhow::Result<Node> {
+    let (fields, emphasis) = match spec.rsplit_once(':') {
+        Some((fields, "italic")) => (fields, Emphasis::Italic),
+        Some((fields, "quoted")) => (fields, Emphasis::Quoted),
+        Some((_, other)) if !other.contains('"') => {
+            return Err(anyhow::anyhow!("unknown emphasis {:?}", other))
+        }
+        _ => (spec, Emphasis::Plain),
+    };
+    let mut alternatives = Vec::new();
+    for alternative in fields.split('|').map(|a| a.trim()) {
+        if let Some(literal) = alternative
+            .strip_prefix('"')
+            .and_then(|a| a.strip_suffix('"'))
+        {
+            alternatives.push(Alternative::Literal(literal.to_string()));
+        } else if CITE_FIELDS.contains(&alternative) {
+            alternatives.push(Alternative::Field(alternative.to_string()));
+        } else {
+            return Err(anyhow::anyhow!(
+                "unknown field {:?}: expected one of {}",
+                alternative,
+                CITE_FIELDS.join(", ")
+            ));
+        }
+    }
+    return Ok(Node::Field {
+        alternatives,
+        emphasis,
+    });
+}
+
+/// Render `nodes`, returning the text and whether any field inside was set.
+fn render(
+    nodes: &[Node],
+    values: &HashMap<&'static str, String>,
+    used: &mut HashSet<String>,
+) -> (Vec<Atom>, bool) {
+    let mut atoms = Vec::new();
+    let mut any_set = false;
+    for node in nodes.iter() {
+        match node {
+            Node::Text(text) => atoms.push(Atom {
+                text: text.clone(),
+                emphasis: Emphasis::Plain,
+            }),
+            Node::Field {
+                alternatives,
+                emphasis,
+            } => {
+                let found = alternatives.iter().find_map(|a| match a {
+                    Alternative::Field(name) if used.contains(name) => None,
+                    Alternative::Field(name) => values
+                        .get(name.as_str())
+                        .map(|v| (Some(name.clone()), v.clone())),
+                    Alternative::Literal(text) => Some((None, text.clone())),
+                });
+                if let Some((name, text)) = found {
+                    let emphasis = match (emphasis, name.as_deref()) {
+                        (Emphasis::Plain, Some("url" | "doi")) => Emphasis::Link,
+                        _ => *emphasis,
+                    };
+                    used.extend(name);
+                    atoms.push(Atom { text, emphasis });
+                    any_set = true;
+                }
+            }
+            Node::Group(children) => {
+                let mut group_used = used.clone();
+                let (group, set) = render(children, values, &mut group_used);
+                let has_fields = children.iter().any(|c| !matches!(c, Node::Text(_)));
+                if set || !has_fields {
+                    *used = group_used;
+                    atoms.extend(group);
+                    any_set |= set;
+                }
+            }
+        }
+    }
+    return (atoms, any_set);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doctype::DocType;
+    use crate::document::tests::database_doc;
+
+    /// `count` authors named `Author1, Alex`, `Author2, Alex`, ...
+    fn numbered_authors(count: usize) -> Vec<Name> {
+        return Name::parse_list(
+            &(1..=count)
+                .map(|i| format!("Author{}, Alex", i))
+                .collect::<Vec<String>>()
+                .join(" and "),
+        );
+    }
+
+    fn names(style: &str, count: usize) -> String {
+        return CitationStyle::load(style)
+            .unwrap()
+            .names
+            .format_list(&numbered_authors(count));
+    }
+
+    #[test]
+    fn apa_shortens_lists_of_21_or_more_names() {
+        let full = names("apa", 20);
+        assert!(full.starts_with("Author1, A., Author2, A., "), "{}", full);
+        assert!(full.ends_with("Author19, A., & Author20, A."), "{}", full);
+        let short = names("apa", 21);
+        assert!(
+            short.ends_with("Author18, A., Author19, A., . . . Author21, A."),
+            "{}",
+            short
+        );
+        assert!(!short.contains("Author20"), "{}", short);
+    }
+
+    #[test]
+    fn ieee_shortens_lists_of_more_than_6_names() {
+        assert_eq!(
+            names("ieee", 6),
+            "A. Author1, A. Author2, A. Author3, A. Author4, A. Author5, and A. Author6"
+        );
+        assert_eq!(names("ieee", 7), "A. Author1 et al.");
+    }
+
+    #[test]
+    fn mla_shortens_lists_of_3_or_more_names() {
+        assert_eq!(names("mla", 1), "Author1, Alex");
+        assert_eq!(names("mla", 2), "Author1, Alex, and Alex Author2");
+        assert_eq!(names("mla", 3), "Author1, Alex, et al.");
+    }
+
+    #[test]
+    fn templates_fall_back_and_drop_unset_groups() {
+        let style = CitationStyle::parse(
+            r#"
+            name = "Test"
+            [templates]
+            default = '{authors|title} ({year|"n.d."}). [{title}. ][Vol. {volume}. ]Done.'
+            "#,
+        )
+        .unwrap();
+        let mut doc = database_doc(DocType::Misc);
+        doc.year = 0;
+        doc.volume = 0;
+        // The title stands in for the authors and is not repeated
+        assert_eq!(
+            style.format(&doc, &[], 1, CitationFormat::Text).unwrap(),
+            "Deep Learning for Cats: A Survey (n.d.). Done."
+        );
+        doc.year = 2020;
+        doc.volume = 12;
+        assert_eq!(
+            style
+                .format(&doc, &numbered_authors(1), 1, CitationFormat::Text)
+                .unwrap(),
+            "Author1, A. (2020). Deep Learning for Cats: A Survey. Vol. 12. Done."
+        );
+        assert!(
+            CitationStyle::parse("name = \"Test\"\n[templates]\ndefault = '[{title}'").is_err()
+        );
+        assert!(CitationStyle::parse("name = \"Test\"\n[templates]\ndefault = '{titel}'").is_err());
+    }
+
+    #[test]
+    fn citations_escape_markup() {
+        let style = CitationStyle::load("apa").unwrap();
+        let mut doc = database_doc(DocType::Misc);
+        doc.title = "Cats & <Dogs> *not* [mice]".to_string();
+        doc.extra.publisher = "A_B".to_string();
+        doc.doi = String::new();
+        doc.extra.url = String::new();
+        assert_eq!(
+            style
+                .format(&doc, &numbered_authors(1), 1, CitationFormat::Html)
+                .unwrap(),
+            "Author1, A. (2020). <i>Cats &amp; &lt;Dogs&gt; *not* [mice]</i>. A_B."
+        );
+        assert_eq!(
+            style
+                .format(&doc, &numbered_authors(1), 1, CitationFormat::Markdown)
+                .unwrap(),
+            "Author1, A. (2020). *Cats & \\<Dogs\\> \\*not\\* \\[mice\\]*. A\\_B."
+        );
+    }
+
+    #[test]
+    fn ieee_abbreviates_months() {
+        let style = CitationStyle::load("ieee").unwrap();
+        let mut doc = database_doc(DocType::Article);
+        for (month, abbr) in [(5, "May 2020"), (6, "Jun. 2020"), (7, "Jul. 2020")] {
+            doc.extra.month = month;
+            let citation = style
+                .format(&doc, &numbered_authors(1), 1, CitationFormat::Text)
+                .unwrap();
+            assert!(citation.contains(abbr), "{}", citation);
+        }
+    }
+
+    #[test]
+    fn theses_are_described_by_their_genre() {
+        let mut doc = database_doc(DocType::Thesis);
+        let cite = |style: &str, doc: &DatabaseDoc| {
+            CitationStyle::load(style)
+                .unwrap()
+                .format(doc, &numbered_authors(1), 1, CitationFormat::Text)
+                .unwrap()
+        };
+        assert!(cite("apa", &doc).contains("[Thesis, University of Cats]"));
+        doc.extra.genre = "Master's thesis".to_string();
+        assert!(cite("apa", &doc).contains("[Master's thesis, University of Cats]"));
+        for style in ["ieee", "chicago", "mla"] {
+            let citation = cite(style, &doc);
+            assert!(citation.contains("Master's thesis"), "{}", citation);
+            assert!(!citation.contains("PhD"), "{}", citation);
+        }
+    }
+}
@@ -1,6 +1,16 @@
 use crate::{
-    document::DatabaseDoc,
-    tag::{DatabaseTag, TagInputList, TagList},
+    attachment::{AttachmentRole, AttachmentSelector},
+    cite::CitationFormat,
+    config::ListFormat,
+    doctype::{parse_month, DocType, ExtraFields},
+    document::{DatabaseDoc, DocList, DuplicatePolicy},
+    filetype::FileType,
+    filter::{DocFilter, SortKey, YearRange},
+    library::LibraryFormat,
+    pdfmeta::PdfMetadata,
+    query::TagQuery,
+    tag::TagInputList,
+    trash::Age,
     Document, Tag,
 };
 use clap::{Args, Parser, Subcommand};
@@ -8,6 +18,14 @@ use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 pub struct Cli {
+    /// Configuration file to use instead of the default in the XDG config directory.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Run the checks of a command and print the rows and files it would change, without
+    /// changing anything.  Supported by the `doc`, `tag` and `author` commands except
+    /// `doc reindex`, and by commands that only read the library.
+    #[arg(long, global = true)]
+    pub dry_run: bool,
     #[command(subcommand)]
     pub entity_type: EntityType,
 }
@@ -19,6 +37,113 @@ pub enum EntityType {
     #[command(name = "doc")]
     /// Operations for document records.
     Document(DocCmd),
+    /// Operations for author records.
+    Author(AuthorCmd),
+    /// Operations for the library database itself.
+    Db(DbCmd),
+    /// Operations on the whole library of documents.
+    Library(LibraryCmd),
+}
+
+#[derive(Debug, Args)]
+pub struct LibraryCmd {
+    /// Operation to execute on the library.
+    #[command(subcommand)]
+    pub command: LibrarySubCmd,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LibrarySubCmd {
+    /// Write every document and its attachments in the format `doc add from-toml` reads.
+    Export(ExportLibrary),
+    /// Archive a consistent snapshot of the database and every stored file.
+    Backup(BackupLibrary),
+    /// Restore a backup archive into a new directory.
+    Restore(RestoreLibrary),
+    /// Report inconsistencies between the database and the stored files.
+    Check(CheckLibrary),
+}
+
+#[derive(Debug, Args)]
+pub struct ExportLibrary {
+    /// File format of the export.
+    #[arg(long, value_enum, default_value = "toml")]
+    pub format: LibraryFormat,
+    /// File to write the export to.  Defaults to stdout.
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+    /// Copy the stored files, under their original filenames, into a `<output>_files`
+    /// directory next to the export and point the exported paths at the copies.
+    #[arg(long, requires = "output")]
+    pub bundle: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BackupLibrary {
+    /// Archive to write, conventionally named `*.tar.zst`.
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckLibrary {
+    /// Add orphan files as documents, remove unused tags and dangling tag links, and
+    /// recreate tags that documents link to but that do not exist.
+    #[arg(long)]
+    pub repair: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct RestoreLibrary {
+    /// Archive written by `library backup`.
+    pub archive: PathBuf,
+    /// New or empty directory to restore the database and stored files into.  Point
+    /// `db_path` and `store_dir` at the restored copies to use them.
+    pub target: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct AuthorCmd {
+    /// Operation to execute on author records.
+    #[command(subcommand)]
+    pub command: AuthorSubCmd,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuthorSubCmd {
+    /// List all author records with their number of documents.
+    List,
+    /// Merge spelling variants of an author into one record.
+    Merge(MergeAuthors),
+}
+
+#[derive(Debug, Args)]
+pub struct MergeAuthors {
+    /// IDs of the author records to merge away.
+    #[arg(required = true)]
+    pub ids: Vec<u32>,
+    /// ID of the author record that replaces them in every document.
+    #[arg(long, required = true)]
+    pub into: u32,
+}
+
+#[derive(Debug, Args)]
+pub struct DbCmd {
+    /// Operation to execute on the library database.
+    #[command(subcommand)]
+    pub command: DbSubCmd,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DbSubCmd {
+    /// Apply pending schema migrations.
+    Migrate(MigrateDb),
+}
+
+#[derive(Debug, Args)]
+pub struct MigrateDb {
+    /// Show applied and pending migrations without applying anything.
+    #[arg(long)]
+    pub status: bool,
 }
 
 #[derive(Debug, Args)]
@@ -105,12 +230,24 @@ pub enum DocSubCmd {
     Add(AddDoc),
     /// Modify stored document information.
     Modify(ModifyDoc),
-    /// Delete a document record.  Also deletes the reference copy of the PDF.
+    /// Move a document record to the trash, with the reference copy of the document file.
     Delete(DeleteDoc),
     /// List all document records.
     List(ListDoc),
     /// Open a stored document by id or title.
     Open(OpenDoc),
+    /// Manage additional files stored with a document, such as slides or datasets.
+    Attach(AttachCmd),
+    /// Export document records in a citation format.
+    Export(ExportDoc),
+    /// Print formatted references to documents, for pasting into documents and emails.
+    Cite(CiteDoc),
+    /// Search the text of stored documents.
+    Search(SearchDoc),
+    /// Add stored documents to the full-text search index.
+    Reindex(ReindexDoc),
+    /// List, restore or permanently delete the documents in the trash.
+    Trash(TrashCmd),
 }
 
 #[derive(Debug, Args)]
@@ -118,63 +255,225 @@ pub struct AddDoc {
     /// Method for adding document records.
     #[command(subcommand)]
     pub source: AddDocSubCmd,
+    /// What to do with a file whose contents are already stored.
+    #[arg(long, value_enum, default_value = "reject", global = true)]
+    pub on_duplicate: DuplicatePolicy,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum AddDocSubCmd {
-    /// Add a single document by entering field values through CLI options.
-    Single(SingleDoc),
-    /// Add one or multiple documents from specifications in a TOML document.
+    /// Add a single document by entering field values through CLI options.  Fields that are
+    /// not given are read from the PDF metadata.
+    Single(Box<SingleDoc>),
+    /// Add one or multiple documents from specifications in a TOML document, or a JSON
+    /// document of the same structure as written by `library export`.
     FromToml(AddDocTomlPath),
+    /// Add the entries of a BibTeX or BibLaTeX database.
+    FromBibtex(AddDocBibtexPath),
+    /// Add the records of a RIS file, as exported by EndNote, Zotero or Mendeley.
+    FromRis(AddDocRisPath),
+    /// Add the items of a CSL-JSON file, as read by Pandoc citeproc.
+    FromCslJson(AddDocCslJsonPath),
+}
+
+#[derive(Debug, Args)]
+pub struct AddDocBibtexPath {
+    /// Location of the .bib file to be parsed for document record information.
+    pub path: PathBuf,
+    /// Directory containing the document files.  Relative `file` fields and
+    /// `<citation key>.<ext>` names are resolved against it.  Defaults to the directory of the
+    /// .bib file.
+    #[arg(long)]
+    pub files_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct AddDocRisPath {
+    /// Location of the .ris file to be parsed for document record information.
+    pub path: PathBuf,
+    /// Directory containing the document files.  Relative `L1` links and `<ID>.<ext>` names
+    /// are resolved against it.  Defaults to the directory of the .ris file.
+    #[arg(long)]
+    pub files_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct AddDocCslJsonPath {
+    /// Location of the .json file to be parsed for document record information.
+    pub path: PathBuf,
+    /// Directory containing the document files.  Relative `file` variables and
+    /// `<id>.<ext>` names are resolved against it.  Defaults to the directory of the .json
+    /// file.
+    #[arg(long)]
+    pub files_dir: Option<PathBuf>,
 }
 
 #[derive(Debug, Args)]
 pub struct AddDocTomlPath {
-    /// Location of the TOML file to be parsed for document record information.
+    /// Location of the TOML or JSON file to be parsed for document record information.
+    /// Relative document paths are resolved against its directory.
     pub path: PathBuf,
+    /// Check every entry before adding any, then add all of them in one transaction.  Nothing
+    /// is added if any entry fails its checks.
+    #[arg(long, conflicts_with = "continue_on_error")]
+    pub atomic: bool,
+    /// Add the entries that can be added instead of stopping at the first failure, and
+    /// print which entries were added, skipped or failed.
+    #[arg(long)]
+    pub continue_on_error: bool,
 }
 
 impl std::convert::From<&str> for AddDocTomlPath {
     fn from(value: &str) -> Self {
         return Self {
             path: PathBuf::from(value),
+            atomic: false,
+            continue_on_error: false,
         };
     }
 }
 
+/// Field values for a single document.  Fields that are not given are filled in from the
+/// metadata of the file where possible, which is currently only done for PDFs.
 #[derive(Debug, Args)]
 pub struct SingleDoc {
-    #[arg(long, value_parser = Document::input_to_lowercase, required = true)]
-    pub title: String,
-    #[arg(long, value_parser = Document::input_to_lowercase, default_value = "")]
-    pub author: String,
-    #[arg(long, default_value = "0")]
-    pub year: u16,
-    #[arg(long, value_parser = Document::input_to_lowercase, default_value = "")]
-    pub publication: String,
-    #[arg(long, default_value = "0")]
-    pub volume: u16,
-    #[arg(long, value_parser = Document::input_to_lowercase, default_value = "")]
-    pub tags: String,
-    #[arg(long, default_value = "")]
-    pub doi: String,
+    #[arg(long, value_parser = Document::input_trimmed)]
+    pub title: Option<String>,
+    #[arg(long, value_parser = Document::input_trimmed)]
+    pub author: Option<String>,
+    #[arg(long)]
+    pub year: Option<u16>,
+    #[arg(long, value_parser = Document::input_trimmed)]
+    pub publication: Option<String>,
+    #[arg(long)]
+    pub volume: Option<u16>,
+    #[arg(long, value_parser = Document::input_to_lowercase)]
+    pub tags: Option<String>,
+    #[arg(long)]
+    pub doi: Option<String>,
+    /// Kind of work.  Inferred from the fields that are given when omitted.
+    #[arg(long, value_enum)]
+    pub doc_type: Option<DocType>,
+    #[command(flatten)]
+    pub extra: ExtraFieldArgs,
     #[arg(long, value_parser = Document::verify_path, required = true)]
     pub path: PathBuf,
+    /// Do not read metadata from the PDF; only the given fields are used.
+    #[arg(long)]
+    pub no_extract: bool,
 }
 
-impl std::convert::Into<Document> for SingleDoc {
-    fn into(self) -> Document {
-        return Document {
+impl SingleDoc {
+    /// Build the document, preferring explicitly given fields over extracted metadata.
+    pub fn into_document(self) -> anyhow::Result<Document> {
+        let is_pdf = FileType::from_path(&self.path)? == FileType::Pdf;
+        let meta = if self.no_extract || !is_pdf {
+            PdfMetadata::default()
+        } else {
+            match PdfMetadata::extract(&self.path) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    log::warn!("{}", e);
+                    PdfMetadata::default()
+                }
+            }
+        };
+        let doi = meta.doi_or_arxiv();
+        let title = match self.title.or(meta.title) {
+            Some(title) => title,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "No title given and none found in the metadata of {:?}",
+                    self.path
+                ))
+            }
+        };
+        let tags = match self.tags {
+            Some(tags) => tags,
+            None => meta.keywords.join(",").to_lowercase(),
+        };
+        let mut extra = ExtraFields::default();
+        if let Some(subject) = meta.subject {
+            extra.abstract_ = subject;
+        }
+        self.extra.apply(&mut extra);
+        return Ok(Document {
             id: None,
-            title: self.title,
-            author: self.author,
-            year: self.year,
-            publication: self.publication,
-            volume: self.volume,
-            tags: self.tags,
-            doi: self.doi,
+            title,
+            author: self.author.or(meta.author).unwrap_or_default(),
+            year: self.year.or(meta.year).unwrap_or(0),
+            publication: self.publication.or(meta.publication).unwrap_or_default(),
+            volume: self.volume.or(meta.volume).unwrap_or(0),
+            tags,
+            doi: self.doi.or(doi).unwrap_or_default(),
+            doc_type: self.doc_type,
+            extra,
+            filename: String::new(),
+            attachments: Vec::new(),
             path: self.path,
-        };
+        });
+    }
+}
+
+/// Optional fields beyond those of a journal article.  Which ones a document needs depends on
+/// its type.
+#[derive(Debug, Args)]
+pub struct ExtraFieldArgs {
+    #[arg(long)]
+    pub issue: Option<String>,
+    /// Page range, e.g. `101--115`.
+    #[arg(long)]
+    pub pages: Option<String>,
+    #[arg(long, value_parser = Document::input_trimmed)]
+    pub publisher: Option<String>,
+    #[arg(long)]
+    pub edition: Option<String>,
+    #[arg(long)]
+    pub isbn: Option<String>,
+    #[arg(long)]
+    pub url: Option<String>,
+    /// University, company or standards body.
+    #[arg(long, value_parser = Document::input_trimmed)]
+    pub institution: Option<String>,
+    /// Kind of work, such as `PhD thesis` or `Master's thesis`.
+    #[arg(long, value_parser = Document::input_trimmed)]
+    pub genre: Option<String>,
+    #[arg(long, value_parser = Document::input_trimmed)]
+    pub editor: Option<String>,
+    /// Title of the proceedings or collection the document appears in.
+    #[arg(long, value_parser = Document::input_trimmed)]
+    pub booktitle: Option<String>,
+    /// Month of publication as a number or name.
+    #[arg(long, value_parser = parse_month)]
+    pub month: Option<u8>,
+    #[arg(long = "abstract")]
+    pub abstract_: Option<String>,
+}
+
+impl ExtraFieldArgs {
+    /// Overwrite the fields of `extra` that were given.
+    pub fn apply(self, extra: &mut ExtraFields) {
+        let fields = [
+            (self.issue, &mut extra.issue),
+            (self.pages, &mut extra.pages),
+            (self.publisher, &mut extra.publisher),
+            (self.edition, &mut extra.edition),
+            (self.isbn, &mut extra.isbn),
+            (self.url, &mut extra.url),
+            (self.institution, &mut extra.institution),
+            (self.genre, &mut extra.genre),
+            (self.editor, &mut extra.editor),
+            (self.booktitle, &mut extra.booktitle),
+            (self.abstract_, &mut extra.abstract_),
+        ];
+        for (value, field) in fields {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        if let Some(month) = self.month {
+            extra.month = month;
+        }
     }
 }
 
@@ -188,22 +487,22 @@ pub struct ModifyDoc {
 #[derive(Debug, Subcommand)]
 pub enum ModifyDocSubCmd {
     /// Specify which document record to modify by its ID.
-    ById(ModifyFieldById),
+    ById(Box<ModifyFieldById>),
     /// Specify which document record to modify by its title.
-    ByTitle(ModifyFieldByTitle),
+    ByTitle(Box<ModifyFieldByTitle>),
 }
 
 #[derive(Debug, Args)]
 pub struct ModifyFieldById {
     #[arg(required = true)]
     pub id: u32,
-    #[arg(long, value_parser = Document::input_to_lowercase)]
+    #[arg(long, value_parser = Document::input_trimmed)]
     pub title: Option<String>,
-    #[arg(long, value_parser = Document::input_to_lowercase)]
+    #[arg(long, value_parser = Document::input_trimmed)]
     pub author: Option<String>,
     #[arg(long)]
     pub year: Option<u16>,
-    #[arg(long, value_parser = Document::input_to_lowercase)]
+    #[arg(long, value_parser = Document::input_trimmed)]
     pub publication: Option<String>,
     #[arg(long)]
     pub volume: Option<u16>,
@@ -211,17 +510,22 @@ pub struct ModifyFieldById {
     pub tags: Option<String>,
     #[arg(long)]
     pub doi: Option<String>,
+    #[arg(long, value_enum)]
+    pub doc_type: Option<DocType>,
+    #[command(flatten)]
+    pub extra: ExtraFieldArgs,
 }
 
 impl ModifyFieldById {
     pub async fn update_doc(self, pool: &sqlx::SqlitePool) -> anyhow::Result<()> {
-        let mut doc = match DatabaseDoc::from_id(self.id, &pool).await? {
+        let mut doc = match DatabaseDoc::from_id(self.id, pool).await? {
             Some(doc) => doc,
             None => {
                 log::error!("Invalid ID: not found in database");
                 return Err(anyhow::anyhow!("No document with ID: {}", self.id));
             }
         };
+        doc.ensure_not_trashed()?;
         if let Some(title) = self.title {
             doc.title = title;
         }
@@ -238,35 +542,29 @@ impl ModifyFieldById {
             doc.volume = volume;
         }
         if let Some(tags) = self.tags {
-            let input_tags = TagInputList::from(tags.as_str());
-            let db_tags = TagList::get_all(&pool).await?;
-            for itag in input_tags.0.iter() {
-                if db_tags.0.iter().find(|dbt| dbt.value == *itag).is_none() {
-                    log::info!("Adding tag to DB: {}", itag);
-                    let new_tag = Tag::new(&itag);
-                    DatabaseTag::from_insert(new_tag, pool).await?;
-                } else {
-                    log::debug!("Tag already exists in DB: {}", itag);
-                }
-            }
-            doc.tags = input_tags.tag_values().clone().join(",");
+            // Missing tags are added to the tags table when the document links are updated
+            doc.tags = TagInputList::from(tags.as_str()).tag_values().join(",");
         }
         if let Some(doi) = self.doi {
             doc.doi = doi;
         }
+        if let Some(doc_type) = self.doc_type {
+            doc.doc_type = doc_type;
+        }
+        self.extra.apply(&mut doc.extra);
         return doc.update(pool).await;
     }
 }
 
 #[derive(Debug, Args)]
 pub struct ModifyFieldByTitle {
-    #[arg(long, required = true, value_parser = Document::input_to_lowercase)]
+    #[arg(long, required = true, value_parser = Document::input_trimmed)]
     pub title: String,
-    #[arg(long, value_parser = Document::input_to_lowercase)]
+    #[arg(long, value_parser = Document::input_trimmed)]
     pub author: Option<String>,
     #[arg(long)]
     pub year: Option<u16>,
-    #[arg(long, value_parser = Document::input_to_lowercase)]
+    #[arg(long, value_parser = Document::input_trimmed)]
     pub publication: Option<String>,
     #[arg(long)]
     pub volume: Option<u16>,
@@ -274,11 +572,15 @@ pub struct ModifyFieldByTitle {
     pub tags: Option<String>,
     #[arg(long)]
     pub doi: Option<String>,
+    #[arg(long, value_enum)]
+    pub doc_type: Option<DocType>,
+    #[command(flatten)]
+    pub extra: ExtraFieldArgs,
 }
 
 impl ModifyFieldByTitle {
     pub async fn update_doc(self, pool: &sqlx::SqlitePool) -> anyhow::Result<()> {
-        let mut doc = match DatabaseDoc::from_title(&self.title, &pool).await? {
+        let mut doc = match DatabaseDoc::from_title(&self.title, pool).await? {
             Some(doc) => doc,
             None => {
                 log::error!("No document found with title: {}", self.title);
@@ -288,6 +590,7 @@ impl ModifyFieldByTitle {
                 ));
             }
         };
+        doc.ensure_not_trashed()?;
         if let Some(author) = self.author {
             doc.author = author;
         }
@@ -301,22 +604,16 @@ impl ModifyFieldByTitle {
             doc.volume = volume;
         }
         if let Some(tags) = self.tags {
-            let input_tags = TagInputList::from(tags.as_str());
-            let db_tags = TagList::get_all(&pool).await?;
-            for itag in input_tags.0.into_iter() {
-                if db_tags.0.iter().find(|dbt| dbt.value == itag).is_none() {
-                    log::info!("Adding tag to DB: {}", itag);
-                    let new_tag = Tag::new(&itag);
-                    DatabaseTag::from_insert(new_tag, pool).await?;
-                } else {
-                    log::debug!("Tag already exists in DB: {}", itag);
-                }
-            }
-            doc.tags = tags;
+            // Missing tags are added to the tags table when the document links are updated
+            doc.tags = TagInputList::from(tags.as_str()).tag_values().join(",");
         }
         if let Some(doi) = self.doi {
             doc.doi = doi;
         }
+        if let Some(doc_type) = self.doc_type {
+            doc.doc_type = doc_type;
+        }
+        self.extra.apply(&mut doc.extra);
         return doc.update(pool).await;
     }
 }
@@ -324,28 +621,280 @@ impl ModifyFieldByTitle {
 #[derive(Debug, Args)]
 #[group(required = true, multiple = false)]
 pub struct DeleteDoc {
-    /// ID of the document record to be deleted.
+    /// ID of the document record to be moved to the trash.
     #[arg(long)]
     pub id: Option<u32>,
-    /// Title of the document record to be deleted.
+    /// Title of the document record to be moved to the trash.
     #[arg(long)]
     pub title: Option<String>,
 }
 
+/// A single document record, picked by id or title.
 #[derive(Debug, Args)]
 #[group(required = true, multiple = false)]
-pub struct OpenDoc {
-    /// ID of the document record to open.
+pub struct DocRef {
+    /// ID of the document record.
     #[arg(long)]
     pub id: Option<u32>,
-    /// Title of the document record to open.
+    /// Title of the document record.
     #[arg(long)]
     pub title: Option<String>,
 }
 
+impl DocRef {
+    pub async fn resolve(&self, pool: &sqlx::SqlitePool) -> anyhow::Result<DatabaseDoc> {
+        let doc = match self {
+            Self { id: Some(id), .. } => DatabaseDoc::from_id(*id, pool)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Document with id {} does not exist", id)),
+            Self {
+                title: Some(title), ..
+            } => DatabaseDoc::from_title(title, pool)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Document does not exist: {:?}", title)),
+            _ => Err(anyhow::anyhow!("Must provide ID or TITLE")),
+        }?;
+        doc.ensure_not_trashed()?;
+        return Ok(doc);
+    }
+}
+
 #[derive(Debug, Args)]
-pub struct ListDoc {
-    /// List document records containing this tag.
+pub struct OpenDoc {
+    #[command(flatten)]
+    pub doc: DocRef,
+    /// Open an attachment instead of the main file, by attachment id or role.
+    #[arg(long)]
+    pub attachment: Option<AttachmentSelector>,
+}
+
+#[derive(Debug, Args)]
+pub struct TrashCmd {
+    #[command(subcommand)]
+    pub command: TrashSubCmd,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TrashSubCmd {
+    /// List the document records in the trash.
+    List,
+    /// Move a document record out of the trash, back into the library.
+    Restore(RestoreTrash),
+    /// Delete the document records in the trash and their stored files for good.
+    Empty(EmptyTrash),
+}
+
+#[derive(Debug, Args)]
+pub struct RestoreTrash {
+    /// ID of the document record, as shown by `doc trash list`.
+    pub id: u32,
+}
+
+#[derive(Debug, Args)]
+pub struct EmptyTrash {
+    /// Only delete document records that have been in the trash at least this long, e.g.
+    /// `12h`, `30d` or `2w`.
+    #[arg(long)]
+    pub older_than: Option<Age>,
+}
+
+#[derive(Debug, Args)]
+pub struct AttachCmd {
+    #[command(subcommand)]
+    pub command: AttachSubCmd,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AttachSubCmd {
+    /// Attach a file to a document record.
+    Add(AddAttachment),
+    /// List the attachments of a document record.
+    List(ListAttachments),
+    /// Remove an attachment and its stored file.
+    Remove(AttachmentId),
+    /// Open an attachment.
+    Open(AttachmentId),
+}
+
+#[derive(Debug, Args)]
+pub struct AddAttachment {
+    #[command(flatten)]
+    pub doc: DocRef,
+    /// What the file is to the document.
+    #[arg(long, value_enum, default_value = "supplement")]
+    pub role: AttachmentRole,
+    /// Location of the file to attach.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ListAttachments {
+    #[command(flatten)]
+    pub doc: DocRef,
+}
+
+#[derive(Debug, Args)]
+pub struct AttachmentId {
+    /// ID of the attachment, as shown by `doc attach list`.
+    pub id: u32,
+}
+
+/// Options selecting which document records a command operates on.  Without any option
+/// every record is selected.
+#[derive(Debug, Args)]
+pub struct DocSelection {
+    /// Select document records containing this tag.
     #[arg(long)]
     pub tag: Option<String>,
+    /// Select document records by ID.  IDs must be comma separated.
+    #[arg(long, value_delimiter = ',', conflicts_with = "tag")]
+    pub id: Vec<u32>,
+    /// Select document records whose tags match a boolean expression, e.g.
+    /// `ml AND (survey OR review) AND NOT draft`.
+    #[arg(long, conflicts_with_all = ["tag", "id"])]
+    pub query: Option<TagQuery>,
+    /// Only document records by this author: an author id from `author list`, or part of
+    /// the name written `First Last` or `Last, First`.
+    #[arg(long)]
+    pub author: Option<String>,
+    /// Only document records whose publication contains this text.
+    #[arg(long)]
+    pub publication: Option<String>,
+    /// Only document records published in this year or range of years, e.g. `2015..2020`,
+    /// `2015..` or `..2020`.
+    #[arg(long)]
+    pub year: Option<YearRange>,
+    /// Only document records with this volume.
+    #[arg(long)]
+    pub volume: Option<u16>,
+    /// Only document records with (`true`) or without (`false`) a DOI.
+    #[arg(long)]
+    pub has_doi: Option<bool>,
+    /// Order of the selected document records.  Defaults to the order of `--id` when it is
+    /// given, and to `id` otherwise.
+    #[arg(long, value_enum)]
+    pub sort: Option<SortKey>,
+    /// Maximum number of document records to select.
+    #[arg(long)]
+    pub limit: Option<u32>,
+    /// Number of matching document records to skip.
+    #[arg(long)]
+    pub offset: Option<u32>,
+}
+
+impl DocSelection {
+    pub fn to_filter(&self) -> DocFilter {
+        let tags = match (&self.tag, &self.query) {
+            (Some(value), _) => Some(TagQuery::Tag(value.trim().to_lowercase())),
+            (None, Some(query)) => Some(query.clone()),
+            (None, None) => None,
+        };
+        return DocFilter {
+            ids: self.id.clone(),
+            tags,
+            author: self.author.clone(),
+            publication: self.publication.clone(),
+            year: self.year,
+            volume: self.volume,
+            has_doi: self.has_doi,
+            sort: self.sort.unwrap_or_default(),
+            limit: self.limit,
+            offset: self.offset,
+        };
+    }
+
+    /// The selected documents.  Every id given with `--id` must exist, and without `--sort`
+    /// the documents are in the order the ids were given.
+    pub async fn resolve(&self, pool: &sqlx::SqlitePool) -> anyhow::Result<DocList> {
+        if self.id.is_empty() {
+            return DocList::from_filter(&self.to_filter(), pool).await;
+        }
+        let requested = DocList::from_ids(&self.id, pool).await?;
+        if self.sort.is_some() {
+            return DocList::from_filter(&self.to_filter(), pool).await;
+        }
+        let filter = DocFilter {
+            limit: None,
+            offset: None,
+            ..self.to_filter()
+        };
+        let matching = DocList::from_filter(&filter, pool)
+            .await?
+            .iter()
+            .map(|doc| doc.id)
+            .collect::<std::collections::HashSet<u32>>();
+        return Ok(DocList(
+            requested
+                .0
+                .into_iter()
+                .filter(|doc| matching.contains(&doc.id))
+                .skip(self.offset.unwrap_or(0) as usize)
+                .take(self.limit.map_or(usize::MAX, |l| l as usize))
+                .collect(),
+        ));
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ListDoc {
+    #[command(flatten)]
+    pub selection: DocSelection,
+    /// Output format.  Defaults to `list_format` from the configuration file.
+    #[arg(long, value_enum)]
+    pub format: Option<ListFormat>,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// BibTeX database entries.
+    Bibtex,
+    /// RIS records, linking the stored document files.
+    Ris,
+    /// CSL-JSON items for Pandoc citeproc, with ids set to the citation keys.
+    CslJson,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportDoc {
+    /// Citation format to export.
+    #[arg(long, value_enum, default_value = "bibtex")]
+    pub format: ExportFormat,
+    #[command(flatten)]
+    pub selection: DocSelection,
+    /// File to write the export to.  Defaults to stdout.
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct CiteDoc {
+    /// IDs of the documents to cite, in reference list order.
+    #[arg(required = true)]
+    pub ids: Vec<u32>,
+    /// Citation style: apa, ieee, chicago or mla, the name of a style file in the configured
+    /// styles directory, or the path of a style file.
+    #[arg(long, default_value = "apa")]
+    pub style: String,
+    /// Markup of the citations.
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: CitationFormat,
+}
+
+#[derive(Debug, Args)]
+pub struct SearchDoc {
+    /// Phrase to search for in the text of stored documents.
+    pub phrase: String,
+    /// Interpret the phrase as an SQLite FTS5 query, e.g. `deep NEAR learning`.
+    #[arg(long)]
+    pub raw: bool,
+    /// Maximum number of results.
+    #[arg(long, default_value = "20")]
+    pub limit: u32,
+}
+
+#[derive(Debug, Args)]
+pub struct ReindexDoc {
+    /// Re-extract the text of every stored document, not only those missing from the index.
+    #[arg(long)]
+    pub all: bool,
 }
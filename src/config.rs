@@ -0,0 +1,318 @@
+use crate::filetype::FileType;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Environment variable naming the configuration file to load.
+pub const CONFIG_ENV: &str = "ODINSOURCE_CONFIG";
+/// Environment variable overriding `store_dir`.
+pub const STORE_DIR_ENV: &str = "ODINSOURCE_STORE_DIR";
+/// Environment variable overriding `db_path`.
+pub const DB_PATH_ENV: &str = "ODINSOURCE_DB_PATH";
+
+/// Database location of versions without a config file, relative to the working directory.
+const LEGACY_DB_PATH: &str = "odinsource.db";
+/// Store location of versions without a config file, which were run from the checkout they
+/// were built in.
+const LEGACY_STORE_DIR: &str = "documents";
+
+/// How document records are printed by listing commands.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ListFormat {
+    /// Every field of every record.
+    #[default]
+    Full,
+    /// One line per record with the id, title and year.
+    Brief,
+}
+
+/// Runtime configuration, read from `$XDG_CONFIG_HOME/odinsource/config.toml` unless another
+/// file is given with `--config` or `ODINSOURCE_CONFIG`.
+///
+/// ```toml
+/// store_dir = "~/papers/store"
+/// db_path = "~/papers/odinsource.db"
+/// list_format = "brief"
+/// styles_dir = "~/papers/styles"
+///
+/// [openers]
+/// default = "xdg-open"
+/// pdf = "zathura {}"
+/// markdown = "glow {}"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Directory holding the stored copies of documents.
+    pub store_dir: PathBuf,
+    /// Location of the SQLite library database.
+    pub db_path: PathBuf,
+    /// Command used to open documents, keyed by file extension or file type name (such as
+    /// `markdown`).  `{}` in the command is replaced by the document path; otherwise the path
+    /// is appended.  The `default` key is used for formats without their own entry.  The
+    /// command is split on whitespace without shell quoting, so the program and its
+    /// arguments cannot contain spaces.
+    pub openers: HashMap<String, String>,
+    /// Output format used by `doc list` when `--format` is not given.
+    pub list_format: ListFormat,
+    /// Directory searched for `<name>.toml` citation style files used by `doc cite --style`.
+    pub styles_dir: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        return Self {
+            store_dir: data_dir().join("documents"),
+            db_path: data_dir().join("odinsource.db"),
+            openers: HashMap::new(),
+            list_format: ListFormat::default(),
+            styles_dir: config_dir().join("styles"),
+        };
+    }
+}
+
+impl Config {
+    /// Load the configuration file, then apply environment variable overrides.  A missing
+    /// file is only an error when it was asked for explicitly.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let explicit = path
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from));
+        let mut config = match explicit {
+            Some(path) => Self::from_file(&path)?,
+            None => {
+                let path = config_dir().join("config.toml");
+                if path.is_file() {
+                    Self::from_file(&path)?
+                } else {
+                    log::debug!("No config file at {:?}, using defaults", path);
+                    Self::default()
+                }
+            }
+        };
+        if let Some(dir) = std::env::var_os(STORE_DIR_ENV) {
+            config.store_dir = PathBuf::from(dir);
+        }
+        if let Some(path) = std::env::var_os(DB_PATH_ENV) {
+            config.db_path = PathBuf::from(path);
+        }
+        config.store_dir = expand_home(&config.store_dir);
+        config.db_path = expand_home(&config.db_path);
+        config.styles_dir = expand_home(&config.styles_dir);
+        // Before the config file, the database was `odinsource.db` in the working directory
+        let legacy_db = PathBuf::from(LEGACY_DB_PATH);
+        if config.db_path == Self::default().db_path
+            && !config.db_path.exists()
+            && legacy_db.is_file()
+        {
+            log::warn!(
+                "Using the database {:?} in the working directory; move it to {:?} or set \
+                 db_path in the config file",
+                legacy_db,
+                config.db_path
+            );
+            config.db_path = legacy_db;
+        }
+        let legacy_store = PathBuf::from(LEGACY_STORE_DIR);
+        if config.store_dir == Self::default().store_dir
+            && !config.store_dir.exists()
+            && legacy_store.is_dir()
+        {
+            log::warn!(
+                "Using the document store {:?} in the working directory; move it to {:?} or \
+                 set store_dir in the config file",
+                legacy_store,
+                config.store_dir
+            );
+            config.store_dir = std::fs::canonicalize(&legacy_store).unwrap_or(legacy_store);
+        }
+        return Ok(config);
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let toml_str = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Could not read config file {:?}: {}", path, e))?;
+        let config: Self = toml::from_str(&toml_str)
+            .map_err(|e| anyhow::anyhow!("Invalid config file {:?}: {}", path, e))?;
+        log::debug!("Loaded config from {:?}", path);
+        return Ok(config);
+    }
+
+    /// Make `config` the configuration returned by `Config::get`.
+    pub fn init(config: Config) -> &'static Config {
+        if CONFIG.set(config).is_err() {
+            log::warn!("Configuration already initialized");
+        }
+        return Self::get();
+    }
+
+    /// The active configuration, or the defaults if none was initialized.  Tests get a store
+    /// in a temporary directory instead, so they never touch the user's library.
+    pub fn get() -> &'static Config {
+        #[cfg(test)]
+        return CONFIG.get_or_init(|| Config {
+            store_dir: std::env::temp_dir()
+                .join(format!("odinsource-test-{}", std::process::id()))
+                .join("documents"),
+            ..Config::default()
+        });
+        #[cfg(not(test))]
+        return CONFIG.get_or_init(Config::default);
+    }
+
+    /// Location of a file in the document store.
+    pub fn stored_file(&self, fname: &str) -> PathBuf {
+        return self.store_dir.join(fname);
+    }
+
+    /// Directory in the store that the files of documents in the trash are moved to.
+    pub fn trash_dir(&self) -> PathBuf {
+        return self.store_dir.join(".trash");
+    }
+
+    /// Location of a file in the trash directory.
+    pub fn trashed_file(&self, fname: &str) -> PathBuf {
+        return self.trash_dir().join(fname);
+    }
+
+    /// Build the command that opens `path`, using the opener configured for its extension.
+    pub fn opener_command(&self, path: &Path) -> anyhow::Result<std::process::Command> {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let opener = self
+            .openers
+            .get(&ext)
+            .or_else(|| FileType::from_extension(&ext).and_then(|t| self.openers.get(t.name())))
+            .or_else(|| self.openers.get("default"))
+            .map(|o| o.as_str())
+            .unwrap_or("xdg-open");
+        let mut parts = opener.split_whitespace();
+        let program = match parts.next() {
+            Some(program) => program,
+            None => return Err(anyhow::anyhow!("Empty opener configured for {:?}", ext)),
+        };
+        let mut command = std::process::Command::new(program);
+        let mut substituted = false;
+        for part in parts {
+            if part.contains("{}") {
+                command.arg(part.replace("{}", &path.to_string_lossy()));
+                substituted = true;
+            } else {
+                command.arg(part);
+            }
+        }
+        if !substituted {
+            command.arg(path);
+        }
+        return Ok(command);
+    }
+}
+
+fn home_dir() -> PathBuf {
+    return std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+}
+
+fn config_dir() -> PathBuf {
+    return match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir().join(".config"),
+    }
+    .join("odinsource");
+}
+
+fn data_dir() -> PathBuf {
+    return match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir().join(".local").join("share"),
+    }
+    .join("odinsource");
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    return match path.strip_prefix("~") {
+        Ok(rest) => home_dir().join(rest),
+        Err(_) => path.to_path_buf(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_home_only_replaces_leading_tilde() {
+        assert_eq!(
+            expand_home(Path::new("~/papers")),
+            home_dir().join("papers")
+        );
+        assert_eq!(expand_home(Path::new("~")), home_dir());
+        assert_eq!(
+            expand_home(Path::new("/srv/~/papers")),
+            Path::new("/srv/~/papers")
+        );
+        assert_eq!(
+            expand_home(Path::new("~other/papers")),
+            Path::new("~other/papers")
+        );
+        assert_eq!(expand_home(Path::new("papers")), Path::new("papers"));
+    }
+
+    // The only test that sets the ODINSOURCE_* variables, since the environment is shared by
+    // the tests running in parallel
+    #[test]
+    fn load_applies_config_file_then_environment() {
+        let dir =
+            std::env::temp_dir().join(format!("odinsource-test-{}-config", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "store_dir = \"~/from-file\"\ndb_path = \"/from/file.db\"\nlist_format = \"brief\"\n\n[openers]\npdf = \"zathura --fork {}\"\n",
+        )
+        .unwrap();
+        std::env::remove_var(STORE_DIR_ENV);
+        std::env::remove_var(DB_PATH_ENV);
+
+        // `--config` wins over ODINSOURCE_CONFIG
+        std::env::set_var(CONFIG_ENV, dir.join("missing.toml"));
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.store_dir, home_dir().join("from-file"));
+        assert_eq!(config.db_path, PathBuf::from("/from/file.db"));
+        assert_eq!(config.list_format, ListFormat::Brief);
+        // An explicitly named file must exist
+        assert!(Config::load(None).is_err());
+
+        std::env::set_var(CONFIG_ENV, &path);
+        std::env::set_var(DB_PATH_ENV, "~/from-env.db");
+        let config = Config::load(None).unwrap();
+        assert_eq!(config.store_dir, home_dir().join("from-file"));
+        assert_eq!(config.db_path, home_dir().join("from-env.db"));
+        std::env::set_var(STORE_DIR_ENV, "/from/env");
+        assert_eq!(
+            Config::load(None).unwrap().store_dir,
+            PathBuf::from("/from/env")
+        );
+
+        std::env::remove_var(CONFIG_ENV);
+        std::env::remove_var(STORE_DIR_ENV);
+        std::env::remove_var(DB_PATH_ENV);
+
+        let command = config
+            .opener_command(Path::new("/papers/A Paper.PDF"))
+            .unwrap();
+        assert_eq!(command.get_program(), "zathura");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["--fork", "/papers/A Paper.PDF"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,530 @@
+use crate::author::{DatabaseAuthor, Name};
+use crate::bibtex::citation_keys;
+use crate::config::Config;
+use crate::doctype::{DocType, ExtraFields};
+use crate::document::{DatabaseDoc, DocList, Document, DocumentBuilder};
+use crate::filetype::FILE_TYPES;
+use crate::tag::TagInputList;
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+
+/// A CSL-JSON item, as read by Pandoc citeproc and exported by Zotero.  Only the variables
+/// that map onto document fields are kept.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct CslItem {
+    #[serde(deserialize_with = "string_or_number")]
+    pub id: String,
+    #[serde(rename = "type")]
+    pub item_type: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub title: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub author: Vec<CslName>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub editor: Vec<CslName>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issued: Option<CslDate>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub container_title: String,
+    /// Series or proceedings title when `container-title` holds the other one.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub collection_title: String,
+    #[serde(
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "string_or_number"
+    )]
+    pub volume: String,
+    #[serde(
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "string_or_number"
+    )]
+    pub issue: String,
+    #[serde(
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "string_or_number"
+    )]
+    pub page: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub publisher: String,
+    /// Issuing body of standards and reports that also have a publisher.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub authority: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub genre: String,
+    #[serde(
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "string_or_number"
+    )]
+    pub edition: String,
+    #[serde(rename = "ISBN", skip_serializing_if = "String::is_empty")]
+    pub isbn: String,
+    #[serde(rename = "URL", skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    #[serde(rename = "DOI", skip_serializing_if = "String::is_empty")]
+    pub doi: String,
+    #[serde(rename = "abstract", skip_serializing_if = "String::is_empty")]
+    pub abstract_: String,
+    /// Comma separated keywords.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub keyword: String,
+    /// Location of the document file.  Not a CSL variable; processors ignore it.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub file: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct CslName {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub family: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub given: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub suffix: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub non_dropping_particle: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub dropping_particle: String,
+    /// Corporate names.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub literal: String,
+}
+
+impl CslName {
+    fn from_name(name: &Name) -> Self {
+        if name.first.is_empty() {
+            return Self {
+                literal: name.last.clone(),
+                ..Self::default()
+            };
+        }
+        return Self {
+            family: name.last.clone(),
+            given: name.first.clone(),
+            suffix: name.suffix.clone(),
+            ..Self::default()
+        };
+    }
+
+    fn to_name(&self) -> Name {
+        if !self.literal.is_empty() {
+            return Name {
+                last: self.literal.clone(),
+                ..Name::default()
+            };
+        }
+        let last = [
+            self.dropping_particle.as_str(),
+            self.non_dropping_particle.as_str(),
+            self.family.as_str(),
+        ]
+        .iter()
+        .filter(|p| !p.is_empty())
+        .copied()
+        .collect::<Vec<&str>>()
+        .join(" ");
+        return Name {
+            first: self.given.clone(),
+            last,
+            suffix: self.suffix.clone(),
+        };
+    }
+}
+
+/// A CSL date: `{"date-parts": [[2020, 3]]}`, or a `raw` or `literal` string.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct CslDate {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub date_parts: Vec<Vec<serde_json::Value>>,
+    #[serde(skip_serializing)]
+    pub raw: String,
+    #[serde(skip_serializing)]
+    pub literal: String,
+}
+
+impl CslDate {
+    fn new(year: u16, month: u8) -> Option<Self> {
+        let mut parts = Vec::new();
+        if year == 0 {
+            return None;
+        }
+        parts.push(serde_json::Value::from(year));
+        if month != 0 {
+            parts.push(serde_json::Value::from(month));
+        }
+        return Some(Self {
+            date_parts: vec![parts],
+            ..Self::default()
+        });
+    }
+
+    /// Year and month of the date; zero when not given.
+    fn year_month(&self) -> (u16, u8) {
+        let part = |i: usize| {
+            self.date_parts
+                .first()
+                .and_then(|p| p.get(i))
+                .and_then(|v| match v {
+                    serde_json::Value::Number(n) => n.as_u64(),
+                    serde_json::Value::String(s) => s.trim().parse().ok(),
+                    _ => None,
+                })
+                .unwrap_or(0)
+        };
+        // Months 13 to 24 are seasons, which have no month
+        if part(0) != 0 {
+            let month = Some(part(1)).filter(|m| (1..=12).contains(m)).unwrap_or(0);
+            return (part(0) as u16, month as u8);
+        }
+        let text = if self.raw.is_empty() {
+            &self.literal
+        } else {
+            &self.raw
+        };
+        let mut numbers = text
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|n| !n.is_empty())
+            .map(|n| n.parse::<u16>().unwrap_or(0));
+        let year = numbers.next().unwrap_or(0);
+        let month = numbers.next().filter(|m| (1..=12).contains(m)).unwrap_or(0);
+        return (year, month as u8);
+    }
+}
+
+impl CslItem {
+    /// Item for a document, with `id` set to its citation key.
+    pub fn from_doc(doc: &DatabaseDoc, authors: &[Name], key: &str, file: &Path) -> Self {
+        let extra = &doc.extra;
+        let (container_title, collection_title) = match doc.doc_type {
+            DocType::Conference => (extra.booktitle.clone(), doc.publication.clone()),
+            _ => (doc.publication.clone(), extra.booktitle.clone()),
+        };
+        // Processors take the university of a thesis and the issuer of a report from
+        // `publisher`
+        let (publisher, authority) = match doc.doc_type {
+            DocType::Thesis | DocType::Report | DocType::Standard if extra.publisher.is_empty() => {
+                (extra.institution.clone(), String::new())
+            }
+            _ => (extra.publisher.clone(), extra.institution.clone()),
+        };
+        return Self {
+            id: key.to_string(),
+            item_type: csl_type(doc.doc_type).to_string(),
+            title: doc.title.clone(),
+            author: authors.iter().map(CslName::from_name).collect(),
+            editor: Name::parse_list(&extra.editor)
+                .iter()
+                .map(CslName::from_name)
+                .collect(),
+            issued: CslDate::new(doc.year, extra.month),
+            container_title,
+            collection_title,
+            volume: match doc.volume {
+                0 => String::new(),
+                volume => volume.to_string(),
+            },
+            issue: extra.issue.clone(),
+            page: extra.pages.replace("--", "-"),
+            publisher,
+            authority,
+            genre: extra.genre.clone(),
+            edition: extra.edition.clone(),
+            isbn: extra.isbn.clone(),
+            url: extra.url.clone(),
+            doi: doc.doi.clone(),
+            abstract_: extra.abstract_.clone(),
+            keyword: TagInputList::from(doc.tags.as_str())
+                .tag_values()
+                .join(", "),
+            file: file.to_string_lossy().to_string(),
+        };
+    }
+
+    /// Convert the item to a document for insertion.  The document file is taken from the
+    /// `file` variable, or else looked up as `<id>.<ext>` in `files_dir` for each supported
+    /// file type.
+    pub fn to_document(&self, files_dir: &Path) -> anyhow::Result<Document> {
+        if self.title.trim().is_empty() {
+            return Err(anyhow::anyhow!("Item {} has no title", self.id));
+        }
+        let path = self.find_file(files_dir)?;
+        let doc_type = doc_type(&self.item_type);
+        let (year, month) = self
+            .issued
+            .as_ref()
+            .map(|d| d.year_month())
+            .unwrap_or((0, 0));
+        let names = |names: &[CslName]| {
+            names
+                .iter()
+                .map(|n| n.to_name().to_bibtex())
+                .collect::<Vec<String>>()
+                .join(" and ")
+        };
+        let (publication, booktitle) = match doc_type {
+            DocType::Conference => (&self.collection_title, &self.container_title),
+            _ => (&self.container_title, &self.collection_title),
+        };
+        let (publisher, institution) = match doc_type {
+            DocType::Thesis | DocType::Report | DocType::Standard if self.authority.is_empty() => {
+                (String::new(), self.publisher.clone())
+            }
+            _ => (self.publisher.clone(), self.authority.clone()),
+        };
+        let extra = ExtraFields {
+            issue: self.issue.clone(),
+            pages: self
+                .page
+                .replace(['-', '\u{2013}'], "--")
+                .replace("----", "--"),
+            publisher,
+            edition: self.edition.clone(),
+            isbn: self.isbn.clone(),
+            url: self.url.clone(),
+            institution,
+            genre: self.genre.clone(),
+            editor: names(&self.editor),
+            booktitle: booktitle.clone(),
+            month,
+            abstract_: self.abstract_.clone(),
+        };
+        let tags = self
+            .keyword
+            .split([',', ';'])
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<&str>>()
+            .join(",");
+        let volume = self
+            .volume
+            .trim()
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect::<String>()
+            .parse()
+            .unwrap_or(0);
+        return DocumentBuilder::new(&self.title, &path.to_string_lossy())
+            .author(&names(&self.author))
+            .publication(publication)
+            .volume(volume)
+            .year(year)
+            .doi(&self.doi)
+            .tags(&tags)
+            .doc_type(doc_type)
+            .extra(extra)
+            .build()
+            .map_err(|e| anyhow::anyhow!("Item {}: {}", self.id, e));
+    }
+
+    fn find_file(&self, files_dir: &Path) -> anyhow::Result<PathBuf> {
+        if !self.file.is_empty() {
+            let candidate = PathBuf::from(&self.file);
+            let path = if candidate.is_absolute() {
+                candidate
+            } else {
+                files_dir.join(candidate)
+            };
+            if path.is_file() {
+                return Ok(path);
+            }
+            log::debug!("Item {}: file {:?} not found", self.id, path);
+        }
+        for file_type in FILE_TYPES.iter() {
+            for ext in file_type.extensions() {
+                let path = files_dir.join(format!("{}.{}", self.id, ext));
+                if path.is_file() {
+                    return Ok(path);
+                }
+            }
+        }
+        return Err(anyhow::anyhow!(
+            "Item {}: no document file found from its file variable or at {:?}",
+            self.id,
+            files_dir.join(format!("{}.<ext>", self.id))
+        ));
+    }
+}
+
+/// CSL item type written for a document type.
+fn csl_type(doc_type: DocType) -> &'static str {
+    return match doc_type {
+        DocType::Article => "article-journal",
+        DocType::Book => "book",
+        DocType::Thesis => "thesis",
+        DocType::Conference => "paper-conference",
+        DocType::Standard => "standard",
+        DocType::Report => "report",
+        DocType::Webpage => "webpage",
+        DocType::Misc => "document",
+    };
+}
+
+/// Document type of a CSL item type.
+fn doc_type(csl_type: &str) -> DocType {
+    return match csl_type {
+        "article-journal" | "article" | "article-magazine" | "article-newspaper" => {
+            DocType::Article
+        }
+        "book" | "chapter" => DocType::Book,
+        "thesis" => DocType::Thesis,
+        "paper-conference" => DocType::Conference,
+        "standard" => DocType::Standard,
+        "report" => DocType::Report,
+        "webpage" | "post" | "post-weblog" => DocType::Webpage,
+        _ => DocType::Misc,
+    };
+}
+
+/// CSL allows numbers where strings are expected, e.g. `"volume": 12`.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    return Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => s,
+        serde_json::Value::Number(n) => n.to_string(),
+        _ => String::new(),
+    });
+}
+
+/// Render `docs` as a CSL-JSON array.
+pub async fn to_csl_json(docs: &DocList, pool: &SqlitePool) -> anyhow::Result<String> {
+    let mut items = Vec::new();
+    for (doc, key) in docs.iter().zip(citation_keys(docs, pool).await?) {
+        let authors = DatabaseAuthor::for_doc(doc.id, pool)
+            .await?
+            .iter()
+            .map(|a| a.name())
+            .collect::<Vec<Name>>();
+        let file = Config::get().stored_file(&doc.stored_name());
+        items.push(CslItem::from_doc(doc, &authors, &key, &file));
+    }
+    let mut json = serde_json::to_string_pretty(&items)?;
+    json.push('\n');
+    return Ok(json);
+}
+
+/// Parse a CSL-JSON file: an array of items, or a single item.
+pub fn parse(input: &str) -> anyhow::Result<Vec<CslItem>> {
+    let value: serde_json::Value = serde_json::from_str(input)?;
+    return Ok(match value {
+        serde_json::Value::Array(_) => serde_json::from_value(value)?,
+        _ => vec![serde_json::from_value(value)?],
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{assert_same_document, authors, database_doc, PdfFile};
+
+    /// Export `doc` to CSL-JSON text and read it back.
+    fn round_trip(doc: &DatabaseDoc, authors: &[Name], file: &Path) -> Document {
+        let json =
+            serde_json::to_string(&[CslItem::from_doc(doc, authors, "doe2020deep", file)]).unwrap();
+        let items = parse(&json).unwrap();
+        assert_eq!(items.len(), 1, "{}", json);
+        assert_eq!(items[0].id, "doe2020deep");
+        return items[0].to_document(Path::new("/nonexistent")).unwrap();
+    }
+
+    #[test]
+    fn article_round_trip_keeps_every_field() {
+        let file = PdfFile::new("csljson-article");
+        let doc = database_doc(DocType::Article);
+        let imported = round_trip(&doc, &authors(), &file);
+        assert_same_document(&doc, &authors(), &imported, &file);
+    }
+
+    #[test]
+    fn conference_round_trip_swaps_container_titles() {
+        let file = PdfFile::new("csljson-conference");
+        let mut doc = database_doc(DocType::Conference);
+        doc.publication = "Lecture Notes on Cats".to_string();
+        doc.extra.booktitle = "Proceedings of CatConf".to_string();
+        let item = CslItem::from_doc(&doc, &authors(), "key", &file);
+        assert_eq!(item.container_title, "Proceedings of CatConf");
+        assert_eq!(item.collection_title, "Lecture Notes on Cats");
+        let imported = round_trip(&doc, &authors(), &file);
+        assert_same_document(&doc, &authors(), &imported, &file);
+    }
+
+    #[test]
+    fn thesis_round_trip_keeps_institution_and_genre() {
+        let file = PdfFile::new("csljson-thesis");
+        let mut doc = database_doc(DocType::Thesis);
+        doc.publication = String::new();
+        doc.volume = 0;
+        doc.extra.publisher = String::new();
+        doc.extra.genre = "Master's thesis".to_string();
+        let item = CslItem::from_doc(&doc, &authors(), "key", &file);
+        assert_eq!(item.publisher, "University of Cats");
+        assert_eq!(item.authority, "");
+        let imported = round_trip(&doc, &authors(), &file);
+        assert_same_document(&doc, &authors(), &imported, &file);
+    }
+
+    #[test]
+    fn round_trip_without_optional_fields() {
+        let file = PdfFile::new("csljson-minimal");
+        let mut doc = database_doc(DocType::Misc);
+        doc.year = 0;
+        doc.publication = String::new();
+        doc.volume = 0;
+        doc.tags = String::new();
+        doc.doi = String::new();
+        doc.extra = ExtraFields::default();
+        let imported = round_trip(&doc, &[], &file);
+        assert_same_document(&doc, &[], &imported, &file);
+    }
+
+    #[test]
+    fn parses_items_from_other_tools() {
+        let file = PdfFile::new("csljson-foreign");
+        let json = format!(
+            r#"{{
+                "id": 17,
+                "type": "article-journal",
+                "title": "A Title",
+                "author": [
+                    {{"family": "Doe", "given": "Jane"}},
+                    {{"family": "Gogh", "given": "Vincent", "non-dropping-particle": "van"}}
+                ],
+                "container-title": "Some Journal",
+                "volume": 7,
+                "issue": "2",
+                "page": "10–20",
+                "issued": {{"date-parts": [["2019", "11", "2"]]}},
+                "keyword": "One; Two",
+                "file": "{}"
+            }}"#,
+            file.display()
+        );
+        let items = parse(&json).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, "17");
+        let doc = items[0].to_document(Path::new("/nonexistent")).unwrap();
+        assert_eq!(doc.title, "A Title");
+        assert_eq!(doc.author, "Doe, Jane and van Gogh, Vincent");
+        assert_eq!(doc.publication, "Some Journal");
+        assert_eq!(doc.volume, 7);
+        assert_eq!(doc.year, 2019);
+        assert_eq!(doc.extra.month, 11);
+        assert_eq!(doc.extra.pages, "10--20");
+        assert_eq!(doc.tags, "one,two");
+        assert_eq!(doc.path, *file);
+    }
+
+    #[test]
+    fn dates_without_a_month() {
+        let date = |json: &str| serde_json::from_str::<CslDate>(json).unwrap().year_month();
+        // Months 13 to 24 are seasons
+        assert_eq!(date(r#"{"date-parts": [[2020, 13]]}"#), (2020, 0));
+        assert_eq!(date(r#"{"date-parts": [[2020, 16]]}"#), (2020, 0));
+        assert_eq!(date(r#"{"date-parts": [[2020, 21]]}"#), (2020, 0));
+        assert_eq!(date(r#"{"date-parts": [[2020, 12]]}"#), (2020, 12));
+        assert_eq!(date(r#"{"date-parts": [[2020]]}"#), (2020, 0));
+        assert_eq!(date(r#"{"raw": "2018-05-01"}"#), (2018, 5));
+        assert_eq!(date(r#"{"literal": "Spring 2017"}"#), (2017, 0));
+    }
+}
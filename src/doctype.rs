@@ -0,0 +1,235 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// The kind of work a document record describes.  Each type requires the fields needed to
+/// cite it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DocType {
+    /// Journal article; requires the journal as `publication`.
+    Article,
+    /// Book; requires a publisher.
+    Book,
+    /// Doctoral or master's thesis; requires the degree granting institution.
+    Thesis,
+    /// Paper in conference proceedings; requires the proceedings title as `booktitle`.
+    Conference,
+    /// Technical standard; requires the standards body as `institution`.
+    Standard,
+    /// Technical report; requires the issuing institution.
+    Report,
+    /// Web page; requires a URL.
+    Webpage,
+    /// Anything else.
+    #[default]
+    Misc,
+}
+
+impl DocType {
+    /// Name stored in the `doc_type` column.
+    pub fn name(&self) -> String {
+        return self
+            .to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default();
+    }
+
+    /// Fields that must be non-empty for a document of this type.
+    pub fn required_fields(&self) -> &'static [&'static str] {
+        return match self {
+            Self::Article => &["publication"],
+            Self::Book => &["publisher"],
+            Self::Thesis => &["institution"],
+            Self::Conference => &["booktitle"],
+            Self::Standard => &["institution"],
+            Self::Report => &["institution"],
+            Self::Webpage => &["url"],
+            Self::Misc => &[],
+        };
+    }
+
+    /// Type of a document that was entered without one, guessed from the fields it has.
+    pub fn infer(publication: &str, extra: &ExtraFields) -> Self {
+        if !extra.booktitle.is_empty() {
+            return Self::Conference;
+        }
+        if !publication.is_empty() {
+            return Self::Article;
+        }
+        if !extra.publisher.is_empty() {
+            return Self::Book;
+        }
+        if !extra.url.is_empty() {
+            return Self::Webpage;
+        }
+        return Self::Misc;
+    }
+
+    /// Check that the fields required by this type are present and the extra fields are
+    /// well formed.
+    pub fn validate(&self, publication: &str, extra: &ExtraFields) -> anyhow::Result<()> {
+        let missing = self
+            .required_fields()
+            .iter()
+            .filter(|field| match **field {
+                "publication" => publication.trim().is_empty(),
+                field => extra.get(field).is_none_or(|v| v.trim().is_empty()),
+            })
+            .copied()
+            .collect::<Vec<&str>>();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "A {} document requires: {}",
+                self,
+                missing.join(", ")
+            ));
+        }
+        return extra.validate();
+    }
+}
+
+impl std::fmt::Display for DocType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}", self.name());
+    }
+}
+
+impl TryFrom<String> for DocType {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        return Self::from_str(&value, true)
+            .map_err(|_| anyhow::anyhow!("Unknown document type: {:?}", value));
+    }
+}
+
+/// Optional bibliographic fields beyond the journal article fields of `Document`.  Empty
+/// strings and a zero month mean the field is not set.
+/// Unset fields are left out when serialized.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize, FromRow)]
+#[serde(default)]
+pub struct ExtraFields {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub issue: String,
+    /// Page range, e.g. `101--115`.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub pages: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub publisher: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub edition: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub isbn: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    /// University, company or standards body.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub institution: String,
+    /// Kind of work, such as `PhD thesis` or `Master's thesis`, written as given.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub genre: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub editor: String,
+    /// Title of the proceedings or collection the document appears in.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub booktitle: String,
+    /// Month of publication, 1 to 12.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub month: u8,
+    #[serde(rename = "abstract", skip_serializing_if = "String::is_empty")]
+    #[sqlx(rename = "abstract")]
+    pub abstract_: String,
+}
+
+fn is_zero(value: &u8) -> bool {
+    return *value == 0;
+}
+
+/// Names of the extra fields, in display order.
+pub const EXTRA_FIELDS: &[&str] = &[
+    "issue",
+    "pages",
+    "publisher",
+    "edition",
+    "isbn",
+    "url",
+    "institution",
+    "genre",
+    "editor",
+    "booktitle",
+    "month",
+    "abstract",
+];
+
+impl ExtraFields {
+    /// Value of a field by name, or `None` for an unknown name.  An unset month is empty.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "issue" => &self.issue,
+            "pages" => &self.pages,
+            "publisher" => &self.publisher,
+            "edition" => &self.edition,
+            "isbn" => &self.isbn,
+            "url" => &self.url,
+            "institution" => &self.institution,
+            "genre" => &self.genre,
+            "editor" => &self.editor,
+            "booktitle" => &self.booktitle,
+            "abstract" => &self.abstract_,
+            "month" if self.month == 0 => return Some(String::new()),
+            "month" => return Some(self.month.to_string()),
+            _ => return None,
+        };
+        return Some(value.clone());
+    }
+
+    /// Set fields as `(name, value)` pairs, in display order.
+    pub fn set_fields(&self) -> Vec<(&'static str, String)> {
+        return EXTRA_FIELDS
+            .iter()
+            .filter_map(|name| self.get(name).map(|v| (*name, v)))
+            .filter(|(_, v)| !v.is_empty())
+            .collect();
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.month > 12 {
+            return Err(anyhow::anyhow!("Invalid month: {}", self.month));
+        }
+        if !self.isbn.is_empty() {
+            let digits = self
+                .isbn
+                .chars()
+                .filter(|c| c.is_ascii_digit() || *c == 'X' || *c == 'x')
+                .count();
+            if digits != 10 && digits != 13 {
+                return Err(anyhow::anyhow!("Invalid ISBN: {:?}", self.isbn));
+            }
+        }
+        if !self.url.is_empty()
+            && !self.url.starts_with("http://")
+            && !self.url.starts_with("https://")
+        {
+            return Err(anyhow::anyhow!("Invalid URL: {:?}", self.url));
+        }
+        return Ok(());
+    }
+}
+
+/// Parse a month given as a number or an English month name or abbreviation.
+pub fn parse_month(value: &str) -> anyhow::Result<u8> {
+    let value = value.trim().to_lowercase();
+    if let Ok(month) = value.parse::<u8>() {
+        if (1..=12).contains(&month) {
+            return Ok(month);
+        }
+    }
+    const MONTHS: &[&str] = &[
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    return MONTHS
+        .iter()
+        .position(|m| value.len() >= 3 && value.starts_with(m))
+        .map(|i| i as u8 + 1)
+        .ok_or_else(|| anyhow::anyhow!("Invalid month: {:?}", value));
+}
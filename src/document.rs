@@ -1,11 +1,257 @@
-use crate::tag::TagInputList;
+use crate::attachment::{Attachment, AttachmentFile};
+use crate::author::{set_doc_authors, Name};
+use crate::config::Config;
+use crate::doctype::{DocType, ExtraFields};
+use crate::filetype::FileType;
+use crate::filter::{DocFilter, SqlParam};
+use crate::fulltext;
+use crate::store::{StagedFile, StagedMove, StagedRemoval};
+use crate::tag::{DatabaseTag, TagInputList};
+use crate::{dryrun, failpoint};
 use anyhow::Context;
 use clap::Args;
-use serde::{Deserialize, Deserializer};
-use sqlx::{FromRow, SqlitePool};
-use std::path::PathBuf;
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, FromRow, Sqlite, SqliteConnection, SqlitePool, Transaction};
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
 use uuid::Uuid;
 
+/// Selects every document column, with the tag values from `document_tags` collapsed into a
+/// comma separated `tags` column and the names from `document_authors` into a BibTeX style
+/// `author` column, so query results map directly onto `DatabaseDoc`.
+pub const DOC_SELECT: &str = r#"
+    SELECT
+        documents.id,
+        documents.title,
+        COALESCE((
+            SELECT group_concat(name, ' and ') FROM (
+                SELECT
+                    CASE WHEN authors.first = '' AND instr(authors.last, ' ') > 0
+                        THEN '{' || authors.last || '}'
+                        ELSE authors.last
+                    END
+                    || CASE WHEN authors.suffix <> '' THEN ', ' || authors.suffix ELSE '' END
+                    || CASE WHEN authors.first <> '' THEN ', ' || authors.first ELSE '' END
+                    AS name
+                FROM document_authors
+                JOIN authors ON authors.id = document_authors.author_id
+                WHERE document_authors.doc_id = documents.id
+                ORDER BY document_authors.position
+            )
+        ), '') AS author,
+        documents.year,
+        documents.publication,
+        documents.volume,
+        documents.doi,
+        documents.doc_type,
+        documents.issue,
+        documents.pages,
+        documents.publisher,
+        documents.edition,
+        documents.isbn,
+        documents.url,
+        documents.institution,
+        documents.editor,
+        documents.booktitle,
+        documents.genre,
+        documents.month,
+        documents.abstract,
+        documents.uuid,
+        documents.file_type,
+        documents.extension,
+        documents.filename,
+        COALESCE(documents.content_hash, '') AS content_hash,
+        documents.deleted_at,
+        COALESCE((
+            SELECT group_concat(value, ',') FROM (
+                SELECT tags.value FROM document_tags
+                JOIN tags ON tags.id = document_tags.tag_id
+                WHERE document_tags.doc_id = documents.id
+                ORDER BY tags.value
+            )
+        ), '') AS tags
+    FROM documents
+"#;
+
+/// What to do when a file being added has the same contents as an already stored document.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Fail with an error naming the existing document.
+    #[default]
+    Reject,
+    /// Add the tags of the new record to the existing document instead of storing the file
+    /// again.
+    Link,
+}
+
+/// Key used to compare titles and names: NFKC normalized, case folded and with whitespace
+/// collapsed, so `BERT:  Pre-training` and `bert: pre-training` are the same title.
+pub fn normalized_key(value: &str) -> String {
+    return value
+        .nfkc()
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+}
+
+/// Compute the title key of documents written before title keys were recorded.  Title keys
+/// are unique, so a document whose title has the same key as another one is renamed with a
+/// number.
+pub async fn backfill_title_keys(pool: &SqlitePool) -> anyhow::Result<usize> {
+    let rows: Vec<(u32, String)> = sqlx::query_as(
+        r#"
+        SELECT id, title FROM documents
+        WHERE title_key IS NULL
+        ORDER BY id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    for (id, title) in rows.iter() {
+        let mut new_title = title.clone();
+        let mut n = 1;
+        while let Some(existing) = DatabaseDoc::from_title(&new_title, pool).await? {
+            n += 1;
+            new_title = format!("{} ({})", title, n);
+            log::warn!(
+                "Document {} has the same title as document {}, renaming it to {:?}",
+                id,
+                existing.id,
+                new_title
+            );
+        }
+        sqlx::query(
+            r#"
+            UPDATE documents
+            SET title=?1, title_key=?2
+            WHERE id=?3
+            "#,
+        )
+        .bind(&new_title)
+        .bind(normalized_key(&new_title))
+        .bind(id)
+        .execute(pool)
+        .await?;
+    }
+    return Ok(rows.len());
+}
+
+/// Commit `tx`, unless the failpoint `failpoint` is armed.  In a dry run the changes made
+/// in `tx` are printed and rolled back instead; `pool` provides the committed state they
+/// are compared to.
+pub async fn commit(
+    mut tx: Transaction<'_, Sqlite>,
+    failpoint: &str,
+    pool: &SqlitePool,
+) -> anyhow::Result<()> {
+    failpoint::check(failpoint)?;
+    if dryrun::enabled() {
+        dryrun::print_changes(&mut tx, pool).await?;
+        tx.rollback().await?;
+        return Ok(());
+    }
+    tx.commit().await?;
+    return Ok(());
+}
+
+/// Move `staged` files to their final names and commit `tx`.  The files are put in place
+/// last so that only the commit can fail once they are there, in which case they are
+/// removed again.
+pub async fn commit_with_files(
+    tx: Transaction<'_, Sqlite>,
+    staged: Vec<StagedFile>,
+    failpoint: &str,
+    pool: &SqlitePool,
+) -> anyhow::Result<()> {
+    if dryrun::enabled() {
+        commit(tx, failpoint, pool).await?;
+        for file in staged.into_iter() {
+            file.persist()?;
+        }
+        return Ok(());
+    }
+    let mut stored = Vec::new();
+    let mut result = Ok(());
+    for file in staged.into_iter() {
+        match file.persist() {
+            Ok(path) => stored.push(path),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    if result.is_ok() {
+        result = commit(tx, failpoint, pool).await;
+    }
+    if result.is_err() {
+        for path in stored.iter() {
+            if std::fs::remove_file(path).is_err() {
+                log::warn!("Could not remove {:?}", path);
+            }
+        }
+    }
+    return result;
+}
+
+/// SHA-256 of the contents of the file at `path`, as lowercase hex.
+pub fn content_hash(path: &Path) -> anyhow::Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Could not read {:?}", path))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    return Ok(format!("{:x}", hasher.finalize()));
+}
+
+/// Compute the content hash of every stored document that does not have one yet, such as
+/// documents added before hashes were recorded.  Returns the number of documents updated.
+/// Documents whose file is missing get an empty hash, so they are only reported once.
+pub async fn backfill_content_hashes(pool: &SqlitePool) -> anyhow::Result<usize> {
+    let docs = sqlx::query_as::<_, DatabaseDoc>(&format!(
+        "{} WHERE documents.content_hash IS NULL",
+        DOC_SELECT
+    ))
+    .fetch_all(pool)
+    .await?;
+    let mut count = 0;
+    for doc in docs.iter() {
+        let hash = if doc.is_stored() {
+            content_hash(&doc.stored_path()?)?
+        } else {
+            log::warn!("Cannot hash document {}: stored file is missing", doc.id);
+            String::new()
+        };
+        match DatabaseDoc::from_hash(&hash, pool).await? {
+            Some(existing) if !hash.is_empty() => log::warn!(
+                "Document {} has the same contents as document {}: {:?}",
+                doc.id,
+                existing.id,
+                existing.title
+            ),
+            _ => {}
+        }
+        sqlx::query(
+            r#"
+            UPDATE documents
+            SET content_hash=?1
+            WHERE id=?2
+            "#,
+        )
+        .bind(&hash)
+        .bind(doc.id)
+        .execute(pool)
+        .await?;
+        count += 1;
+    }
+    if count > 0 {
+        log::info!("Recorded content hashes of {} documents", count);
+    }
+    return Ok(count);
+}
+
 #[derive(FromRow, Debug, Hash)]
 pub struct DatabaseDoc {
     pub id: u32,
@@ -16,7 +262,22 @@ pub struct DatabaseDoc {
     pub volume: u16,
     pub tags: String,
     pub doi: String,
+    #[sqlx(try_from = "String")]
+    pub doc_type: DocType,
+    #[sqlx(flatten)]
+    pub extra: ExtraFields,
     pub uuid: String,
+    #[sqlx(try_from = "String")]
+    pub file_type: FileType,
+    /// Extension of the file when it was added, empty for documents added before it was
+    /// recorded, whose stored copies have the canonical extension of their file type.
+    pub extension: String,
+    /// Name of the file when it was added, empty for documents added before it was recorded.
+    pub filename: String,
+    /// SHA-256 of the stored file, empty until it has been computed.
+    pub content_hash: String,
+    /// When the document was moved to the trash, `None` for documents in the library.
+    pub deleted_at: Option<String>,
 }
 
 impl std::convert::Into<Document> for DatabaseDoc {
@@ -33,6 +294,9 @@ impl std::convert::Into<Document> for DatabaseDoc {
             volume,
             tags,
             doi,
+            doc_type,
+            extra,
+            filename,
             ..
         } = self;
         return Document {
@@ -44,53 +308,173 @@ impl std::convert::Into<Document> for DatabaseDoc {
             volume,
             tags,
             doi,
+            doc_type: Some(doc_type),
+            extra,
+            filename,
+            attachments: Vec::new(),
             path,
         };
     }
 }
 
 impl DatabaseDoc {
+    /// Name of the stored copy of the document file in the store directory.
+    pub fn stored_name(&self) -> String {
+        if self.extension.is_empty() {
+            return format!("{}.{}", self.uuid, self.file_type.extension());
+        }
+        return format!("{}.{}", self.uuid, self.extension);
+    }
+
+    /// Where the stored file is kept: in the store directory, or in its trash directory
+    /// while the document is in the trash.
+    pub fn stored_file(&self) -> PathBuf {
+        return match self.deleted_at {
+            Some(_) => Config::get().trashed_file(&self.stored_name()),
+            None => Config::get().stored_file(&self.stored_name()),
+        };
+    }
+
     pub fn is_stored(&self) -> bool {
-        let fname = format!("{}.{}", self.uuid, "pdf");
-        let path = std::path::PathBuf::from(std::env!("DOC_STORE_URL")).join(fname);
-        return self.uuid.len() == 36 && path.exists();
+        return self.uuid.len() == 36 && self.stored_file().exists();
+    }
+
+    pub fn is_trashed(&self) -> bool {
+        return self.deleted_at.is_some();
+    }
+
+    /// Fail for a document in the trash, which only the `doc trash` commands work on.
+    pub fn ensure_not_trashed(&self) -> anyhow::Result<()> {
+        if self.is_trashed() {
+            return Err(anyhow::anyhow!(
+                "Document {} is in the trash: {:?}; restore it with `doc trash restore {}`",
+                self.id,
+                self.title,
+                self.id
+            ));
+        }
+        return Ok(());
+    }
+
+    /// One line description of the record for brief listings.
+    pub fn summary(&self) -> String {
+        return format!("{:>5}  {} ({})", self.id, self.title, self.year);
     }
 
     pub fn stored_path(&self) -> anyhow::Result<PathBuf> {
         if !self.is_stored() {
             return Err(anyhow::anyhow!("Document is not stored: {:?}", self.title))?;
         }
-        let fname = format!("{}.{}", self.uuid, "pdf");
-        let path = std::path::PathBuf::from(std::env!("DOC_STORE_URL")).join(fname);
-        return Ok(path);
+        return Ok(self.stored_file());
     }
 
-    pub async fn from_id(id: u32, pool: &SqlitePool) -> anyhow::Result<Option<Self>> {
-        return Ok(sqlx::query_as::<_, Self>(
-            r#"
-            SELECT * FROM documents
-            WHERE id=?1
-            "#,
-        )
-        .bind(&id)
-        .fetch_optional(pool)
+    pub async fn from_id<'e, E>(id: u32, executor: E) -> anyhow::Result<Option<Self>>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        return Ok(
+            sqlx::query_as::<_, Self>(&format!("{} WHERE documents.id=?1", DOC_SELECT))
+                .bind(id)
+                .fetch_optional(executor)
+                .await?,
+        );
+    }
+
+    /// Look up a document by title, ignoring case, Unicode compatibility forms and spacing.
+    pub async fn from_title<'e, E>(title: &str, executor: E) -> anyhow::Result<Option<Self>>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        return Ok(sqlx::query_as::<_, Self>(&format!(
+            "{} WHERE documents.title_key=?1 ORDER BY documents.id",
+            DOC_SELECT
+        ))
+        .bind(normalized_key(title))
+        .fetch_optional(executor)
+        .await?);
+    }
+
+    pub async fn from_hash<'e, E>(hash: &str, executor: E) -> anyhow::Result<Option<Self>>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        return Ok(sqlx::query_as::<_, Self>(&format!(
+            "{} WHERE documents.content_hash=?1 ORDER BY documents.id",
+            DOC_SELECT
+        ))
+        .bind(hash)
+        .fetch_optional(executor)
         .await?);
     }
 
-    pub async fn from_title(title: &str, pool: &SqlitePool) -> anyhow::Result<Option<Self>> {
-        return Ok(sqlx::query_as::<_, Self>(
+    /// Replace the tag links of document `id` with `tags`, adding any tag values that are not
+    /// yet in the tags table.
+    async fn set_tags(
+        id: u32,
+        tags: &TagInputList,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
             r#"
-            SELECT * FROM documents
-            WHERE title=?1
+            DELETE FROM document_tags
+            WHERE doc_id=?1
             "#,
         )
-        .bind(&title)
-        .fetch_optional(pool)
-        .await?);
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+        return Self::add_tags(id, tags, conn).await;
+    }
+
+    /// Link `tags` to document `id` in addition to the tags it has, adding any tag values
+    /// that are not yet in the tags table.
+    async fn add_tags(
+        id: u32,
+        tags: &TagInputList,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        for tag in tags.clone().as_tags() {
+            let db_tag = DatabaseTag::from_tag(tag, &mut *conn).await?;
+            sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO document_tags (doc_id, tag_id)
+                VALUES (?1, ?2)
+                "#,
+            )
+            .bind(id)
+            .bind(db_tag.id)
+            .execute(&mut *conn)
+            .await?;
+        }
+        return Ok(());
+    }
+
+    pub async fn from_insert(
+        doc: Document,
+        on_duplicate: DuplicatePolicy,
+        pool: &SqlitePool,
+    ) -> anyhow::Result<Self> {
+        // The record, its links and the stored file are committed together
+        let mut tx = pool.begin().await?;
+        let (dbd, staged) = Self::insert_staged(doc, on_duplicate, &mut tx).await?;
+        let stored = !staged.is_empty();
+        commit_with_files(tx, staged, "insert:commit", pool).await?;
+        if stored {
+            log::info!("Document {:?} stored as {:?}", dbd.title, dbd.stored_name());
+        }
+        return Ok(dbd);
     }
 
-    // Check for exIf so, aisting tags
-    pub async fn from_insert(doc: Document, pool: &SqlitePool) -> anyhow::Result<Self> {
+    /// Add `doc` and its attachments in the transaction of `conn`, with their files copied
+    /// into the store under temporary names that must be persisted before the transaction
+    /// commits.  A document that is already in the library is returned without staged
+    /// files: as it is for the same title, or with the tags of `doc` added for the same
+    /// contents and `DuplicatePolicy::Link`.
+    pub async fn insert_staged(
+        doc: Document,
+        on_duplicate: DuplicatePolicy,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<(Self, Vec<StagedFile>)> {
         let Document {
             title,
             author,
@@ -99,120 +483,395 @@ impl DatabaseDoc {
             year,
             doi,
             tags,
+            doc_type,
+            extra,
+            filename,
+            attachments,
             path,
             ..
         } = doc;
 
+        let doc_type = doc_type.unwrap_or_else(|| DocType::infer(&publication, &extra));
+        doc_type
+            .validate(&publication, &extra)
+            .map_err(|e| anyhow::anyhow!("{:?}: {}", title, e))?;
+
         // Check for existing document with the same title
         // If not, create UUID
-        let uuid = match Self::from_title(&title, pool).await? {
+        let uuid = match Self::from_title(&title, &mut *conn).await? {
+            Some(dbd) if dbd.is_trashed() => {
+                return Err(anyhow::anyhow!(
+                    "{:?} is in the trash as document {}; restore it or empty the trash",
+                    title,
+                    dbd.id
+                ))
+            }
             Some(dbd) => {
                 log::warn!("Document already in DB: {:?}", title);
-                return Ok(dbd);
+                return Ok((dbd, Vec::new()));
             }
             None => Uuid::new_v4().to_string(),
         };
 
+        let file_type = FileType::from_path(&path)?;
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_else(|| file_type.extension().to_string());
+
+        // Check for a stored document with the same file contents
+        let hash = content_hash(&path)?;
+        if let Some(dbd) = Self::from_hash(&hash, &mut *conn).await? {
+            if dbd.is_trashed() {
+                return Err(anyhow::anyhow!(
+                    "{:?} has the same contents as document {} in the trash: {:?}",
+                    path,
+                    dbd.id,
+                    dbd.title
+                ));
+            }
+            return match on_duplicate {
+                DuplicatePolicy::Reject => Err(anyhow::anyhow!(
+                    "{:?} has the same contents as document {}: {:?}",
+                    path,
+                    dbd.id,
+                    dbd.title
+                )),
+                DuplicatePolicy::Link => {
+                    log::warn!(
+                        "{:?} is already stored as document {}: {:?}; adding the tags of {:?} \
+                         to it",
+                        path,
+                        dbd.id,
+                        dbd.title,
+                        title
+                    );
+                    Self::add_tags(dbd.id, &TagInputList::from(tags.as_str()), &mut *conn).await?;
+                    match Self::from_id(dbd.id, &mut *conn).await? {
+                        Some(dbd) => Ok((dbd, Vec::new())),
+                        None => Err(anyhow::anyhow!("Document {} disappeared", dbd.id)),
+                    }
+                }
+            };
+        }
+
         // Add entry to database
-        sqlx::query(
+        let id = sqlx::query(
             r#"
             INSERT INTO documents (
                 title,
-                author,
+                title_key,
                 publication,
                 volume,
                 year,
                 uuid,
                 doi,
-                tags
+                content_hash,
+                file_type,
+                doc_type,
+                issue,
+                pages,
+                publisher,
+                edition,
+                isbn,
+                url,
+                institution,
+                editor,
+                booktitle,
+                month,
+                abstract,
+                filename,
+                extension,
+                genre
+            )
+            VALUES (
+                ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12,
+                ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24
             )
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
             "#,
         )
         .bind(&title)
-        .bind(author)
+        .bind(normalized_key(&title))
         .bind(publication)
         .bind(volume)
         .bind(year)
         .bind(&uuid)
         .bind(doi)
-        .bind(tags.to_lowercase())
-        .execute(pool)
-        .await?;
+        .bind(&hash)
+        .bind(file_type.name())
+        .bind(doc_type.name())
+        .bind(&extra.issue)
+        .bind(&extra.pages)
+        .bind(&extra.publisher)
+        .bind(&extra.edition)
+        .bind(&extra.isbn)
+        .bind(&extra.url)
+        .bind(&extra.institution)
+        .bind(&extra.editor)
+        .bind(&extra.booktitle)
+        .bind(extra.month)
+        .bind(&extra.abstract_)
+        .bind(if filename.is_empty() {
+            path.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default()
+        } else {
+            filename
+        })
+        .bind(&extension)
+        .bind(&extra.genre)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid() as u32;
+        failpoint::check("insert:row")?;
 
-        // Store copy of pdf in documents folder
-        let stored_path =
-            std::path::PathBuf::from(std::env!("DOC_STORE_URL")).join(uuid.clone() + ".pdf");
-        std::fs::copy(path.clone(), stored_path.clone())?;
-        log::info!("Document {:?} stored as {:?}", path, stored_path);
+        // Store copy of the file in documents folder, keeping its extension.
+        // It keeps a temporary name until the record is committed.
+        let stored_path = Config::get().stored_file(&format!("{}.{}", uuid, extension));
+        let staged = StagedFile::copy(&path, &stored_path)?;
 
-        // Ensure entry properly inserted and document is correctly stored
-        return match Self::from_title(&title, pool).await? {
-            Some(dbd) => {
-                // Add tags to tags table and return inserted DatabaseDoc
-                for tag in TagInputList::from(tags.as_str()).as_tags() {
-                    tag.insert(&pool).await?;
-                }
-                return Ok(dbd);
+        // Link tags and authors to the inserted document
+        Self::set_tags(id, &TagInputList::from(tags.as_str()), &mut *conn).await?;
+        set_doc_authors(id, &Name::parse_list(&author), &mut *conn).await?;
+        fulltext::index_document(id, staged.path(), &mut *conn).await?;
+        let mut staged = vec![staged];
+        for attachment in attachments.iter() {
+            let (_, file) = Attachment::insert_staged(
+                id,
+                &attachment.path,
+                attachment.role,
+                &attachment.filename,
+                &mut *conn,
+            )
+            .await?;
+            staged.extend(file);
+        }
+        let dbd = match Self::from_id(id, &mut *conn).await? {
+            Some(dbd) => dbd,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Failed to create DatabaseDoc after insert."
+                ))
             }
-            None => Err(anyhow::anyhow!(
-                "Failed to create DatabaseDoc after insert."
-            ))?,
         };
+        return Ok((dbd, staged));
+    }
+
+    /// Stored files of the document and its attachments, in the store directory and where
+    /// they are kept in the trash.
+    async fn file_locations(
+        &self,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+        let config = Config::get();
+        let mut names = vec![self.stored_name()];
+        for attachment in Attachment::for_doc(self.id, &mut *conn).await?.iter() {
+            names.push(attachment.stored_name());
+        }
+        return Ok(names
+            .iter()
+            .map(|name| (config.stored_file(name), config.trashed_file(name)))
+            .collect());
+    }
+
+    /// Move the document to the trash, with the stored files of the document and its
+    /// attachments.  It keeps its records until the trash is emptied.
+    pub async fn trash(self, pool: &SqlitePool) -> anyhow::Result<()> {
+        self.ensure_not_trashed()?;
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE documents
+            SET deleted_at = CURRENT_TIMESTAMP
+            WHERE id=?1
+            "#,
+        )
+        .bind(self.id)
+        .execute(&mut *tx)
+        .await?;
+        failpoint::check("trash:row")?;
+        let mut moves = Vec::new();
+        for (stored, trashed) in self.file_locations(&mut tx).await?.iter() {
+            if stored.exists() {
+                moves.push(StagedMove::new(stored, trashed)?);
+            } else {
+                log::warn!("Could not move {:?} to the trash: file is missing", stored);
+            }
+        }
+        commit(tx, "trash:commit", pool).await?;
+        for staged in moves.into_iter() {
+            staged.finish();
+        }
+        log::info!("Document {} moved to the trash: {:?}", self.id, self.title);
+        return Ok(());
     }
 
+    /// Take the document out of the trash and move its stored files back into the store.
+    pub async fn restore(self, pool: &SqlitePool) -> anyhow::Result<()> {
+        if !self.is_trashed() {
+            return Err(anyhow::anyhow!(
+                "Document {} is not in the trash: {:?}",
+                self.id,
+                self.title
+            ));
+        }
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE documents
+            SET deleted_at = NULL
+            WHERE id=?1
+            "#,
+        )
+        .bind(self.id)
+        .execute(&mut *tx)
+        .await?;
+        failpoint::check("restore:row")?;
+        let mut moves = Vec::new();
+        for (stored, trashed) in self.file_locations(&mut tx).await?.iter() {
+            if trashed.exists() {
+                moves.push(StagedMove::new(trashed, stored)?);
+            } else {
+                log::warn!("Could not restore {:?}: file is missing", trashed);
+            }
+        }
+        commit(tx, "restore:commit", pool).await?;
+        for staged in moves.into_iter() {
+            staged.finish();
+        }
+        log::info!(
+            "Document {} restored from the trash: {:?}",
+            self.id,
+            self.title
+        );
+        return Ok(());
+    }
+
+    /// Delete the document with its attachments and their stored files for good, from the
+    /// library or from the trash.  The stored files are set aside until the records are
+    /// deleted, and put back if that fails.
     pub async fn delete(self, pool: &SqlitePool) -> anyhow::Result<()> {
-        let DatabaseDoc { title, uuid, .. } = self;
+        let mut tx = pool.begin().await?;
+        let paths = self
+            .file_locations(&mut tx)
+            .await?
+            .into_iter()
+            .map(|(stored, trashed)| if self.is_trashed() { trashed } else { stored })
+            .collect::<Vec<PathBuf>>();
 
-        // Delete entry from database
+        // Delete attachments, tag links and entry from database
+        sqlx::query(
+            r#"
+            DELETE FROM attachments
+            WHERE doc_id=?1
+            "#,
+        )
+        .bind(self.id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM document_tags
+            WHERE doc_id=?1
+            "#,
+        )
+        .bind(self.id)
+        .execute(&mut *tx)
+        .await?;
         sqlx::query(
             r#"
             DELETE FROM documents
-            WHERE title=?1
+            WHERE id=?1
             "#,
         )
-        .bind(&title)
-        .execute(pool)
+        .bind(self.id)
+        .execute(&mut *tx)
         .await?;
+        failpoint::check("delete:row")?;
 
-        // Remove stored file
-        let fname = uuid + ".pdf";
-        let asset_path = std::path::PathBuf::from(std::env!("DOC_STORE_URL")).join(fname);
-        if std::fs::remove_file(asset_path.clone()).is_err() {
-            log::warn!("Could not delete {:?}.", asset_path);
-        } else {
-            log::info!("Document {:?} deleted.", asset_path);
-        };
-
+        // Set the stored files aside; dropping them on an error moves them back
+        let mut removals = Vec::new();
+        for path in paths.iter() {
+            if path.exists() {
+                removals.push(StagedRemoval::new(path)?);
+            } else {
+                log::warn!("Could not delete {:?}: file is missing", path);
+            }
+        }
+        commit(tx, "delete:commit", pool).await?;
+        for removal in removals.into_iter() {
+            removal.finish();
+        }
+        log::info!("Document {} deleted: {:?}", self.id, self.title);
         return Ok(());
     }
 
     pub async fn update(self, pool: &SqlitePool) -> anyhow::Result<()> {
-        // Delete entry from database
+        self.doc_type.validate(&self.publication, &self.extra)?;
+        let mut tx = pool.begin().await?;
+        if let Some(existing) = Self::from_title(&self.title, &mut *tx).await? {
+            if existing.id != self.id {
+                return Err(anyhow::anyhow!(
+                    "Document {} already has the title {:?}",
+                    existing.id,
+                    existing.title
+                ));
+            }
+        }
+
+        // Update entry in database
         sqlx::query(
             r#"
             UPDATE documents
             SET
                 title = ?2,
-                author = ?3,
+                title_key = ?3,
                 year = ?4,
                 publication = ?5,
                 volume = ?6,
-                tags = ?7,
-                doi = ?8
+                doi = ?7,
+                doc_type = ?8,
+                issue = ?9,
+                pages = ?10,
+                publisher = ?11,
+                edition = ?12,
+                isbn = ?13,
+                url = ?14,
+                institution = ?15,
+                editor = ?16,
+                booktitle = ?17,
+                month = ?18,
+                abstract = ?19,
+                genre = ?20
             WHERE id=?1
             "#,
         )
-        .bind(&self.id)
+        .bind(self.id)
         .bind(&self.title)
-        .bind(&self.author)
-        .bind(&self.year)
+        .bind(normalized_key(&self.title))
+        .bind(self.year)
         .bind(&self.publication)
-        .bind(&self.volume)
-        .bind(&self.tags)
+        .bind(self.volume)
         .bind(&self.doi)
-        .execute(pool)
+        .bind(self.doc_type.name())
+        .bind(&self.extra.issue)
+        .bind(&self.extra.pages)
+        .bind(&self.extra.publisher)
+        .bind(&self.extra.edition)
+        .bind(&self.extra.isbn)
+        .bind(&self.extra.url)
+        .bind(&self.extra.institution)
+        .bind(&self.extra.editor)
+        .bind(&self.extra.booktitle)
+        .bind(self.extra.month)
+        .bind(&self.extra.abstract_)
+        .bind(&self.extra.genre)
+        .execute(&mut *tx)
         .await?;
+        failpoint::check("update:row")?;
+        Self::set_tags(self.id, &TagInputList::from(self.tags.as_str()), &mut tx).await?;
+        set_doc_authors(self.id, &Name::parse_list(&self.author), &mut tx).await?;
+        commit(tx, "update:commit", pool).await?;
         log::debug!("Document sucessfully updated:\n{}", self);
         return Ok(());
     }
@@ -222,6 +881,7 @@ impl std::fmt::Display for DatabaseDoc {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "{}", "-".repeat(80))?;
         writeln!(f, "{:12} {}", "id:", self.id)?;
+        writeln!(f, "{:12} {}", "type:", self.doc_type)?;
         writeln!(f, "{:12} {}", "title:", self.title)?;
         writeln!(f, "{:12} {}", "author:", self.author)?;
         writeln!(f, "{:12} {}", "publication:", self.publication)?;
@@ -229,31 +889,39 @@ impl std::fmt::Display for DatabaseDoc {
         writeln!(f, "{:12} {}", "year:", self.year)?;
         writeln!(f, "{:12} {}", "doi:", self.doi)?;
         writeln!(f, "{:12} {}", "tags:", self.tags)?;
+        write_extra_fields(f, &self.extra)?;
         writeln!(f, "{:12} {}", "uuid:", self.uuid)?;
+        writeln!(f, "{:12} {}", "file type:", self.file_type)?;
+        writeln!(f, "{:12} {}", "filename:", self.filename)?;
         writeln!(f, "{}", "-".repeat(80))
     }
 }
 
+/// Write the extra fields that are set, one per line.  Long abstracts are cut to one line.
+fn write_extra_fields(f: &mut std::fmt::Formatter<'_>, extra: &ExtraFields) -> std::fmt::Result {
+    for (name, value) in extra.set_fields() {
+        let value = value.split_whitespace().collect::<Vec<&str>>().join(" ");
+        let value = match value.char_indices().nth(64) {
+            Some((i, _)) => format!("{}...", &value[..i]),
+            None => value,
+        };
+        writeln!(f, "{:12} {}", format!("{}:", name), value)?;
+    }
+    return Ok(());
+}
+
 /// Used for user interface (CLI, toml, etc.)
-#[derive(Debug, Hash, Deserialize, Args)]
+#[derive(Debug, Hash, Deserialize, Serialize, Args)]
 pub struct Document {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<u32>,
-    #[serde(
-        default = "String::new",
-        deserialize_with = "Document::value_to_lowercase"
-    )]
+    #[serde(default = "String::new", deserialize_with = "Document::value_trimmed")]
     pub title: String,
-    #[serde(
-        default = "String::new",
-        deserialize_with = "Document::value_to_lowercase"
-    )]
+    #[serde(default = "String::new", deserialize_with = "Document::value_trimmed")]
     pub author: String,
     #[serde(default = "Document::default_u16")]
     pub year: u16,
-    #[serde(
-        default = "String::new",
-        deserialize_with = "Document::value_to_lowercase"
-    )]
+    #[serde(default = "String::new", deserialize_with = "Document::value_trimmed")]
     pub publication: String,
     #[serde(default = "Document::default_u16")]
     pub volume: u16,
@@ -264,6 +932,21 @@ pub struct Document {
     pub tags: String,
     #[serde(default = "String::new")]
     pub doi: String,
+    /// Inferred from the fields that are set when not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(skip)]
+    pub doc_type: Option<DocType>,
+    #[serde(flatten)]
+    #[arg(skip)]
+    pub extra: ExtraFields,
+    /// Original filename to record instead of the file name of `path`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    #[arg(skip)]
+    pub filename: String,
+    /// Files to attach to the document when it is added.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[arg(skip)]
+    pub attachments: Vec<AttachmentFile>,
     pub path: PathBuf,
 }
 
@@ -272,6 +955,8 @@ impl std::fmt::Display for Document {
         writeln!(f, "{}", "-".repeat(80))?;
         let id = self.id.map_or("None".to_string(), |v| v.to_string());
         writeln!(f, "{:12} {}", "id:", id)?;
+        let doc_type = self.doc_type.map_or("None".to_string(), |v| v.to_string());
+        writeln!(f, "{:12} {}", "type:", doc_type)?;
         writeln!(f, "{:12} {}", "title:", self.title)?;
         writeln!(f, "{:12} {}", "author:", self.author)?;
         writeln!(f, "{:12} {}", "publication:", self.publication)?;
@@ -279,6 +964,7 @@ impl std::fmt::Display for Document {
         writeln!(f, "{:12} {}", "year:", self.year)?;
         writeln!(f, "{:12} {}", "doi:", self.doi)?;
         writeln!(f, "{:12} {}", "tags:", self.tags)?;
+        write_extra_fields(f, &self.extra)?;
         writeln!(f, "{:12} {:?}", "path:", self.path)?;
         writeln!(f, "{}", "-".repeat(80))
     }
@@ -297,14 +983,21 @@ impl Document {
         return Ok(value.to_lowercase());
     }
 
+    /// Titles and names are stored as entered, apart from surrounding whitespace.
+    pub fn value_trimmed<'de, D>(deserializer: D) -> Result<String, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        return Ok(String::deserialize(deserializer)?.trim().to_string());
+    }
+
+    pub fn input_trimmed(value: &str) -> anyhow::Result<String> {
+        return Ok(value.trim().to_string());
+    }
+
     pub fn verify_path(path: &str) -> anyhow::Result<PathBuf> {
         let path = PathBuf::from(path);
-        if !path.is_file() || path.extension() != Some(&std::ffi::OsStr::new("pdf")) {
-            return Err(anyhow::anyhow!(
-                "Path does not reference a valid PDF: {:?}",
-                path
-            ))?;
-        }
+        FileType::from_path(&path)?;
         return Ok(path);
     }
 
@@ -314,39 +1007,33 @@ impl Document {
     // Must have, at minimum, a title and valid file path
     pub fn new(title: &str, path: &str) -> anyhow::Result<Self> {
         let path = PathBuf::from(path);
-        if !path.is_file() || path.extension() != Some(&std::ffi::OsStr::new("pdf")) {
-            return Err(anyhow::anyhow!(
-                "Path does not reference a valid PDF: {:?}",
-                path
-            ))?;
-        }
+        FileType::from_path(&path)?;
         return Ok(Self {
             id: None,
-            title: title.to_lowercase(),
+            title: title.trim().to_string(),
             author: String::new(),
             year: 0,
             publication: String::new(),
             volume: 0,
             tags: String::new(),
             doi: String::new(),
+            doc_type: None,
+            extra: ExtraFields::default(),
+            filename: String::new(),
+            attachments: Vec::new(),
             path,
         });
     }
 
-    pub async fn delete_from_title(title: &str, pool: &SqlitePool) -> anyhow::Result<()> {
-        return Document {
-            id: None,
-            title: title.to_lowercase(),
-            author: String::new(),
-            year: 0,
-            publication: String::new(),
-            volume: 0,
-            tags: String::new(),
-            doi: String::new(),
-            path: PathBuf::new(),
-        }
-        .delete(pool)
-        .await;
+    /// Move the document titled `title` to the trash.
+    pub async fn trash_from_title(title: &str, pool: &SqlitePool) -> anyhow::Result<()> {
+        return match DatabaseDoc::from_title(title, pool).await? {
+            Some(dbd) => dbd.trash(pool).await,
+            None => Err(anyhow::anyhow!(
+                "Document does not exist with title: {}",
+                title
+            )),
+        };
     }
 
     pub async fn from_id(id: u32, pool: &SqlitePool) -> anyhow::Result<Self> {
@@ -357,7 +1044,7 @@ impl Document {
     }
 
     pub async fn from_title(title: &str, pool: &SqlitePool) -> anyhow::Result<Self> {
-        return match DatabaseDoc::from_title(&title.to_lowercase(), pool).await? {
+        return match DatabaseDoc::from_title(title, pool).await? {
             Some(dbt) => Ok(dbt.into()),
             None => Err(anyhow::anyhow!(
                 "Document does not exist with title: {}",
@@ -373,20 +1060,14 @@ impl Document {
         };
     }
 
-    pub async fn insert(self, pool: &SqlitePool) -> anyhow::Result<()> {
-        let _ = DatabaseDoc::from_insert(self, pool).await?;
+    pub async fn insert(
+        self,
+        on_duplicate: DuplicatePolicy,
+        pool: &SqlitePool,
+    ) -> anyhow::Result<()> {
+        let _ = DatabaseDoc::from_insert(self, on_duplicate, pool).await?;
         return Ok(());
     }
-
-    pub async fn delete(self, pool: &SqlitePool) -> anyhow::Result<()> {
-        return match DatabaseDoc::from_title(&self.title, pool).await? {
-            Some(dbd) => dbd.delete(pool).await,
-            None => {
-                log::warn!("Document not in DB: {:?}", self);
-                return Ok(());
-            }
-        };
-    }
 }
 
 pub struct DocumentBuilder {
@@ -397,19 +1078,23 @@ pub struct DocumentBuilder {
     year: u16,
     tags: String,
     doi: String,
+    doc_type: Option<DocType>,
+    extra: ExtraFields,
     path: PathBuf,
 }
 
 impl DocumentBuilder {
     pub fn new(title: &str, path: &str) -> Self {
         return Self {
-            title: title.to_lowercase(),
+            title: title.trim().to_string(),
             author: String::new(),
             publication: String::new(),
             volume: 0,
             year: 0,
             tags: String::new(),
             doi: String::new(),
+            doc_type: None,
+            extra: ExtraFields::default(),
             path: PathBuf::from(path),
         };
     }
@@ -417,12 +1102,14 @@ impl DocumentBuilder {
     pub fn author(self, author: &str) -> Self {
         return Self {
             title: self.title,
-            author: author.to_lowercase(),
+            author: author.trim().to_string(),
             year: self.year,
             publication: self.publication,
             volume: self.volume,
             tags: self.tags,
             doi: self.doi,
+            doc_type: self.doc_type,
+            extra: self.extra,
             path: self.path,
         };
     }
@@ -432,10 +1119,12 @@ impl DocumentBuilder {
             title: self.title,
             author: self.author,
             year: self.year,
-            publication: publication.to_lowercase(),
+            publication: publication.trim().to_string(),
             volume: self.volume,
             tags: self.tags,
             doi: self.doi,
+            doc_type: self.doc_type,
+            extra: self.extra,
             path: self.path,
         };
     }
@@ -449,6 +1138,8 @@ impl DocumentBuilder {
             volume,
             tags: self.tags,
             doi: self.doi,
+            doc_type: self.doc_type,
+            extra: self.extra,
             path: self.path,
         };
     }
@@ -462,6 +1153,8 @@ impl DocumentBuilder {
             volume: self.volume,
             tags: self.tags,
             doi: self.doi,
+            doc_type: self.doc_type,
+            extra: self.extra,
             path: self.path,
         };
     }
@@ -475,6 +1168,8 @@ impl DocumentBuilder {
             volume: self.volume,
             tags: tags.to_lowercase(),
             doi: self.doi,
+            doc_type: self.doc_type,
+            extra: self.extra,
             path: self.path,
         };
     }
@@ -488,6 +1183,8 @@ impl DocumentBuilder {
             volume: self.volume,
             tags: self.tags,
             doi: doi.to_string(),
+            doc_type: self.doc_type,
+            extra: self.extra,
             path: self.path,
         };
     }
@@ -501,17 +1198,44 @@ impl DocumentBuilder {
             volume: self.volume,
             tags: self.tags,
             doi: self.doi,
+            doc_type: self.doc_type,
+            extra: self.extra,
             path: PathBuf::from(path),
         };
     }
 
+    pub fn doc_type(self, doc_type: DocType) -> Self {
+        return Self {
+            title: self.title,
+            author: self.author,
+            year: self.year,
+            publication: self.publication,
+            volume: self.volume,
+            tags: self.tags,
+            doi: self.doi,
+            doc_type: Some(doc_type),
+            extra: self.extra,
+            path: self.path,
+        };
+    }
+
+    pub fn extra(self, extra: ExtraFields) -> Self {
+        return Self {
+            title: self.title,
+            author: self.author,
+            year: self.year,
+            publication: self.publication,
+            volume: self.volume,
+            tags: self.tags,
+            doi: self.doi,
+            doc_type: self.doc_type,
+            extra,
+            path: self.path,
+        };
+    }
+
     pub fn build(self) -> anyhow::Result<Document> {
-        if !self.path.is_file() || self.path.extension() != Some(&std::ffi::OsStr::new("pdf")) {
-            return Err(anyhow::anyhow!(
-                "Path does not reference a valid PDF: {:?}",
-                self.path
-            ))?;
-        }
+        FileType::from_path(&self.path)?;
         return Ok(Document {
             id: None,
             title: self.title,
@@ -521,20 +1245,29 @@ impl DocumentBuilder {
             volume: self.volume,
             tags: self.tags,
             doi: self.doi,
+            doc_type: self.doc_type,
+            extra: self.extra,
+            filename: String::new(),
+            attachments: Vec::new(),
             path: self.path,
         });
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// Documents of a `doc add from-toml` file, which `library export` also writes.
+#[derive(Deserialize, Serialize, Debug)]
 pub struct TomlDocuments {
     pub documents: Vec<Document>,
 }
 
 impl TomlDocuments {
-    pub async fn add_to_db(self, pool: &SqlitePool) -> anyhow::Result<()> {
+    pub async fn add_to_db(
+        self,
+        on_duplicate: DuplicatePolicy,
+        pool: &SqlitePool,
+    ) -> anyhow::Result<()> {
         for doc in self.documents.into_iter() {
-            doc.insert(pool).await?;
+            doc.insert(on_duplicate, pool).await?;
         }
         return Ok(());
     }
@@ -545,9 +1278,7 @@ pub struct DocList(pub Vec<DatabaseDoc>);
 
 impl std::fmt::Display for DocList {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        return self.0.iter().fold(Ok(()), |result, doc| {
-            result.and_then(|_| writeln!(f, "{}", doc))
-        });
+        return self.0.iter().try_fold((), |_, doc| writeln!(f, "{}", doc));
     }
 }
 
@@ -559,79 +1290,606 @@ impl std::ops::Deref for DocList {
 }
 
 impl DocList {
+    /// Every document in the library, leaving out the documents in the trash.
     pub async fn get_all(pool: &SqlitePool) -> anyhow::Result<Self> {
         return Ok(Self(
-            sqlx::query_as::<_, DatabaseDoc>(r#" SELECT * FROM documents "#)
-                .fetch_all(pool)
-                .await?,
+            sqlx::query_as::<_, DatabaseDoc>(&format!(
+                "{} WHERE documents.deleted_at IS NULL",
+                DOC_SELECT
+            ))
+            .fetch_all(pool)
+            .await?,
         ));
     }
 
-    pub async fn modify_tag(
-        mut self,
-        old_tag: &str,
-        new_tag: &str,
-        pool: &SqlitePool,
-    ) -> anyhow::Result<()> {
-        for doc in self.0.iter_mut() {
-            let mut updated = Vec::new();
-            doc.tags = doc
-                .tags
-                .split(',')
-                .map(|t| {
-                    let tag = t.trim();
-                    if t.trim() == old_tag {
-                        updated.push((old_tag, new_tag));
-                        return new_tag;
-                    } else {
-                        return tag;
-                    }
-                })
-                .collect::<Vec<&str>>()
-                .join(",");
-            if updated.len() > 0 {
-                sqlx::query(
-                    r#"
-                    UPDATE documents
-                    SET tags = ?2
-                    WHERE id = ?1
-                    "#,
-                )
-                .bind(doc.id)
-                .bind(&doc.tags)
-                .execute(pool)
-                .await.context("modify doc tags")?;
-                // update tags db
-                for (old, new) in updated.into_iter() {
-                    sqlx::query(
-                        r#"
-                    UPDATE tags
-                    SET value = ?2
-                    WHERE value = ?1
-                    "#,
-                    )
-                    .bind(old)
-                    .bind(new)
-                    .execute(pool)
-                    .await.context("modify tags")?;
+    /// The documents in the trash, in the order they were moved there.
+    pub async fn trashed(pool: &SqlitePool) -> anyhow::Result<Self> {
+        return Ok(Self(
+            sqlx::query_as::<_, DatabaseDoc>(&format!(
+                "{} WHERE documents.deleted_at IS NOT NULL ORDER BY documents.deleted_at, documents.id",
+                DOC_SELECT
+            ))
+            .fetch_all(pool)
+            .await?,
+        ));
+    }
+
+    /// Documents with the given ids, in the order the ids are given.
+    pub async fn from_ids(ids: &[u32], pool: &SqlitePool) -> anyhow::Result<Self> {
+        let mut docs = Vec::new();
+        for id in ids.iter() {
+            match DatabaseDoc::from_id(*id, pool).await? {
+                Some(dbd) => {
+                    dbd.ensure_not_trashed()?;
+                    docs.push(dbd);
                 }
+                None => return Err(anyhow::anyhow!("No document with ID: {}", id)),
             }
         }
-        return Ok(());
+        return Ok(Self(docs));
     }
 
-    pub async fn from_tag(title: &str, pool: &SqlitePool) -> anyhow::Result<Self> {
-        let tag = "%".to_string() + title + "%";
-        let docs = sqlx::query_as::<_, DatabaseDoc>(
-            r#"
-            SELECT * FROM documents
-            WHERE tags LIKE ?1
-            "#,
-        )
-        .bind(&tag)
-        .fetch_all(pool)
-        .await?;
+    pub async fn from_filter(filter: &DocFilter, pool: &SqlitePool) -> anyhow::Result<Self> {
+        let (clauses, params) = filter.to_sql();
+        let sql = format!("{}{}", DOC_SELECT, clauses);
+        log::debug!("Document filter compiled to:\n{}", sql);
+        let mut docs = sqlx::query_as::<_, DatabaseDoc>(&sql);
+        for param in params.into_iter() {
+            docs = match param {
+                SqlParam::Text(value) => docs.bind(value),
+                SqlParam::Int(value) => docs.bind(value),
+            };
+        }
+        return Ok(Self(docs.fetch_all(pool).await?));
+    }
+}
 
-        return Ok(Self(docs));
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::attachment::AttachmentRole;
+    use crate::migration::migrate;
+    use sqlx::sqlite::SqliteConnectOptions;
+
+    /// Tables that an insert writes to.
+    pub(crate) const TABLES: &[&str] = &[
+        "documents",
+        "document_tags",
+        "tags",
+        "document_authors",
+        "authors",
+        "document_text",
+        "attachments",
+    ];
+
+    pub(crate) fn test_path(name: &str, ext: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!(
+            "odinsource-test-{}-{}.{}",
+            std::process::id(),
+            name.replace(':', "-"),
+            ext
+        ));
+    }
+
+    /// A migrated library in a new database.  The tests share the store directory, so each
+    /// one only looks for the file contents it added.
+    pub(crate) async fn library(name: &str) -> SqlitePool {
+        std::fs::create_dir_all(&Config::get().store_dir).unwrap();
+        let db_path = test_path(name, "db");
+        let _ = std::fs::remove_file(&db_path);
+        let options = SqliteConnectOptions::new()
+            .filename(&db_path)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        migrate(&pool).await.unwrap();
+        return pool;
+    }
+
+    /// A Markdown document whose contents are unique to `name`.
+    pub(crate) fn document(name: &str) -> Document {
+        let path = test_path(name, "md");
+        std::fs::write(&path, format!("# {}\n\nContents of {}.\n", name, name)).unwrap();
+        return DocumentBuilder::new(&format!("Test {}", name), &path.to_string_lossy())
+            .author("Doe, Jane")
+            .tags("one,two")
+            .build()
+            .unwrap();
+    }
+
+    /// Files in the store, under their final or a temporary name, with the contents of
+    /// the file at `path`.
+    pub(crate) fn stored_copies(path: &Path) -> Vec<PathBuf> {
+        let contents = std::fs::read(path).unwrap();
+        return std::fs::read_dir(&Config::get().store_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|stored| std::fs::read(stored).is_ok_and(|c| c == contents))
+            .collect();
+    }
+
+    /// A file that passes the PDF signature check, unique to the calling test and removed
+    /// when dropped.
+    pub(crate) struct PdfFile(PathBuf);
+
+    impl PdfFile {
+        pub(crate) fn new(name: &str) -> Self {
+            let path = test_path(name, "pdf");
+            std::fs::write(&path, b"%PDF-1.4\n%%EOF\n").unwrap();
+            return Self(path);
+        }
+    }
+
+    impl std::ops::Deref for PdfFile {
+        type Target = Path;
+        fn deref(&self) -> &Path {
+            return &self.0;
+        }
+    }
+
+    impl Drop for PdfFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// A record with every field set, for testing the citation formats without a library.
+    pub(crate) fn database_doc(doc_type: DocType) -> DatabaseDoc {
+        return DatabaseDoc {
+            id: 1,
+            title: "Deep Learning for Cats: A Survey".to_string(),
+            author: String::new(),
+            year: 2020,
+            publication: "Journal of Feline Studies".to_string(),
+            volume: 12,
+            tags: "machine learning,cats".to_string(),
+            doi: "10.1000/xyz.123".to_string(),
+            doc_type,
+            extra: ExtraFields {
+                issue: "3".to_string(),
+                pages: "101--115".to_string(),
+                publisher: "Cat Press".to_string(),
+                edition: String::new(),
+                isbn: String::new(),
+                url: "https://example.org/cats".to_string(),
+                institution: "University of Cats".to_string(),
+                genre: String::new(),
+                editor: "Smith, Anna and Jones, Bob".to_string(),
+                booktitle: "Cat Series".to_string(),
+                month: 3,
+                abstract_: "We survey cats.\n\nThe cats were surveyed twice.".to_string(),
+            },
+            uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+            file_type: FileType::Pdf,
+            extension: "pdf".to_string(),
+            filename: "cats.pdf".to_string(),
+            content_hash: String::new(),
+            deleted_at: None,
+        };
+    }
+
+    /// Authors with a particle, a suffix and a corporate name.
+    pub(crate) fn authors() -> Vec<Name> {
+        return Name::parse_list(
+            "Doe, Jane Ann and van Beethoven, Jr., Ludwig and {World Health Organization}",
+        );
+    }
+
+    /// Check that `imported`, read back from an export of `doc`, has every field of it.
+    pub(crate) fn assert_same_document(
+        doc: &DatabaseDoc,
+        authors: &[Name],
+        imported: &Document,
+        file: &Path,
+    ) {
+        assert_eq!(imported.title, doc.title);
+        assert_eq!(Name::parse_list(&imported.author), authors);
+        assert_eq!(imported.year, doc.year);
+        assert_eq!(imported.publication, doc.publication);
+        assert_eq!(imported.volume, doc.volume);
+        assert_eq!(imported.tags, doc.tags);
+        assert_eq!(imported.doi, doc.doi);
+        assert_eq!(imported.doc_type, Some(doc.doc_type));
+        assert_eq!(imported.extra, doc.extra);
+        assert_eq!(imported.path, file);
+    }
+
+    pub(crate) async fn count(table: &str, pool: &SqlitePool) -> i64 {
+        return sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn insert_fails_at(failpoint: &'static str) {
+        let pool = library(failpoint).await;
+        let doc = document(failpoint);
+        let path = doc.path.clone();
+        failpoint::arm(failpoint);
+        let result = DatabaseDoc::from_insert(doc, DuplicatePolicy::Reject, &pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        for table in TABLES.iter() {
+            assert_eq!(count(table, &pool).await, 0, "rows left in {}", table);
+        }
+        assert_eq!(stored_copies(&path), Vec::<PathBuf>::new());
+    }
+
+    /// A stored document with one attachment.
+    pub(crate) async fn stored_document(
+        name: &str,
+        pool: &SqlitePool,
+    ) -> (DatabaseDoc, Attachment) {
+        let dbd = DatabaseDoc::from_insert(document(name), DuplicatePolicy::Reject, pool)
+            .await
+            .unwrap();
+        let path = test_path(&format!("{}-slides", name), "txt");
+        std::fs::write(&path, format!("Slides of {}", name)).unwrap();
+        let attachment = Attachment::from_insert(&dbd, &path, AttachmentRole::Slides, pool)
+            .await
+            .unwrap();
+        return (dbd, attachment);
+    }
+
+    async fn delete_fails_at(failpoint: &'static str) {
+        let pool = library(failpoint).await;
+        let (dbd, attachment) = stored_document(failpoint, &pool).await;
+        let id = dbd.id;
+        failpoint::arm(failpoint);
+        let result = dbd.delete(&pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        let dbd = DatabaseDoc::from_id(id, &pool).await.unwrap().unwrap();
+        assert_eq!(dbd.tags, "one,two");
+        assert_eq!(dbd.author, "Doe, Jane");
+        assert!(dbd.is_stored());
+        assert_eq!(Attachment::for_doc(id, &pool).await.unwrap().len(), 1);
+        assert!(attachment.stored_path().is_ok());
+        assert_eq!(count("document_text", &pool).await, 1);
+    }
+
+    async fn trash_fails_at(failpoint: &'static str) {
+        let pool = library(failpoint).await;
+        let (dbd, attachment) = stored_document(failpoint, &pool).await;
+        let id = dbd.id;
+        failpoint::arm(failpoint);
+        let result = dbd.trash(&pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        let dbd = DatabaseDoc::from_id(id, &pool).await.unwrap().unwrap();
+        assert!(!dbd.is_trashed());
+        assert!(dbd.is_stored());
+        assert!(attachment.stored_path().is_ok());
+        assert_eq!(DocList::get_all(&pool).await.unwrap().len(), 1);
+    }
+
+    async fn restore_fails_at(failpoint: &'static str) {
+        let name = format!("restore-{}", failpoint);
+        let pool = library(&name).await;
+        let (dbd, attachment) = stored_document(&name, &pool).await;
+        let id = dbd.id;
+        dbd.trash(&pool).await.unwrap();
+        let dbd = DatabaseDoc::from_id(id, &pool).await.unwrap().unwrap();
+        failpoint::arm(failpoint);
+        let result = dbd.restore(&pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        let dbd = DatabaseDoc::from_id(id, &pool).await.unwrap().unwrap();
+        assert!(dbd.is_trashed());
+        assert!(dbd.is_stored());
+        assert!(Config::get()
+            .trashed_file(&attachment.stored_name())
+            .exists());
+        assert_eq!(DocList::trashed(&pool).await.unwrap().len(), 1);
+    }
+
+    async fn update_fails_at(failpoint: &'static str) {
+        let pool = library(failpoint).await;
+        let (mut dbd, _) = stored_document(failpoint, &pool).await;
+        let id = dbd.id;
+        dbd.title = "Changed".to_string();
+        dbd.tags = "three".to_string();
+        dbd.author = "Roe, John".to_string();
+        failpoint::arm(failpoint);
+        let result = dbd.update(&pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        let dbd = DatabaseDoc::from_id(id, &pool).await.unwrap().unwrap();
+        assert_eq!(dbd.title, format!("Test {}", failpoint));
+        assert_eq!(dbd.tags, "one,two");
+        assert_eq!(dbd.author, "Doe, Jane");
+    }
+
+    async fn attach_fails_at(failpoint: &'static str) {
+        let name = format!("attach-{}", failpoint);
+        let pool = library(&name).await;
+        let dbd = DatabaseDoc::from_insert(document(&name), DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        let path = test_path(&format!("{}-slides", name), "txt");
+        std::fs::write(&path, format!("Slides of {}", name)).unwrap();
+        failpoint::arm(failpoint);
+        let result = Attachment::from_insert(&dbd, &path, AttachmentRole::Slides, &pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        assert_eq!(count("attachments", &pool).await, 0);
+        assert_eq!(stored_copies(&path), Vec::<PathBuf>::new());
+    }
+
+    async fn detach_fails_at(failpoint: &'static str) {
+        let name = format!("detach-{}", failpoint);
+        let pool = library(&name).await;
+        let (_, attachment) = stored_document(&name, &pool).await;
+        let id = attachment.id;
+        failpoint::arm(failpoint);
+        let result = attachment.delete(&pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        let attachment = Attachment::from_id(id, &pool).await.unwrap().unwrap();
+        assert!(attachment.stored_path().is_ok());
+    }
+
+    #[tokio::test]
+    async fn insert_stores_record_and_file() {
+        let pool = library("insert").await;
+        let doc = document("insert");
+        let path = doc.path.clone();
+        let dbd = DatabaseDoc::from_insert(doc, DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        assert_eq!(dbd.tags, "one,two");
+        assert_eq!(
+            stored_copies(&path),
+            vec![Config::get().stored_file(&dbd.stored_name())]
+        );
+        assert_eq!(count("document_text", &pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn insert_links_same_contents_to_existing_document() {
+        let pool = library("link").await;
+        let doc = document("link");
+        let path = doc.path.clone();
+        let existing = DatabaseDoc::from_insert(doc, DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        let copy = DocumentBuilder::new("Another title", &path.to_string_lossy())
+            .tags("three")
+            .build()
+            .unwrap();
+        let linked = DatabaseDoc::from_insert(copy, DuplicatePolicy::Link, &pool)
+            .await
+            .unwrap();
+        assert_eq!(linked.id, existing.id);
+        assert_eq!(linked.title, "Test link");
+        assert_eq!(linked.tags, "one,three,two");
+        assert_eq!(count("documents", &pool).await, 1);
+        assert_eq!(stored_copies(&path).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn insert_keeps_extension_of_file() {
+        let pool = library("extension").await;
+        // A multibyte character cut off at the end of the sniffed prefix
+        let path = test_path("extension", "markdown");
+        let mut contents = format!("# {}\n\n", "extension");
+        contents.push_str(&"x".repeat(4095 - contents.len()));
+        contents.push_str("\u{e9} is still text.\n");
+        std::fs::write(&path, contents).unwrap();
+        let doc = DocumentBuilder::new("Test extension", &path.to_string_lossy())
+            .build()
+            .unwrap();
+        let dbd = DatabaseDoc::from_insert(doc, DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        assert_eq!(dbd.file_type, FileType::Markdown);
+        assert_eq!(dbd.stored_name(), format!("{}.markdown", dbd.uuid));
+        assert!(dbd.stored_file().is_file());
+    }
+
+    #[tokio::test]
+    async fn insert_rolls_back_when_row_fails() {
+        insert_fails_at("insert:row").await;
+    }
+
+    #[tokio::test]
+    async fn insert_rolls_back_when_copy_fails() {
+        insert_fails_at("store:copy").await;
+    }
+
+    #[tokio::test]
+    async fn insert_rolls_back_when_rename_fails() {
+        insert_fails_at("store:persist").await;
+    }
+
+    #[tokio::test]
+    async fn insert_rolls_back_when_commit_fails() {
+        insert_fails_at("insert:commit").await;
+    }
+
+    #[tokio::test]
+    async fn delete_removes_records_and_files() {
+        let pool = library("delete").await;
+        let (dbd, attachment) = stored_document("delete", &pool).await;
+        let path = Config::get().stored_file(&dbd.stored_name());
+        dbd.delete(&pool).await.unwrap();
+        for table in ["documents", "document_tags", "attachments", "document_text"] {
+            assert_eq!(count(table, &pool).await, 0, "rows left in {}", table);
+        }
+        assert!(!path.exists());
+        assert!(attachment.stored_path().is_err());
+    }
+
+    #[tokio::test]
+    async fn delete_rolls_back_when_row_fails() {
+        delete_fails_at("delete:row").await;
+    }
+
+    #[tokio::test]
+    async fn delete_rolls_back_when_removing_file_fails() {
+        delete_fails_at("store:remove").await;
+    }
+
+    #[tokio::test]
+    async fn delete_rolls_back_when_commit_fails() {
+        delete_fails_at("delete:commit").await;
+    }
+
+    #[tokio::test]
+    async fn trash_moves_files_and_restore_moves_them_back() {
+        let pool = library("trash").await;
+        let (dbd, attachment) = stored_document("trash", &pool).await;
+        let id = dbd.id;
+        let path = Config::get().stored_file(&dbd.stored_name());
+        let attachment_path = attachment.stored_path().unwrap();
+        dbd.trash(&pool).await.unwrap();
+        let dbd = DatabaseDoc::from_id(id, &pool).await.unwrap().unwrap();
+        assert!(dbd.is_trashed());
+        assert_eq!(
+            dbd.stored_path().unwrap(),
+            Config::get().trashed_file(&dbd.stored_name())
+        );
+        assert!(!path.exists());
+        assert!(Config::get()
+            .trashed_file(&attachment.stored_name())
+            .is_file());
+        assert_eq!(DocList::get_all(&pool).await.unwrap().len(), 0);
+        assert_eq!(DocList::trashed(&pool).await.unwrap().len(), 1);
+
+        dbd.restore(&pool).await.unwrap();
+        let dbd = DatabaseDoc::from_id(id, &pool).await.unwrap().unwrap();
+        assert!(!dbd.is_trashed());
+        assert_eq!(dbd.stored_path().unwrap(), path);
+        assert!(attachment_path.is_file());
+        assert_eq!(DocList::get_all(&pool).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn delete_removes_trashed_records_and_files() {
+        let pool = library("delete-trashed").await;
+        let (dbd, attachment) = stored_document("delete-trashed", &pool).await;
+        let id = dbd.id;
+        dbd.trash(&pool).await.unwrap();
+        let dbd = DatabaseDoc::from_id(id, &pool).await.unwrap().unwrap();
+        let path = dbd.stored_path().unwrap();
+        dbd.delete(&pool).await.unwrap();
+        for table in ["documents", "document_tags", "attachments", "document_text"] {
+            assert_eq!(count(table, &pool).await, 0, "rows left in {}", table);
+        }
+        assert!(!path.exists());
+        assert!(!Config::get()
+            .trashed_file(&attachment.stored_name())
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn trash_rolls_back_when_row_fails() {
+        trash_fails_at("trash:row").await;
+    }
+
+    #[tokio::test]
+    async fn trash_rolls_back_when_moving_file_fails() {
+        trash_fails_at("store:move").await;
+    }
+
+    #[tokio::test]
+    async fn trash_rolls_back_when_commit_fails() {
+        trash_fails_at("trash:commit").await;
+    }
+
+    #[tokio::test]
+    async fn trash_from_title_keeps_records_with_missing_file() {
+        let pool = library("trash-missing").await;
+        let (dbd, _) = stored_document("trash-missing", &pool).await;
+        std::fs::remove_file(dbd.stored_file()).unwrap();
+        Document::trash_from_title(&dbd.title, &pool).await.unwrap();
+        let dbd = DatabaseDoc::from_id(dbd.id, &pool).await.unwrap().unwrap();
+        assert!(dbd.is_trashed());
+        assert!(Document::trash_from_title("No such title", &pool)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn restore_rolls_back_when_row_fails() {
+        restore_fails_at("restore:row").await;
+    }
+
+    #[tokio::test]
+    async fn restore_rolls_back_when_moving_file_fails() {
+        restore_fails_at("store:move").await;
+    }
+
+    #[tokio::test]
+    async fn restore_rolls_back_when_commit_fails() {
+        restore_fails_at("restore:commit").await;
+    }
+
+    #[tokio::test]
+    async fn update_rolls_back_when_row_fails() {
+        update_fails_at("update:row").await;
+    }
+
+    #[tokio::test]
+    async fn update_rolls_back_when_commit_fails() {
+        update_fails_at("update:commit").await;
+    }
+
+    #[tokio::test]
+    async fn detach_removes_record_and_file() {
+        let pool = library("detach").await;
+        let (dbd, attachment) = stored_document("detach", &pool).await;
+        let path = Config::get().stored_file(&attachment.stored_name());
+        attachment.delete(&pool).await.unwrap();
+        assert_eq!(count("attachments", &pool).await, 0);
+        assert!(!path.exists());
+        assert!(dbd.is_stored());
+    }
+
+    #[tokio::test]
+    async fn detach_refuses_attachment_of_trashed_document() {
+        let pool = library("detach-trashed").await;
+        let (dbd, attachment) = stored_document("detach-trashed", &pool).await;
+        let id = attachment.id;
+        dbd.trash(&pool).await.unwrap();
+        let attachment = Attachment::from_id(id, &pool).await.unwrap().unwrap();
+        let path = Config::get().trashed_file(&attachment.stored_name());
+        assert!(attachment.delete(&pool).await.is_err());
+        assert!(Attachment::from_id(id, &pool).await.unwrap().is_some());
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn attach_rolls_back_when_row_fails() {
+        attach_fails_at("attach:row").await;
+    }
+
+    #[tokio::test]
+    async fn attach_rolls_back_when_copy_fails() {
+        attach_fails_at("store:copy").await;
+    }
+
+    #[tokio::test]
+    async fn attach_rolls_back_when_rename_fails() {
+        attach_fails_at("store:persist").await;
+    }
+
+    #[tokio::test]
+    async fn attach_rolls_back_when_commit_fails() {
+        attach_fails_at("attach:commit").await;
+    }
+
+    #[tokio::test]
+    async fn detach_rolls_back_when_row_fails() {
+        detach_fails_at("detach:row").await;
+    }
+
+    #[tokio::test]
+    async fn detach_rolls_back_when_removing_file_fails() {
+        detach_fails_at("store:remove").await;
+    }
+
+    #[tokio::test]
+    async fn detach_rolls_back_when_commit_fails() {
+        detach_fails_at("detach:commit").await;
     }
 }
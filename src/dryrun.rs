@@ -0,0 +1,318 @@
+//! `--dry-run`: mutating commands make their changes in a transaction that is rolled back
+//! instead of committed, and print the rows and files they would have changed.
+
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{Executor, Sqlite, SqliteConnection, SqlitePool};
+use std::collections::BTreeMap;
+
+#[cfg(not(test))]
+static ENABLED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// Tests run in parallel, so a dry run only applies to the thread of the test that enabled it
+#[cfg(test)]
+thread_local! {
+    static ENABLED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Tables a command can change, in the order their changes are printed.
+const TABLES: &[&str] = &[
+    "documents",
+    "document_text",
+    "attachments",
+    "tags",
+    "document_tags",
+    "authors",
+    "document_authors",
+];
+
+pub fn enable() {
+    #[cfg(not(test))]
+    ENABLED.store(true, std::sync::atomic::Ordering::Relaxed);
+    #[cfg(test)]
+    ENABLED.with(|enabled| enabled.set(true));
+}
+
+#[cfg(test)]
+pub fn disable() {
+    ENABLED.with(|enabled| enabled.set(false));
+}
+
+pub fn enabled() -> bool {
+    #[cfg(not(test))]
+    return ENABLED.load(std::sync::atomic::Ordering::Relaxed);
+    #[cfg(test)]
+    return ENABLED.with(|enabled| enabled.get());
+}
+
+/// Open the library for a dry run.  Every connection records the rowids that its writes
+/// touch in the temporary table `dryrun_changes`, so `print_changes` only has to compare
+/// those rows.  The records are rolled back with the rest of the transaction.
+pub async fn connect(options: SqliteConnectOptions) -> anyhow::Result<SqlitePool> {
+    return Ok(SqlitePoolOptions::new()
+        .after_connect(|conn, _| Box::pin(record_changes(conn)))
+        .connect_with(options)
+        .await?);
+}
+
+async fn record_changes(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        CREATE TEMP TABLE dryrun_changes (
+            tbl TEXT NOT NULL,
+            row INTEGER NOT NULL
+        )
+        "#,
+    )
+    .execute(&mut *conn)
+    .await?;
+    // Virtual tables cannot have triggers; `document_text` rows share the id of their document
+    for table in TABLES.iter().filter(|t| **t != "document_text") {
+        for (event, row) in [("INSERT", "NEW"), ("UPDATE", "NEW"), ("DELETE", "OLD")] {
+            sqlx::query(&format!(
+                r#"
+                CREATE TEMP TRIGGER dryrun_{table}_{event} AFTER {event} ON main.{table}
+                BEGIN
+                    INSERT INTO dryrun_changes (tbl, row) VALUES ('{table}', {row}.rowid);
+                END
+                "#,
+                table = table,
+                event = event,
+                row = row
+            ))
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+    return Ok(());
+}
+
+/// Rows of `table` with the given rowids, keyed by rowid, as JSON objects of `columns`.
+/// Indexed text is summarized by its length.
+async fn table_rows<'e, E>(
+    table: &str,
+    columns: &[String],
+    rowids: &[i64],
+    executor: E,
+) -> anyhow::Result<BTreeMap<i64, String>>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let fields = match table {
+        "document_text" => "'characters', length(content)".to_string(),
+        _ => columns
+            .iter()
+            .map(|c| format!("'{}', \"{}\"", c, c))
+            .collect::<Vec<String>>()
+            .join(", "),
+    };
+    let rowids = rowids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    let sql = format!(
+        "SELECT rowid, json_object({}) FROM {} WHERE rowid IN ({})",
+        fields, table, rowids
+    );
+    let rows: Vec<(i64, String)> = sqlx::query_as(&sql).fetch_all(executor).await?;
+    return Ok(rows.into_iter().collect());
+}
+
+/// Columns of a row that hold a value, as `name: value`.
+fn describe(row: &str) -> String {
+    let fields =
+        serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(row).unwrap_or_default();
+    return fields
+        .iter()
+        .filter(|(_, v)| !v.is_null() && v.as_str() != Some(""))
+        .map(|(k, v)| format!("{}: {}", k, v))
+        .collect::<Vec<String>>()
+        .join(", ");
+}
+
+/// Columns that differ between two versions of a row, as `name: old -> new`.
+fn describe_update(before: &str, after: &str) -> String {
+    let before = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(before)
+        .unwrap_or_default();
+    let after = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(after)
+        .unwrap_or_default();
+    return after
+        .iter()
+        .filter(|(k, v)| before.get(*k) != Some(*v))
+        .map(|(k, v)| {
+            let old = before.get(k).cloned().unwrap_or_default();
+            format!("{}: {} -> {}", k, old, v)
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+}
+
+/// Print every row that the uncommitted transaction of `conn` inserted, updated or deleted,
+/// compared to the committed state seen through `pool`.  `conn` must come from a pool
+/// opened with `connect`.
+pub async fn print_changes(conn: &mut SqliteConnection, pool: &SqlitePool) -> anyhow::Result<()> {
+    let mut touched: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+    for (table, rowid) in sqlx::query_as::<_, (String, i64)>(
+        r#"
+        SELECT DISTINCT tbl, row FROM temp.dryrun_changes
+        ORDER BY tbl, row
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    {
+        touched.entry(table).or_default().push(rowid);
+    }
+    if let Some(ids) = touched.get("documents").cloned() {
+        touched.insert("document_text".to_string(), ids);
+    }
+
+    let mut changed = false;
+    for table in TABLES.iter() {
+        let rowids = match touched.get(*table) {
+            Some(rowids) => rowids,
+            None => continue,
+        };
+        let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?1)")
+            .bind(table)
+            .fetch_all(pool)
+            .await?;
+        let before = table_rows(table, &columns, rowids, pool).await?;
+        let after = table_rows(table, &columns, rowids, &mut *conn).await?;
+        for (rowid, row) in after.iter() {
+            match before.get(rowid) {
+                None => println!("would insert {} row {}: {}", table, rowid, describe(row)),
+                Some(old) if old != row => println!(
+                    "would update {} row {}: {}",
+                    table,
+                    rowid,
+                    describe_update(old, row)
+                ),
+                Some(_) => continue,
+            }
+            changed = true;
+        }
+        for (rowid, row) in before.iter() {
+            if !after.contains_key(rowid) {
+                println!("would delete {} row {}: {}", table, rowid, describe(row));
+                changed = true;
+            }
+        }
+    }
+    if !changed {
+        println!("would change no rows");
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment::{Attachment, AttachmentRole};
+    use crate::author::DatabaseAuthor;
+    use crate::document::tests::{document, library, stored_copies, stored_document, test_path};
+    use crate::document::{DatabaseDoc, DuplicatePolicy};
+    use crate::tag::{DatabaseTag, Tag, TagInputList};
+    use std::path::PathBuf;
+
+    /// Every row of every table a command can change.
+    async fn snapshot(pool: &SqlitePool) -> Vec<BTreeMap<i64, String>> {
+        let mut tables = Vec::new();
+        for table in TABLES.iter() {
+            let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?1)")
+                .bind(table)
+                .fetch_all(pool)
+                .await
+                .unwrap();
+            let rowids: Vec<i64> = sqlx::query_scalar(&format!("SELECT rowid FROM {}", table))
+                .fetch_all(pool)
+                .await
+                .unwrap();
+            tables.push(table_rows(table, &columns, &rowids, pool).await.unwrap());
+        }
+        return tables;
+    }
+
+    #[tokio::test]
+    async fn dry_run_changes_neither_library_nor_store() {
+        let pool = library("dryrun").await;
+        let (dbd, attachment) = stored_document("dryrun", &pool).await;
+        let (trashed, _) = stored_document("dryrun-trashed", &pool).await;
+        let trashed_id = trashed.id;
+        trashed.trash(&pool).await.unwrap();
+        let mut other = document("dryrun-other");
+        other.author = "Roe, John".to_string();
+        DatabaseDoc::from_insert(other, DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        let before = snapshot(&pool).await;
+        let added = document("dryrun-added");
+        let added_path = added.path.clone();
+        let notes = test_path("dryrun-notes", "txt");
+        std::fs::write(&notes, "Notes of dryrun").unwrap();
+
+        let dry = connect(SqliteConnectOptions::new().filename(test_path("dryrun", "db")))
+            .await
+            .unwrap();
+        enable();
+        DatabaseDoc::from_insert(added, DuplicatePolicy::Reject, &dry)
+            .await
+            .unwrap();
+        let mut modified = DatabaseDoc::from_id(dbd.id, &dry).await.unwrap().unwrap();
+        modified.title = "Changed".to_string();
+        modified.author = "Roe, John".to_string();
+        modified.tags = "three".to_string();
+        modified.update(&dry).await.unwrap();
+        DatabaseTag::from_value("one", &dry)
+            .await
+            .unwrap()
+            .unwrap()
+            .rename("four", &dry)
+            .await
+            .unwrap();
+        Tag::new("five").insert(&dry).await.unwrap();
+        Tag::new("two").delete(&dry).await.unwrap();
+        TagInputList::from("one")
+            .delete_from_db(&dry)
+            .await
+            .unwrap();
+        let authors = DatabaseAuthor::get_all(&dry).await.unwrap();
+        authors[1]
+            .author
+            .clone()
+            .merge(&authors[0].author, &dry)
+            .await
+            .unwrap();
+        Attachment::from_insert(&dbd, &notes, AttachmentRole::Code, &dry)
+            .await
+            .unwrap();
+        let existing = Attachment::from_id(attachment.id, &dry)
+            .await
+            .unwrap()
+            .unwrap();
+        existing.delete(&dry).await.unwrap();
+        let doc = DatabaseDoc::from_id(dbd.id, &dry).await.unwrap().unwrap();
+        doc.trash(&dry).await.unwrap();
+        let doc = DatabaseDoc::from_id(dbd.id, &dry).await.unwrap().unwrap();
+        doc.delete(&dry).await.unwrap();
+        let doc = DatabaseDoc::from_id(trashed_id, &dry)
+            .await
+            .unwrap()
+            .unwrap();
+        doc.restore(&dry).await.unwrap();
+        assert_eq!(crate::trash::empty(None, &dry).await.unwrap(), 1);
+        disable();
+
+        assert_eq!(snapshot(&pool).await, before);
+        assert_eq!(stored_copies(&added_path), Vec::<PathBuf>::new());
+        assert_eq!(stored_copies(&notes), Vec::<PathBuf>::new());
+        assert!(dbd.is_stored());
+        assert!(attachment.stored_path().is_ok());
+        let trashed = DatabaseDoc::from_id(trashed_id, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(trashed.is_trashed());
+        assert!(trashed.is_stored());
+    }
+}
@@ -0,0 +1,29 @@
+//! Failure injection for tests of operations that must roll back as a whole.  Outside of
+//! tests every failpoint passes.
+
+#[cfg(test)]
+thread_local! {
+    static ARMED: std::cell::RefCell<Option<&'static str>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Fail with an error when the failpoint `name` is armed.
+pub fn check(name: &str) -> anyhow::Result<()> {
+    #[cfg(test)]
+    if ARMED.with(|armed| *armed.borrow() == Some(name)) {
+        return Err(anyhow::anyhow!("Injected failure at {}", name));
+    }
+    #[cfg(not(test))]
+    let _ = name;
+    return Ok(());
+}
+
+/// Make the failpoint `name` fail on the current thread until `disarm` is called.
+#[cfg(test)]
+pub fn arm(name: &'static str) {
+    ARMED.with(|armed| *armed.borrow_mut() = Some(name));
+}
+
+#[cfg(test)]
+pub fn disarm() {
+    ARMED.with(|armed| *armed.borrow_mut() = None);
+}
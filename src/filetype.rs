@@ -0,0 +1,191 @@
+use std::io::Read;
+use std::path::Path;
+
+/// Number of leading bytes read when sniffing the format of a file.
+const SNIFF_LEN: u64 = 4096;
+
+/// A supported document format.  The stored copy of a document keeps the extension of the
+/// file it was added from, so openers configured by extension keep working.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FileType {
+    Pdf,
+    Epub,
+    Djvu,
+    Html,
+    Markdown,
+    Docx,
+}
+
+/// Every supported format, in the order they are tried when guessing from an extension.
+pub const FILE_TYPES: &[FileType] = &[
+    FileType::Pdf,
+    FileType::Epub,
+    FileType::Djvu,
+    FileType::Html,
+    FileType::Markdown,
+    FileType::Docx,
+];
+
+impl FileType {
+    /// Name stored in the `file_type` column and accepted as an `[openers]` key.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Self::Pdf => "pdf",
+            Self::Epub => "epub",
+            Self::Djvu => "djvu",
+            Self::Html => "html",
+            Self::Markdown => "markdown",
+            Self::Docx => "docx",
+        };
+    }
+
+    /// Recognized file extensions, canonical extension first.
+    pub fn extensions(&self) -> &'static [&'static str] {
+        return match self {
+            Self::Pdf => &["pdf"],
+            Self::Epub => &["epub"],
+            Self::Djvu => &["djvu", "djv"],
+            Self::Html => &["html", "htm", "xhtml"],
+            Self::Markdown => &["md", "markdown"],
+            Self::Docx => &["docx"],
+        };
+    }
+
+    /// Canonical extension of this format, given to stored copies of documents added before
+    /// their original extension was recorded.
+    pub fn extension(&self) -> &'static str {
+        return self.extensions()[0];
+    }
+
+    pub fn mime(&self) -> &'static str {
+        return match self {
+            Self::Pdf => "application/pdf",
+            Self::Epub => "application/epub+zip",
+            Self::Djvu => "image/vnd.djvu",
+            Self::Html => "text/html",
+            Self::Markdown => "text/markdown",
+            Self::Docx => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        };
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        let ext = ext.to_lowercase();
+        return FILE_TYPES
+            .iter()
+            .find(|t| t.extensions().contains(&ext.as_str()))
+            .copied();
+    }
+
+    /// Determine the format of the file at `path` from its extension, and check that its
+    /// contents really are of that format.
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!(
+                "Path does not reference a file: {:?}",
+                path
+            ));
+        }
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file_type = match Self::from_extension(&ext) {
+            Some(file_type) => file_type,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported file type {:?}: expected one of {}",
+                    path,
+                    FILE_TYPES
+                        .iter()
+                        .map(|t| t.extension())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ))
+            }
+        };
+        if !file_type.sniff(path)? {
+            return Err(anyhow::anyhow!(
+                "Path does not reference a valid {}: {:?}",
+                file_type,
+                path
+            ));
+        }
+        return Ok(file_type);
+    }
+
+    /// Check the contents of the file at `path` against the signature of this format.
+    pub fn sniff(&self, path: &Path) -> anyhow::Result<bool> {
+        let mut head = Vec::new();
+        std::fs::File::open(path)?
+            .take(SNIFF_LEN)
+            .read_to_end(&mut head)?;
+        return Ok(match self {
+            // Readers accept the header anywhere in the first kilobyte
+            Self::Pdf => find(&head[..head.len().min(1024)], b"%PDF-").is_some(),
+            // The uncompressed `mimetype` entry must come first in the archive
+            Self::Epub => {
+                head.starts_with(b"PK\x03\x04")
+                    && head.get(30..58) == Some(b"mimetypeapplication/epub+zip".as_slice())
+            }
+            Self::Djvu => {
+                head.starts_with(b"AT&TFORM")
+                    && matches!(head.get(12..16), Some(b"DJVU") | Some(b"DJVM"))
+            }
+            Self::Html => {
+                let text = String::from_utf8_lossy(&head).to_lowercase();
+                let text = text.trim_start_matches('\u{feff}').trim_start();
+                text.starts_with("<!doctype html") || text.contains("<html")
+            }
+            // A character may be cut off at the end of a full sniffing buffer
+            Self::Markdown => {
+                !head.contains(&0)
+                    && match std::str::from_utf8(&head) {
+                        Ok(_) => true,
+                        Err(e) => e.error_len().is_none() && head.len() as u64 == SNIFF_LEN,
+                    }
+            }
+            // Office documents are zip archives; the main part is listed in the central
+            // directory at the end of the file
+            Self::Docx => {
+                head.starts_with(b"PK\x03\x04")
+                    && find(&std::fs::read(path)?, b"word/document.xml").is_some()
+            }
+        });
+    }
+}
+
+impl std::fmt::Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Pdf => "PDF",
+            Self::Epub => "EPUB",
+            Self::Djvu => "DjVu",
+            Self::Html => "HTML",
+            Self::Markdown => "Markdown",
+            Self::Docx => "DOCX",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+impl std::str::FromStr for FileType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return FILE_TYPES
+            .iter()
+            .find(|t| t.name() == s)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Unknown file type: {:?}", s));
+    }
+}
+
+impl TryFrom<String> for FileType {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        return value.parse();
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    return haystack.windows(needle.len()).position(|w| w == needle);
+}
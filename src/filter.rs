@@ -0,0 +1,363 @@
+use crate::query::TagQuery;
+
+/// An inclusive range of publication years, written `2015..2020`, `2015..`, `..2020` or
+/// `2015`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct YearRange {
+    pub start: Option<u16>,
+    pub end: Option<u16>,
+}
+
+impl std::str::FromStr for YearRange {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_year = |v: &str| -> anyhow::Result<Option<u16>> {
+            let v = v.trim();
+            if v.is_empty() {
+                return Ok(None);
+            }
+            return v
+                .parse::<u16>()
+                .map(Some)
+                .map_err(|_| anyhow::anyhow!("Invalid year: {:?}", v));
+        };
+        let range = match s.split_once("..") {
+            Some((start, end)) => Self {
+                start: parse_year(start)?,
+                end: parse_year(end)?,
+            },
+            None => {
+                let year = parse_year(s)?;
+                Self {
+                    start: year,
+                    end: year,
+                }
+            }
+        };
+        if let (Some(start), Some(end)) = (range.start, range.end) {
+            if start > end {
+                return Err(anyhow::anyhow!("Year range is reversed: {}", s));
+            }
+        }
+        return Ok(range);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum SortKey {
+    #[default]
+    Id,
+    Title,
+    Author,
+    Year,
+}
+
+impl SortKey {
+    fn order_by(&self) -> &'static str {
+        return match self {
+            Self::Id => "documents.id",
+            Self::Title => "documents.title_key, documents.id",
+            Self::Author => {
+                r#"(
+                    SELECT authors.name_key FROM document_authors
+                    JOIN authors ON authors.id = document_authors.author_id
+                    WHERE document_authors.doc_id = documents.id
+                    ORDER BY document_authors.position
+                    LIMIT 1
+                ), documents.year, documents.id"#
+            }
+            Self::Year => "documents.year, documents.title_key, documents.id",
+        };
+    }
+}
+
+/// A value bound to a `?` placeholder of a filter query.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlParam {
+    Text(String),
+    Int(i64),
+}
+
+/// Conditions on document records, compiled to a parameterized query over `documents`.
+/// Every condition that is set must hold.
+#[derive(Clone, Debug, Default)]
+pub struct DocFilter {
+    pub ids: Vec<u32>,
+    pub tags: Option<TagQuery>,
+    /// An author id, or a case insensitive substring of an author name written either
+    /// `First Last` or `Last, First`.
+    pub author: Option<String>,
+    /// Case insensitive substring of the publication field.
+    pub publication: Option<String>,
+    pub year: Option<YearRange>,
+    pub volume: Option<u16>,
+    pub has_doi: Option<bool>,
+    pub sort: SortKey,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+impl DocFilter {
+    /// The `WHERE`, `ORDER BY` and `LIMIT` clauses to append to `DOC_SELECT`, and the
+    /// parameters to bind, in placeholder order.
+    pub fn to_sql(&self) -> (String, Vec<SqlParam>) {
+        // Documents in the trash are only listed by `doc trash list`
+        let mut conditions = vec!["documents.deleted_at IS NULL".to_string()];
+        let mut params = Vec::new();
+        if !self.ids.is_empty() {
+            conditions.push(format!(
+                "documents.id IN ({})",
+                vec!["?"; self.ids.len()].join(", ")
+            ));
+            params.extend(self.ids.iter().map(|id| SqlParam::Int(*id as i64)));
+        }
+        if let Some(query) = &self.tags {
+            let (condition, values) = query.to_sql();
+            conditions.push(condition);
+            params.extend(values.into_iter().map(SqlParam::Text));
+        }
+        if let Some(author) = &self.author {
+            match author.trim().parse::<u32>() {
+                Ok(id) => {
+                    conditions.push(
+                        r#"EXISTS (
+                            SELECT 1 FROM document_authors
+                            WHERE document_authors.doc_id = documents.id
+                            AND document_authors.author_id = ?
+                        )"#
+                        .to_string(),
+                    );
+                    params.push(SqlParam::Int(id as i64));
+                }
+                Err(_) => {
+                    conditions.push(
+                        r#"EXISTS (
+                            SELECT 1 FROM document_authors
+                            JOIN authors ON authors.id = document_authors.author_id
+                            WHERE document_authors.doc_id = documents.id
+                            AND (
+                                trim(authors.first || ' ' || authors.last) LIKE ? ESCAPE '\'
+                                OR (authors.last || ', ' || authors.first) LIKE ? ESCAPE '\'
+                            )
+                        )"#
+                        .to_string(),
+                    );
+                    params.push(SqlParam::Text(like_pattern(author.trim())));
+                    params.push(SqlParam::Text(like_pattern(author.trim())));
+                }
+            }
+        }
+        if let Some(publication) = &self.publication {
+            conditions.push(r#"documents.publication LIKE ? ESCAPE '\'"#.to_string());
+            params.push(SqlParam::Text(like_pattern(publication)));
+        }
+        if let Some(year) = &self.year {
+            if let Some(start) = year.start {
+                conditions.push("documents.year >= ?".to_string());
+                params.push(SqlParam::Int(start as i64));
+            }
+            if let Some(end) = year.end {
+                conditions.push("documents.year <= ?".to_string());
+                params.push(SqlParam::Int(end as i64));
+            }
+        }
+        if let Some(volume) = self.volume {
+            conditions.push("documents.volume = ?".to_string());
+            params.push(SqlParam::Int(volume as i64));
+        }
+        match self.has_doi {
+            Some(true) => conditions.push("COALESCE(documents.doi, '') <> ''".to_string()),
+            Some(false) => conditions.push("COALESCE(documents.doi, '') = ''".to_string()),
+            None => {}
+        }
+
+        let mut sql = String::from(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+        sql.push_str(" ORDER BY ");
+        sql.push_str(self.sort.order_by());
+        if self.limit.is_some() || self.offset.is_some() {
+            sql.push_str(" LIMIT ? OFFSET ?");
+            params.push(SqlParam::Int(self.limit.map_or(-1, |l| l as i64)));
+            params.push(SqlParam::Int(self.offset.unwrap_or(0) as i64));
+        }
+        return (sql, params);
+    }
+}
+
+/// `%value%` with LIKE wildcards in `value` escaped.
+fn like_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    return format!("%{}%", escaped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{document, library};
+    use crate::document::{DatabaseDoc, DocList, DuplicatePolicy};
+
+    fn years(start: Option<u16>, end: Option<u16>) -> YearRange {
+        return YearRange { start, end };
+    }
+
+    #[test]
+    fn year_range_parses_every_form() {
+        assert_eq!(
+            "2015..2020".parse::<YearRange>().unwrap(),
+            years(Some(2015), Some(2020))
+        );
+        assert_eq!(
+            "2015..".parse::<YearRange>().unwrap(),
+            years(Some(2015), None)
+        );
+        assert_eq!(
+            "..2020".parse::<YearRange>().unwrap(),
+            years(None, Some(2020))
+        );
+        assert_eq!(
+            " 2015 ".parse::<YearRange>().unwrap(),
+            years(Some(2015), Some(2015))
+        );
+        assert_eq!(
+            "2020..2020".parse::<YearRange>().unwrap(),
+            years(Some(2020), Some(2020))
+        );
+    }
+
+    #[test]
+    fn year_range_rejects_reversed_and_invalid_years() {
+        let reversed = "2020..2015".parse::<YearRange>().unwrap_err();
+        assert!(reversed.to_string().contains("reversed"));
+        let invalid = "20x5..2020".parse::<YearRange>().unwrap_err();
+        assert!(invalid.to_string().contains("Invalid year"));
+        assert!("70000".parse::<YearRange>().is_err());
+        assert!("2015...2020".parse::<YearRange>().is_err());
+    }
+
+    #[test]
+    fn like_pattern_escapes_wildcards() {
+        assert_eq!(like_pattern("cats"), "%cats%");
+        assert_eq!(like_pattern("100%"), "%100\\%%");
+        assert_eq!(like_pattern("a_b"), "%a\\_b%");
+        assert_eq!(like_pattern("C:\\cats"), "%C:\\\\cats%");
+    }
+
+    #[test]
+    fn params_follow_placeholder_order() {
+        let filter = DocFilter {
+            ids: vec![3, 1],
+            tags: Some("cats".parse().unwrap()),
+            author: Some("Doe".to_string()),
+            publication: Some("Feline".to_string()),
+            year: Some(years(Some(2015), Some(2020))),
+            volume: Some(12),
+            has_doi: Some(true),
+            limit: Some(10),
+            offset: Some(20),
+            ..Default::default()
+        };
+        let (sql, params) = filter.to_sql();
+        assert_eq!(sql.matches('?').count(), params.len());
+        let mut expected = vec![SqlParam::Int(3), SqlParam::Int(1)];
+        expected.extend(
+            TagQuery::parse("cats")
+                .unwrap()
+                .to_sql()
+                .1
+                .into_iter()
+                .map(SqlParam::Text),
+        );
+        expected.extend([
+            SqlParam::Text("%Doe%".to_string()),
+            SqlParam::Text("%Doe%".to_string()),
+            SqlParam::Text("%Feline%".to_string()),
+            SqlParam::Int(2015),
+            SqlParam::Int(2020),
+            SqlParam::Int(12),
+            SqlParam::Int(10),
+            SqlParam::Int(20),
+        ]);
+        assert_eq!(params, expected);
+    }
+
+    #[test]
+    fn author_id_is_bound_once() {
+        let filter = DocFilter {
+            author: Some(" 7 ".to_string()),
+            ..Default::default()
+        };
+        let (sql, params) = filter.to_sql();
+        assert!(sql.contains("document_authors.author_id = ?"));
+        assert_eq!(params, vec![SqlParam::Int(7)]);
+    }
+
+    #[test]
+    fn limit_without_offset_starts_at_first_row() {
+        let (sql, params) = DocFilter {
+            limit: Some(5),
+            ..Default::default()
+        }
+        .to_sql();
+        assert!(sql.ends_with(" LIMIT ? OFFSET ?"));
+        assert_eq!(params, vec![SqlParam::Int(5), SqlParam::Int(0)]);
+
+        let (_, params) = DocFilter {
+            offset: Some(5),
+            ..Default::default()
+        }
+        .to_sql();
+        assert_eq!(params, vec![SqlParam::Int(-1), SqlParam::Int(5)]);
+
+        let (sql, params) = DocFilter::default().to_sql();
+        assert!(!sql.contains("LIMIT"));
+        assert!(params.is_empty());
+    }
+
+    #[tokio::test]
+    async fn filters_documents_in_library() {
+        let pool = library("filter").await;
+        for (name, year) in [("a", 2014), ("b", 2016), ("c", 2018), ("d", 2020)] {
+            let mut doc = document(&format!("filter-{}", name));
+            doc.year = year;
+            if name == "b" {
+                doc.publication = "100% Cats_Weekly".to_string();
+            }
+            DatabaseDoc::from_insert(doc, DuplicatePolicy::Reject, &pool)
+                .await
+                .unwrap();
+        }
+        let titles = |docs: DocList| -> Vec<String> {
+            return docs.0.into_iter().map(|d| d.title).collect();
+        };
+
+        let filter = DocFilter {
+            year: Some("2016..".parse().unwrap()),
+            sort: SortKey::Year,
+            offset: Some(1),
+            ..Default::default()
+        };
+        let docs = DocList::from_filter(&filter, &pool).await.unwrap();
+        assert_eq!(titles(docs), vec!["Test filter-c", "Test filter-d"]);
+
+        let filter = DocFilter {
+            sort: SortKey::Year,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let docs = DocList::from_filter(&filter, &pool).await.unwrap();
+        assert_eq!(titles(docs), vec!["Test filter-a", "Test filter-b"]);
+
+        // Wildcards in the filter only match themselves
+        for (publication, matches) in [("100%", 1), ("0% C", 1), ("s_W", 1), ("s%W", 0)] {
+            let filter = DocFilter {
+                publication: Some(publication.to_string()),
+                ..Default::default()
+            };
+            let docs = DocList::from_filter(&filter, &pool).await.unwrap();
+            assert_eq!(docs.len(), matches, "{}", publication);
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use crate::document::{DatabaseDoc, DocList};
+use crate::filetype::FileType;
+use regex::Regex;
+use sqlx::{FromRow, SqliteConnection, SqlitePool};
+use std::io::IsTerminal;
+use std::path::Path;
+
+/// Extract the text of a document file.  Text extraction is best effort: malformed files
+/// and formats without an extractor are reported as errors rather than aborting the command.
+pub fn extract_text(path: &Path) -> anyhow::Result<String> {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_default();
+    return match FileType::from_extension(&ext) {
+        Some(FileType::Pdf) => extract_pdf_text(path),
+        Some(FileType::Html) => Ok(strip_html(&std::fs::read_to_string(path)?)),
+        Some(FileType::Markdown) => Ok(std::fs::read_to_string(path)?),
+        Some(file_type) => Err(anyhow::anyhow!(
+            "No text extractor for {} files: {:?}",
+            file_type,
+            path
+        )),
+        None => Err(anyhow::anyhow!("Unsupported file type: {:?}", path)),
+    };
+}
+
+fn extract_pdf_text(path: &Path) -> anyhow::Result<String> {
+    let owned = path.to_path_buf();
+    return match std::panic::catch_unwind(move || pdf_extract::extract_text(owned)) {
+        Ok(Ok(text)) => Ok(text),
+        Ok(Err(e)) => Err(anyhow::anyhow!(
+            "Could not extract text from {:?}: {}",
+            path,
+            e
+        )),
+        Err(_) => Err(anyhow::anyhow!("Text extractor failed on {:?}", path)),
+    };
+}
+
+/// Text content of an HTML page, without markup, scripts and styles.
+fn strip_html(html: &str) -> String {
+    let hidden = Regex::new(r"(?is)<(script|style)[^>]*>.*?</(script|style)>")
+        .expect("valid script pattern");
+    let tags = Regex::new(r"(?s)<[^>]*>").expect("valid tag pattern");
+    let text = hidden.replace_all(html, " ");
+    return tags
+        .replace_all(&text, " ")
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&");
+}
+
+/// Store the text of the file at `path` in the full-text index as the text of document
+/// `doc_id`, replacing any earlier entry.  Documents without extractable text are indexed
+/// with empty content so they are not picked up again by `doc reindex`.
+pub async fn index_document(
+    doc_id: u32,
+    path: &Path,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let text = match extract_text(path) {
+        Ok(text) => text,
+        Err(e) => {
+            log::warn!("{}", e);
+            String::new()
+        }
+    };
+    log::info!(
+        "Indexing {} characters of text for document {}",
+        text.len(),
+        doc_id
+    );
+    sqlx::query(
+        r#"
+        DELETE FROM document_text
+        WHERE rowid=?1
+        "#,
+    )
+    .bind(doc_id)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO document_text (rowid, content)
+        VALUES (?1, ?2)
+        "#,
+    )
+    .bind(doc_id)
+    .bind(text)
+    .execute(&mut *conn)
+    .await?;
+    return Ok(());
+}
+
+/// Index every document that is not in the full-text index yet, or every document when
+/// `all` is set.  Returns the number of documents indexed.
+pub async fn reindex(all: bool, pool: &SqlitePool) -> anyhow::Result<usize> {
+    let indexed: Vec<u32> = sqlx::query_scalar(r#"SELECT rowid FROM document_text"#)
+        .fetch_all(pool)
+        .await?;
+    let mut count = 0;
+    let mut conn = pool.acquire().await?;
+    for doc in DocList::get_all(pool).await?.iter() {
+        if !all && indexed.contains(&doc.id) {
+            continue;
+        }
+        if !doc.is_stored() {
+            log::warn!("Skipping document {}: stored file is missing", doc.id);
+            continue;
+        }
+        index_document(doc.id, &doc.stored_path()?, &mut conn).await?;
+        count += 1;
+    }
+    return Ok(count);
+}
+
+#[derive(FromRow, Debug)]
+struct SearchRow {
+    id: u32,
+    snippet: String,
+    rank: f64,
+}
+
+/// A document matching a full-text search.
+#[derive(Debug)]
+pub struct SearchHit {
+    pub doc: DatabaseDoc,
+    /// Excerpt of the matching text with the matched terms highlighted.
+    pub snippet: String,
+    /// BM25 relevance; lower is a better match.
+    pub rank: f64,
+}
+
+impl std::fmt::Display for SearchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.doc.summary())?;
+        return writeln!(
+            f,
+            "       {}",
+            self.snippet
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" ")
+        );
+    }
+}
+
+/// Search the full-text index, best matches first.  Unless `raw` is set, `phrase` is matched
+/// as a literal phrase instead of being interpreted as an FTS5 query.
+pub async fn search(
+    phrase: &str,
+    raw: bool,
+    limit: u32,
+    pool: &SqlitePool,
+) -> anyhow::Result<Vec<SearchHit>> {
+    let query = if raw {
+        phrase.to_string()
+    } else {
+        format!("\"{}\"", phrase.replace('"', "\"\""))
+    };
+    let (open, close) = if std::io::stdout().is_terminal() {
+        ("\x1b[1m", "\x1b[0m")
+    } else {
+        ("**", "**")
+    };
+    let rows = sqlx::query_as::<_, SearchRow>(
+        r#"
+        SELECT
+            rowid AS id,
+            snippet(document_text, 0, ?2, ?3, '...', 16) AS snippet,
+            bm25(document_text) AS rank
+        FROM document_text
+        WHERE document_text MATCH ?1
+        AND rowid IN (SELECT id FROM documents WHERE deleted_at IS NULL)
+        ORDER BY rank
+        LIMIT ?4
+        "#,
+    )
+    .bind(&query)
+    .bind(open)
+    .bind(close)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| anyhow::anyhow!("Invalid search {:?}: {}", phrase, e))?;
+    let mut hits = Vec::new();
+    for row in rows.into_iter() {
+        if let Some(doc) = DatabaseDoc::from_id(row.id, pool).await? {
+            hits.push(SearchHit {
+                doc,
+                snippet: row.snippet,
+                rank: row.rank,
+            });
+        }
+    }
+    return Ok(hits);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{document, library};
+    use crate::document::DuplicatePolicy;
+
+    async fn indexed(name: &str, text: &str, pool: &SqlitePool) -> DatabaseDoc {
+        let doc = document(name);
+        std::fs::write(&doc.path, text).unwrap();
+        return DatabaseDoc::from_insert(doc, DuplicatePolicy::Reject, pool)
+            .await
+            .unwrap();
+    }
+
+    fn titles(hits: &[SearchHit]) -> Vec<&str> {
+        return hits.iter().map(|h| h.doc.title.as_str()).collect();
+    }
+
+    #[test]
+    fn strip_html_keeps_only_text() {
+        let html = r#"<html><head><style>p { color: red; }</style>
+<SCRIPT type="text/javascript">var cats = "<b>";</SCRIPT></head>
+<body><p class="x">Cats&nbsp;&amp; <a
+href="dogs.html">dogs</a> &lt;3 &quot;purr&quot;</p></body></html>"#;
+        let text = strip_html(html);
+        assert_eq!(
+            text.split_whitespace().collect::<Vec<&str>>(),
+            vec!["Cats", "&", "dogs", "<3", "\"purr\""]
+        );
+        assert!(!text.contains("color"));
+        assert!(!text.contains("var"));
+    }
+
+    #[tokio::test]
+    async fn search_matches_phrases_and_skips_trash() {
+        let pool = library("search").await;
+        indexed("search-fox", "The quick brown fox jumps.", &pool).await;
+        indexed("search-dog", "A brown dog, quick to bark.", &pool).await;
+        let quote = indexed("search-quote", r#"The cat said "meow" twice."#, &pool).await;
+
+        let hits = search("quick brown", false, 10, &pool).await.unwrap();
+        assert_eq!(titles(&hits), vec!["Test search-fox"]);
+        assert!(hits[0].snippet.contains("**quick brown**"));
+        let hits = search("quick AND brown", true, 10, &pool).await.unwrap();
+        assert_eq!(hits.len(), 2);
+        let hits = search("quick AND brown", false, 10, &pool).await.unwrap();
+        assert!(hits.is_empty());
+        let hits = search("quick AND brown", true, 1, &pool).await.unwrap();
+        assert_eq!(hits.len(), 1);
+
+        // Quotes in a phrase are literal, not the end of the FTS5 string
+        let hits = search(r#"said "meow""#, false, 10, &pool).await.unwrap();
+        assert_eq!(titles(&hits), vec!["Test search-quote"]);
+        let error = search(r#"said "meow"#, true, 10, &pool).await.unwrap_err();
+        assert!(error.to_string().starts_with("Invalid search"));
+
+        quote.trash(&pool).await.unwrap();
+        let hits = search("meow", false, 10, &pool).await.unwrap();
+        assert!(hits.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reindex_skips_indexed_documents_unless_all() {
+        let pool = library("reindex").await;
+        let first = indexed("reindex-first", "First text.", &pool).await;
+        indexed("reindex-second", "Second text.", &pool).await;
+        assert_eq!(reindex(false, &pool).await.unwrap(), 0);
+
+        sqlx::query("DELETE FROM document_text WHERE rowid=?1")
+            .bind(first.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(search("first", false, 10, &pool).await.unwrap().is_empty());
+        assert_eq!(reindex(false, &pool).await.unwrap(), 1);
+        let hits = search("first", false, 10, &pool).await.unwrap();
+        assert_eq!(titles(&hits), vec!["Test reindex-first"]);
+
+        assert_eq!(reindex(true, &pool).await.unwrap(), 2);
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM document_text")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, 2);
+    }
+}
@@ -0,0 +1,1552 @@
+use crate::attachment::{Attachment, AttachmentFile};
+use crate::config::Config;
+use crate::doctype::{DocType, ExtraFields};
+use crate::document::{
+    commit_with_files, content_hash, normalized_key, DatabaseDoc, DocList, Document,
+    DuplicatePolicy, TomlDocuments, DOC_SELECT,
+};
+use crate::filetype::FileType;
+use crate::pdfmeta::PdfMetadata;
+use crate::tag::DatabaseTag;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// File format of a library export.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum LibraryFormat {
+    Toml,
+    Json,
+}
+
+impl LibraryFormat {
+    /// Format of a `doc add from-toml` file, from its extension.
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        return match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Ok(Self::Toml),
+            Some("json") => Ok(Self::Json),
+            _ => Err(anyhow::anyhow!(
+                "Invalid document file {:?}: expected a .toml or .json file",
+                path
+            )),
+        };
+    }
+}
+
+/// Read a document specification file written by hand or by `library export`.  Relative
+/// paths are resolved against the directory of the file when a file exists there.
+pub fn read_documents(path: &Path) -> anyhow::Result<TomlDocuments> {
+    let format = LibraryFormat::from_path(path)?;
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Invalid document file {:?}: {}", path, e))?;
+    let mut docs: TomlDocuments = match format {
+        LibraryFormat::Toml => toml::from_str(&contents)?,
+        LibraryFormat::Json => serde_json::from_str(&contents)?,
+    };
+    let base = path.parent().unwrap_or(Path::new(""));
+    let resolve = |path: &mut PathBuf| {
+        let relative = base.join(&*path);
+        if path.is_relative() && relative.is_file() {
+            *path = relative;
+        }
+    };
+    for doc in docs.documents.iter_mut() {
+        resolve(&mut doc.path);
+        for attachment in doc.attachments.iter_mut() {
+            resolve(&mut attachment.path);
+        }
+    }
+    return Ok(docs);
+}
+
+/// Read the entries of a citation database such as a BibTeX file with `parse` and convert
+/// them to documents with `to_document`.  Document files are looked up in `files_dir`,
+/// which defaults to the directory of the database.
+pub fn read_entries<T>(
+    format: &str,
+    path: &Path,
+    files_dir: Option<PathBuf>,
+    parse: fn(&str) -> anyhow::Result<Vec<T>>,
+    to_document: fn(&T, &Path) -> anyhow::Result<Document>,
+) -> anyhow::Result<TomlDocuments> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Invalid {} file {:?}: {}", format, path, e))?;
+    let files_dir = match files_dir {
+        Some(dir) => dir,
+        None => path.parent().map(|p| p.to_path_buf()).unwrap_or_default(),
+    };
+    let documents = parse(&contents)
+        .map_err(|e| anyhow::anyhow!("Invalid {} file {:?}: {}", format, path, e))?
+        .iter()
+        .map(|entry| to_document(entry, &files_dir))
+        .collect::<anyhow::Result<Vec<Document>>>()?;
+    return Ok(TomlDocuments { documents });
+}
+
+/// Write every document of the library and its attachments in the format
+/// `doc add from-toml` reads.  Paths point at the stored files, or with `bundle` at copies
+/// under their original filenames in a `<output>_files` directory next to `output`.  Nothing
+/// is written if a stored file is missing.
+pub async fn export(
+    format: LibraryFormat,
+    output: Option<&Path>,
+    bundle: bool,
+    pool: &SqlitePool,
+) -> anyhow::Result<usize> {
+    let bundle_dir = match (bundle, output) {
+        (true, Some(output)) => Some(bundle_dir(output)),
+        (true, None) => return Err(anyhow::anyhow!("Bundling files requires --output")),
+        (false, _) => None,
+    };
+    let mut entries = Vec::new();
+    for doc in DocList::get_all(pool).await?.0.into_iter() {
+        let attachments = Attachment::for_doc(doc.id, pool).await?;
+        let mut stored = vec![doc.stored_file()];
+        for attachment in attachments.iter() {
+            stored.push(Config::get().stored_file(&attachment.stored_name()));
+        }
+        if let Some(missing) = stored.iter().find(|path| !path.is_file()) {
+            return Err(anyhow::anyhow!(
+                "Document {} is missing its stored file {:?}",
+                doc.id,
+                missing
+            ));
+        }
+        entries.push((doc, attachments));
+    }
+    if let Some(dir) = bundle_dir.as_ref() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut used_names = HashSet::new();
+    let mut documents = Vec::new();
+    for (doc, attachments) in entries.into_iter() {
+        let path = export_file(
+            &doc.stored_file(),
+            &doc.filename,
+            &doc.stored_name(),
+            bundle_dir.as_deref(),
+            &mut used_names,
+        )?;
+        let mut files = Vec::new();
+        for attachment in attachments.into_iter() {
+            let stored_name = attachment.stored_name();
+            files.push(AttachmentFile {
+                role: attachment.role,
+                path: export_file(
+                    &Config::get().stored_file(&stored_name),
+                    &attachment.filename,
+                    &stored_name,
+                    bundle_dir.as_deref(),
+                    &mut used_names,
+                )?,
+                filename: attachment.filename,
+            });
+        }
+        documents.push(Document {
+            id: None,
+            title: doc.title,
+            author: doc.author,
+            year: doc.year,
+            publication: doc.publication,
+            volume: doc.volume,
+            tags: doc.tags,
+            doi: doc.doi,
+            doc_type: Some(doc.doc_type),
+            extra: doc.extra,
+            filename: doc.filename,
+            attachments: files,
+            path,
+        });
+    }
+    let count = documents.len();
+    let docs = TomlDocuments { documents };
+    let exported = match format {
+        LibraryFormat::Toml => toml::to_string_pretty(&docs)?,
+        LibraryFormat::Json => serde_json::to_string_pretty(&docs)? + "\n",
+    };
+    match output {
+        Some(path) => {
+            std::fs::write(path, exported)?;
+            log::info!("Exported {} documents to {:?}", count, path);
+        }
+        None => print!("{}", exported),
+    }
+    return Ok(count);
+}
+
+/// Path of a stored file in an export: the stored file itself, or a copy in `bundle_dir`
+/// named after `filename`, or `fallback` when it is empty.
+fn export_file(
+    stored: &Path,
+    filename: &str,
+    fallback: &str,
+    bundle_dir: Option<&Path>,
+    used_names: &mut HashSet<String>,
+) -> anyhow::Result<PathBuf> {
+    let dir = match bundle_dir {
+        Some(dir) => dir,
+        None => return Ok(stored.to_path_buf()),
+    };
+    let name = unique_name(filename, fallback, used_names);
+    std::fs::copy(stored, dir.join(&name))
+        .map_err(|e| anyhow::anyhow!("Could not copy {:?} to {:?}: {}", stored, dir, e))?;
+    // Relative to the export so the two can be moved together
+    return Ok(PathBuf::from(dir.file_name().unwrap_or_default()).join(&name));
+}
+
+/// Directory bundled files are copied to: `library.toml` bundles into `library_files`.
+fn bundle_dir(output: &Path) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    return output.with_file_name(format!("{}_files", stem));
+}
+
+/// `filename`, or `fallback` when it is empty, made unique among `used` by numbering.
+fn unique_name(filename: &str, fallback: &str, used: &mut HashSet<String>) -> String {
+    let name = if filename.is_empty() {
+        fallback
+    } else {
+        filename
+    };
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut candidate = name.to_string();
+    let mut n = 1;
+    while !used.insert(candidate.to_lowercase()) {
+        n += 1;
+        candidate = format!("{}-{}{}", stem, n, ext);
+    }
+    return candidate;
+}
+
+/// Version of the backup archive layout written by `backup`.
+const BACKUP_VERSION: u32 = 1;
+/// Archive path of the manifest.
+const MANIFEST_NAME: &str = "manifest.json";
+/// Archive path of the database snapshot.
+const DATABASE_NAME: &str = "odinsource.db";
+/// Archive directory holding the contents of the store directory.
+const STORE_PREFIX: &str = "store/";
+
+/// Contents of a backup archive, written to it as `manifest.json`.
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    version: u32,
+    /// Seconds since the Unix epoch when the backup was taken.
+    created: u64,
+    /// Number of document records in the database snapshot.
+    documents: i64,
+    /// Every other file in the archive.
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ManifestFile {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+impl ManifestFile {
+    fn from_path(archive_path: String, path: &Path) -> anyhow::Result<Self> {
+        return Ok(Self {
+            path: archive_path,
+            size: std::fs::metadata(path)?.len(),
+            sha256: content_hash(path)?,
+        });
+    }
+}
+
+/// Write a zstd compressed tar archive of a consistent snapshot of the database, the stored
+/// files of its documents and attachments and a manifest of their hashes.  Fails if any of
+/// those files is missing.  Returns the number of documents in the snapshot.
+pub async fn backup(output: &Path, pool: &SqlitePool) -> anyhow::Result<i64> {
+    let config = Config::get();
+    if output.exists() {
+        return Err(anyhow::anyhow!("Backup {:?} already exists", output));
+    }
+    let snapshot = std::env::temp_dir().join(format!("odinsource-{}.db", Uuid::new_v4()));
+    let partial = partial_path(output);
+    let result = write_backup(output, &partial, &snapshot, config, pool).await;
+    let _ = std::fs::remove_file(&snapshot);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    return result;
+}
+
+async fn write_backup(
+    output: &Path,
+    partial: &Path,
+    snapshot: &Path,
+    config: &Config,
+    pool: &SqlitePool,
+) -> anyhow::Result<i64> {
+    // Unlike copying the file, VACUUM INTO sees a single transaction even while the
+    // library is in use
+    sqlx::query("VACUUM INTO ?")
+        .bind(snapshot.to_string_lossy().to_string())
+        .execute(pool)
+        .await?;
+    let options = SqliteConnectOptions::new()
+        .filename(snapshot)
+        .read_only(true);
+    let snapshot_pool = SqlitePool::connect_with(options).await?;
+    let documents: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM documents")
+        .fetch_one(&snapshot_pool)
+        .await?;
+    let referenced = referenced_files(&snapshot_pool).await;
+    snapshot_pool.close().await;
+    let referenced = referenced?;
+
+    // Only the files the snapshot refers to are archived, so documents added while the
+    // backup runs neither end up without a record nor make the restored library fail `check`
+    let mut files = vec![(DATABASE_NAME.to_string(), snapshot.to_path_buf())];
+    let mut missing = Vec::new();
+    for (owner, path) in referenced.into_iter() {
+        if !path.is_file() {
+            missing.push(format!("{} ({:?})", owner, path));
+            continue;
+        }
+        let relative = path.strip_prefix(&config.store_dir)?.to_string_lossy();
+        files.push((format!("{}{}", STORE_PREFIX, relative), path.clone()));
+    }
+    if !missing.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Stored files are missing, run `library check`:\n  {}",
+            missing.join("\n  ")
+        ));
+    }
+    let manifest = Manifest {
+        version: BACKUP_VERSION,
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+        documents,
+        files: files
+            .iter()
+            .map(|(name, path)| ManifestFile::from_path(name.clone(), path))
+            .collect::<anyhow::Result<Vec<ManifestFile>>>()?,
+    };
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+
+    let file = std::fs::File::create(partial)
+        .with_context(|| format!("Could not create backup {:?}", partial))?;
+    let mut archive = tar::Builder::new(zstd::Encoder::new(file, 0)?);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created);
+    archive.append_data(&mut header, MANIFEST_NAME, manifest_json.as_slice())?;
+    for (name, path) in files.iter() {
+        archive.append_path_with_name(path, name)?;
+    }
+    archive.into_inner()?.finish()?.sync_all()?;
+    std::fs::rename(partial, output)?;
+    log::info!(
+        "Backed up {} documents and {} stored files to {:?}",
+        documents,
+        files.len() - 1,
+        output
+    );
+    return Ok(documents);
+}
+
+/// Restore a `backup` archive into `target`, which must not exist or be an empty directory.
+/// The database is restored as `odinsource.db` and the stored files into `documents`.  The
+/// archive is unpacked next to `target` and only moved into place once every file in it
+/// matches the manifest.
+pub fn restore(archive: &Path, target: &Path) -> anyhow::Result<()> {
+    if target.exists() {
+        let empty = target.is_dir() && std::fs::read_dir(target)?.next().is_none();
+        if !empty {
+            return Err(anyhow::anyhow!(
+                "Restore target {:?} must be a new or empty directory",
+                target
+            ));
+        }
+    }
+    let staging = partial_path(target);
+    let result = unpack_backup(archive, &staging).and_then(|manifest| {
+        if target.exists() {
+            std::fs::remove_dir(target)?;
+        }
+        std::fs::rename(&staging, target)?;
+        return Ok(manifest);
+    });
+    let manifest = match result {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+    println!(
+        "Restored {} documents and {} stored files to {:?}",
+        manifest.documents,
+        manifest.files.len() - 1,
+        target
+    );
+    println!("db_path = {:?}", target.join(DATABASE_NAME));
+    println!("store_dir = {:?}", target.join("documents"));
+    return Ok(());
+}
+
+fn unpack_backup(archive: &Path, staging: &Path) -> anyhow::Result<Manifest> {
+    let file = std::fs::File::open(archive)
+        .with_context(|| format!("Could not read backup {:?}", archive))?;
+    let mut entries = tar::Archive::new(zstd::Decoder::new(file)?);
+    std::fs::create_dir_all(staging.join("documents"))?;
+    let mut manifest: Option<Manifest> = None;
+    let mut unpacked = HashMap::new();
+    for entry in entries.entries()? {
+        let mut entry = entry.with_context(|| format!("Damaged backup {:?}", archive))?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        if !entry.header().entry_type().is_file() {
+            return Err(anyhow::anyhow!("Unexpected entry in backup: {:?}", name));
+        }
+        if name == MANIFEST_NAME {
+            manifest = Some(serde_json::from_reader(&mut entry)?);
+            continue;
+        }
+        let dest = if name == DATABASE_NAME {
+            staging.join(DATABASE_NAME)
+        } else if let Some(relative) = name.strip_prefix(STORE_PREFIX) {
+            staging.join("documents").join(relative)
+        } else {
+            return Err(anyhow::anyhow!("Unexpected file in backup: {:?}", name));
+        };
+        let unpacked_ok = entry
+            .unpack_in(staging)
+            .with_context(|| format!("Damaged backup {:?}", archive))?;
+        if !unpacked_ok {
+            return Err(anyhow::anyhow!("Unsafe path in backup: {:?}", name));
+        }
+        let extracted = staging.join(&name);
+        if extracted != dest {
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(&extracted, &dest)?;
+        }
+        unpacked.insert(name, dest);
+    }
+    let _ = std::fs::remove_dir_all(staging.join(STORE_PREFIX));
+
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => return Err(anyhow::anyhow!("Backup {:?} has no manifest", archive)),
+    };
+    if manifest.version > BACKUP_VERSION {
+        return Err(anyhow::anyhow!(
+            "Backup {:?} was written by a newer version (format {})",
+            archive,
+            manifest.version
+        ));
+    }
+    let mut problems = Vec::new();
+    for file in manifest.files.iter() {
+        match unpacked.remove(&file.path) {
+            Some(path) => {
+                let actual = ManifestFile::from_path(file.path.clone(), &path)?;
+                if actual.size != file.size || actual.sha256 != file.sha256 {
+                    problems.push(format!("{} does not match its hash", file.path));
+                }
+            }
+            None => problems.push(format!("{} is missing", file.path)),
+        }
+    }
+    for name in unpacked.keys() {
+        problems.push(format!("{} is not in the manifest", name));
+    }
+    if !manifest.files.iter().any(|f| f.path == DATABASE_NAME) {
+        problems.push(format!("{} is missing", DATABASE_NAME));
+    }
+    if !problems.is_empty() {
+        problems.sort();
+        return Err(anyhow::anyhow!(
+            "Backup {:?} does not match its manifest:\n  {}",
+            archive,
+            problems.join("\n  ")
+        ));
+    }
+    return Ok(manifest);
+}
+
+/// The stored file of every document and attachment in the library, including those in
+/// the trash, with a description of its owner.
+async fn referenced_files(pool: &SqlitePool) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let config = Config::get();
+    let mut files = Vec::new();
+    let mut trashed_ids = HashSet::new();
+    let docs = sqlx::query_as::<_, DatabaseDoc>(&format!("{} ORDER BY documents.id", DOC_SELECT))
+        .fetch_all(pool)
+        .await?;
+    for doc in docs.into_iter() {
+        if doc.is_trashed() {
+            trashed_ids.insert(doc.id);
+        }
+        files.push((format!("document {}", doc.id), doc.stored_file()));
+    }
+    for attachment in Attachment::get_all(pool).await?.into_iter() {
+        let path = if trashed_ids.contains(&attachment.doc_id) {
+            config.trashed_file(&attachment.stored_name())
+        } else {
+            config.stored_file(&attachment.stored_name())
+        };
+        files.push((format!("attachment {}", attachment.id), path));
+    }
+    return Ok(files);
+}
+
+/// Whether `path` is the library database or one of its journal files.
+fn is_database_file(path: &Path, config: &Config) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let db_name = config
+        .db_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    return path.parent() == config.db_path.parent() && name.starts_with(db_name.as_ref());
+}
+
+/// Sibling of `path` that work in progress is written to before it is moved into place.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    return path.with_file_name(format!(".{}.partial", name));
+}
+
+/// An inconsistency between the database and the store found by `check`.
+#[derive(Debug)]
+pub enum Problem {
+    /// A file in the store that no document or attachment refers to.
+    OrphanFile(PathBuf),
+    /// The stored file of a document or attachment does not exist.
+    MissingFile { owner: String, path: PathBuf },
+    /// The stored file no longer has the content hash recorded when it was added.
+    HashMismatch { owner: String, path: PathBuf },
+    /// A tag that no document uses.
+    UnusedTag(String),
+    /// A tag id linked to documents that has no row in the tags table.
+    MissingTag { id: u32, documents: i64 },
+    /// A tag link of a document that no longer exists.
+    DanglingLink { doc_id: u32, tag_id: u32 },
+}
+
+impl Problem {
+    /// The problem as a single line without column alignment.
+    fn description(&self) -> String {
+        return self
+            .to_string()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Self::OrphanFile(path) => write!(f, "{:16} {:?}", "orphan file:", path),
+            Self::MissingFile { owner, path } => {
+                write!(f, "{:16} {} ({:?})", "missing file:", owner, path)
+            }
+            Self::HashMismatch { owner, path } => {
+                write!(f, "{:16} {} ({:?})", "hash mismatch:", owner, path)
+            }
+            Self::UnusedTag(value) => write!(f, "{:16} {:?}", "unused tag:", value),
+            Self::MissingTag { id, documents } => write!(
+                f,
+                "{:16} id {} used by {} documents",
+                "missing tag:", id, documents
+            ),
+            Self::DanglingLink { doc_id, tag_id } => write!(
+                f,
+                "{:16} tag {} of missing document {}",
+                "dangling link:", tag_id, doc_id
+            ),
+        };
+    }
+}
+
+/// Result of `check`: every problem found, and what was done about the ones `--repair`
+/// could fix.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub problems: Vec<Problem>,
+    pub repairs: Vec<String>,
+    /// Repairs that were attempted and failed, with the reason.
+    pub failed_repairs: Vec<String>,
+    /// Problems left after repairing.
+    pub remaining: usize,
+    /// Ids of the documents added for orphan files, which only have the details found in
+    /// the file itself.
+    pub added: Vec<u32>,
+}
+
+impl std::fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for problem in self.problems.iter() {
+            writeln!(f, "{}", problem)?;
+        }
+        for repair in self.repairs.iter() {
+            writeln!(f, "{:16} {}", "repaired:", repair)?;
+        }
+        for failure in self.failed_repairs.iter() {
+            writeln!(f, "{:16} {}", "not repaired:", failure)?;
+        }
+        if !self.added.is_empty() {
+            let ids = self
+                .added
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>();
+            writeln!(
+                f,
+                "{:16} {}; fill in their details with `doc modify by-id <id>`",
+                "new documents:",
+                ids.join(", ")
+            )?;
+        }
+        return match (self.problems.len(), self.remaining) {
+            (0, _) => writeln!(f, "No problems found."),
+            (found, 0) if !self.repairs.is_empty() => {
+                writeln!(f, "{} problems found, all repaired.", found)
+            }
+            (found, remaining) if !self.repairs.is_empty() => writeln!(
+                f,
+                "{} problems found, {} could not be repaired.",
+                found, remaining
+            ),
+            (found, _) => writeln!(f, "{} problems found.", found),
+        };
+    }
+}
+
+/// Audit the library: stored files without a record, records without a stored file, stored
+/// files that do not match their hash, unused tags and broken tag links.  With `repair`,
+/// orphan files are added as documents, unused tags and dangling links are removed, and
+/// tags that documents link to but that do not exist are recreated as `missing-tag-<id>`.
+/// Missing files and hash mismatches are only reported.  The files of documents in the
+/// trash are looked for in the trash directory, which is also searched for orphans.
+pub async fn check(repair: bool, pool: &SqlitePool) -> anyhow::Result<CheckReport> {
+    let config = Config::get();
+    let mut report = CheckReport::default();
+    let mut known = HashSet::new();
+
+    let trashed = DocList::trashed(pool).await?;
+    let trashed_ids = trashed.iter().map(|doc| doc.id).collect::<HashSet<u32>>();
+    for doc in DocList::get_all(pool).await?.0.into_iter().chain(trashed.0) {
+        let owner = format!("document {} {:?}", doc.id, doc.title);
+        let path = doc.stored_file();
+        known.insert(path.clone());
+        check_stored_file(owner, path, &doc.content_hash, &mut report.problems)?;
+    }
+    for attachment in Attachment::get_all(pool).await?.into_iter() {
+        let owner = format!(
+            "attachment {} of document {}",
+            attachment.id, attachment.doc_id
+        );
+        // The files of documents in the trash are kept in the trash directory
+        let path = if trashed_ids.contains(&attachment.doc_id) {
+            config.trashed_file(&attachment.stored_name())
+        } else {
+            config.stored_file(&attachment.stored_name())
+        };
+        known.insert(path.clone());
+        check_stored_file(owner, path, &attachment.content_hash, &mut report.problems)?;
+    }
+    // Files can also be left behind in the trash directory
+    let mut dirs = vec![config.store_dir.clone()];
+    if config.trash_dir().is_dir() {
+        dirs.push(config.trash_dir());
+    }
+    for dir in dirs.iter() {
+        for path in std::fs::read_dir(dir)? {
+            let path = path?.path();
+            let hidden = path
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+            if path.is_file()
+                && !hidden
+                && !known.contains(&path)
+                && !is_database_file(&path, config)
+            {
+                report.problems.push(Problem::OrphanFile(path));
+            }
+        }
+    }
+
+    for tag in sqlx::query_as::<_, DatabaseTag>(
+        r#"
+        SELECT * FROM tags
+        WHERE id NOT IN (SELECT tag_id FROM document_tags)
+        ORDER BY value
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    {
+        report.problems.push(Problem::UnusedTag(tag.value));
+    }
+    for (id, documents) in sqlx::query_as::<_, (u32, i64)>(
+        r#"
+        SELECT tag_id, COUNT(*) FROM document_tags
+        WHERE tag_id NOT IN (SELECT id FROM tags)
+        AND doc_id IN (SELECT id FROM documents)
+        GROUP BY tag_id
+        ORDER BY tag_id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    {
+        report.problems.push(Problem::MissingTag { id, documents });
+    }
+    for (doc_id, tag_id) in sqlx::query_as::<_, (u32, u32)>(
+        r#"
+        SELECT doc_id, tag_id FROM document_tags
+        WHERE doc_id NOT IN (SELECT id FROM documents)
+        ORDER BY doc_id, tag_id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    {
+        report
+            .problems
+            .push(Problem::DanglingLink { doc_id, tag_id });
+    }
+
+    report.remaining = report.problems.len();
+    if repair {
+        for problem in report.problems.iter() {
+            match repair_problem(problem, &mut report.added, pool).await {
+                Ok(Some(repair)) => {
+                    report.repairs.push(repair);
+                    report.remaining -= 1;
+                }
+                Ok(None) => {}
+                Err(e) => report
+                    .failed_repairs
+                    .push(format!("{}: {}", problem.description(), e)),
+            }
+        }
+    }
+    return Ok(report);
+}
+
+fn check_stored_file(
+    owner: String,
+    path: PathBuf,
+    hash: &str,
+    problems: &mut Vec<Problem>,
+) -> anyhow::Result<()> {
+    if !path.is_file() {
+        problems.push(Problem::MissingFile { owner, path });
+    } else if !hash.is_empty() && content_hash(&path)? != hash {
+        problems.push(Problem::HashMismatch { owner, path });
+    }
+    return Ok(());
+}
+
+/// Fix `problem` if that can be done without losing information.  Returns a description of
+/// the repair, or `None` for problems that need a person to look at them.
+async fn repair_problem(
+    problem: &Problem,
+    added: &mut Vec<u32>,
+    pool: &SqlitePool,
+) -> anyhow::Result<Option<String>> {
+    return match problem {
+        Problem::OrphanFile(path) => {
+            let doc = register_orphan(path, pool).await?;
+            added.push(doc.id);
+            Ok(Some(format!(
+                "added {:?} as document {}: {:?}",
+                path, doc.id, doc.title
+            )))
+        }
+        Problem::UnusedTag(value) => match DatabaseTag::from_value(value, pool).await? {
+            Some(tag) => {
+                tag.delete(pool).await?;
+                Ok(Some(format!("removed unused tag {:?}", value)))
+            }
+            None => Ok(None),
+        },
+        Problem::MissingTag { id, .. } => {
+            let value = format!("missing-tag-{}", id);
+            sqlx::query(
+                r#"
+                INSERT INTO tags (id, value)
+                VALUES (?1, ?2)
+                "#,
+            )
+            .bind(id)
+            .bind(&value)
+            .execute(pool)
+            .await?;
+            Ok(Some(format!(
+                "recreated tag {} as {:?}; rename it with `tag modify`",
+                id, value
+            )))
+        }
+        Problem::DanglingLink { doc_id, tag_id } => {
+            sqlx::query(
+                r#"
+                DELETE FROM document_tags
+                WHERE doc_id=?1 AND tag_id=?2
+                "#,
+            )
+            .bind(doc_id)
+            .bind(tag_id)
+            .execute(pool)
+            .await?;
+            Ok(Some(format!(
+                "removed tag {} link of missing document {}",
+                tag_id, doc_id
+            )))
+        }
+        Problem::MissingFile { .. } | Problem::HashMismatch { .. } => Ok(None),
+    };
+}
+
+/// Add a document for an orphan file in the store, titled from its PDF metadata or else
+/// `missing title <file name>`.  The file is stored again under a new name and the orphan removed.
+async fn register_orphan(path: &Path, pool: &SqlitePool) -> anyhow::Result<DatabaseDoc> {
+    let filename = path
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let meta = match FileType::from_path(path)? {
+        FileType::Pdf => PdfMetadata::extract(path).unwrap_or_default(),
+        _ => PdfMetadata::default(),
+    };
+    // An existing title would make the insert return that document instead.  Stored files
+    // are named by their UUID, which makes a poor title, so say what is missing instead
+    let title = match meta.title {
+        Some(title) if DatabaseDoc::from_title(&title, pool).await?.is_none() => title,
+        _ => format!("missing title {}", filename),
+    };
+    let doc = Document {
+        id: None,
+        title,
+        author: meta.author.unwrap_or_default(),
+        year: meta.year.unwrap_or(0),
+        publication: meta.publication.unwrap_or_default(),
+        volume: meta.volume.unwrap_or(0),
+        tags: String::new(),
+        doi: meta.doi.unwrap_or_default(),
+        doc_type: None,
+        extra: ExtraFields::default(),
+        filename: String::new(),
+        attachments: Vec::new(),
+        path: path.to_path_buf(),
+    };
+    let hash = content_hash(path)?;
+    let dbd = DatabaseDoc::from_insert(doc, DuplicatePolicy::Reject, pool).await?;
+    if dbd.content_hash != hash {
+        return Err(anyhow::anyhow!("{:?} was not stored", path));
+    }
+    std::fs::remove_file(path)?;
+    return Ok(dbd);
+}
+
+/// What happened to one entry of a batch import.
+#[derive(Debug)]
+pub enum ImportStatus {
+    /// Added as the document with this id.
+    Added(u32),
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct ImportEntry {
+    pub title: String,
+    pub status: ImportStatus,
+}
+
+/// Outcome of every entry of a batch import, printed as a table.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub entries: Vec<ImportEntry>,
+}
+
+impl ImportReport {
+    fn push(&mut self, title: &str, status: ImportStatus) {
+        self.entries.push(ImportEntry {
+            title: title.to_string(),
+            status,
+        });
+    }
+
+    pub fn failed(&self) -> usize {
+        return self
+            .entries
+            .iter()
+            .filter(|e| matches!(e.status, ImportStatus::Failed(_)))
+            .count();
+    }
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:>5}  {:8} {:40} detail", "#", "status", "title")?;
+        let (mut added, mut skipped) = (0, 0);
+        for (n, entry) in self.entries.iter().enumerate() {
+            let title = if entry.title.chars().count() > 40 {
+                format!("{}...", entry.title.chars().take(37).collect::<String>())
+            } else {
+                entry.title.clone()
+            };
+            let (status, detail) = match &entry.status {
+                ImportStatus::Added(id) => {
+                    added += 1;
+                    ("added", format!("document {}", id))
+                }
+                ImportStatus::Skipped(reason) => {
+                    skipped += 1;
+                    ("skipped", reason.clone())
+                }
+                ImportStatus::Failed(reason) => ("failed", reason.clone()),
+            };
+            writeln!(f, "{:>5}  {:8} {:40} {}", n + 1, status, title, detail)?;
+        }
+        return writeln!(
+            f,
+            "{} added, {} skipped, {} failed",
+            added,
+            skipped,
+            self.failed()
+        );
+    }
+}
+
+/// Add each document that can be added, each in its own transaction, and report the ones
+/// that were already in the library or could not be added.
+pub async fn import_each(
+    docs: TomlDocuments,
+    on_duplicate: DuplicatePolicy,
+    pool: &SqlitePool,
+) -> anyhow::Result<ImportReport> {
+    let mut report = ImportReport::default();
+    for doc in docs.documents.into_iter() {
+        let title = doc.title.clone();
+        let status = match import_one(doc, on_duplicate, pool).await {
+            Ok(status) => status,
+            Err(e) => ImportStatus::Failed(e.to_string()),
+        };
+        report.push(&title, status);
+    }
+    return Ok(report);
+}
+
+async fn import_one(
+    doc: Document,
+    on_duplicate: DuplicatePolicy,
+    pool: &SqlitePool,
+) -> anyhow::Result<ImportStatus> {
+    let title_key = normalized_key(&doc.title);
+    let mut tx = pool.begin().await?;
+    let (dbd, staged) = DatabaseDoc::insert_staged(doc, on_duplicate, &mut tx).await?;
+    let status = if !staged.is_empty() {
+        ImportStatus::Added(dbd.id)
+    } else if normalized_key(&dbd.title) == title_key {
+        ImportStatus::Skipped(format!("already in the library as document {}", dbd.id))
+    } else {
+        ImportStatus::Skipped(format!("same contents; tags added to document {}", dbd.id))
+    };
+    commit_with_files(tx, staged, "import:commit", pool).await?;
+    return Ok(status);
+}
+
+/// Check every document first, then add all of them in one transaction.  Nothing is added
+/// if any document fails its checks: a missing or invalid file, missing required fields, or
+/// a title or file that is already in the library or the batch.
+pub async fn import_atomic(
+    docs: TomlDocuments,
+    on_duplicate: DuplicatePolicy,
+    pool: &SqlitePool,
+) -> anyhow::Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut titles = HashMap::new();
+    let mut hashes = HashMap::new();
+    for (n, doc) in docs.documents.iter().enumerate() {
+        let status =
+            match check_entry(doc, n + 1, on_duplicate, &mut titles, &mut hashes, pool).await {
+                Ok(()) => ImportStatus::Skipped("checked".to_string()),
+                Err(e) => ImportStatus::Failed(e.to_string()),
+            };
+        report.push(&doc.title, status);
+    }
+    if report.failed() > 0 {
+        for entry in report.entries.iter_mut() {
+            if !matches!(entry.status, ImportStatus::Failed(_)) {
+                entry.status = ImportStatus::Skipped("not added: other entries failed".into());
+            }
+        }
+        return Ok(report);
+    }
+
+    let mut tx = pool.begin().await?;
+    let mut staged_files = Vec::new();
+    let mut report = ImportReport::default();
+    for (n, doc) in docs.documents.into_iter().enumerate() {
+        let title = doc.title.clone();
+        let (dbd, staged) = DatabaseDoc::insert_staged(doc, on_duplicate, &mut tx)
+            .await
+            .with_context(|| format!("Entry {} {:?}; no documents were added", n + 1, title))?;
+        let status = if staged.is_empty() {
+            ImportStatus::Skipped(format!("same contents; tags added to document {}", dbd.id))
+        } else {
+            ImportStatus::Added(dbd.id)
+        };
+        staged_files.extend(staged);
+        report.push(&title, status);
+    }
+    commit_with_files(tx, staged_files, "import:commit", pool).await?;
+    return Ok(report);
+}
+
+/// Check that entry `n` of a batch can be added.  `titles` and `hashes` map the title keys
+/// and content hashes of the entries checked so far to their entry numbers.
+async fn check_entry(
+    doc: &Document,
+    n: usize,
+    on_duplicate: DuplicatePolicy,
+    titles: &mut HashMap<String, usize>,
+    hashes: &mut HashMap<String, usize>,
+    pool: &SqlitePool,
+) -> anyhow::Result<()> {
+    FileType::from_path(&doc.path)?;
+    for attachment in doc.attachments.iter() {
+        if !attachment.path.is_file() {
+            return Err(anyhow::anyhow!(
+                "attachment {:?} is not a file",
+                attachment.path
+            ));
+        }
+    }
+    doc.doc_type
+        .unwrap_or_else(|| DocType::infer(&doc.publication, &doc.extra))
+        .validate(&doc.publication, &doc.extra)?;
+    if let Some(first) = titles.insert(normalized_key(&doc.title), n) {
+        return Err(anyhow::anyhow!("same title as entry {}", first));
+    }
+    if let Some(dbd) = DatabaseDoc::from_title(&doc.title, pool).await? {
+        if dbd.is_trashed() {
+            return Err(anyhow::anyhow!("in the trash as document {}", dbd.id));
+        }
+        return Err(anyhow::anyhow!(
+            "already in the library as document {}",
+            dbd.id
+        ));
+    }
+    let hash = content_hash(&doc.path)?;
+    if let Some(first) = hashes.insert(hash.clone(), n) {
+        return Err(anyhow::anyhow!("same contents as entry {}", first));
+    }
+    if let Some(dbd) = DatabaseDoc::from_hash(&hash, pool).await? {
+        if dbd.is_trashed() {
+            return Err(anyhow::anyhow!(
+                "same contents as document {} in the trash",
+                dbd.id
+            ));
+        }
+        if on_duplicate == DuplicatePolicy::Reject {
+            return Err(anyhow::anyhow!("same contents as document {}", dbd.id));
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment::AttachmentRole;
+    use crate::document::tests::{
+        count, document, library, stored_copies, stored_document, test_path, TABLES,
+    };
+    use crate::failpoint;
+
+    /// Export a library with one document and attachment, then add the export to an empty
+    /// library.
+    async fn export_round_trip(name: &str, format: LibraryFormat, bundle: bool) {
+        let pool = library(name).await;
+        let (dbd, attachment) = stored_document(name, &pool).await;
+        let ext = match format {
+            LibraryFormat::Toml => "toml",
+            LibraryFormat::Json => "json",
+        };
+        let output = test_path(&format!("{}-export", name), ext);
+        assert_eq!(
+            export(format, Some(&output), bundle, &pool).await.unwrap(),
+            1
+        );
+
+        let copy = library(&format!("{}-copy", name)).await;
+        read_documents(&output)
+            .unwrap()
+            .add_to_db(DuplicatePolicy::Reject, &copy)
+            .await
+            .unwrap();
+        let docs = DocList::get_all(&copy).await.unwrap();
+        assert_eq!(docs.len(), 1);
+        let imported = &docs.0[0];
+        assert_eq!(imported.title, dbd.title);
+        assert_eq!(imported.author, dbd.author);
+        assert_eq!(imported.tags, dbd.tags);
+        assert_eq!(imported.doc_type, dbd.doc_type);
+        assert_eq!(imported.extra, dbd.extra);
+        assert_eq!(imported.filename, dbd.filename);
+        assert_eq!(imported.content_hash, dbd.content_hash);
+        assert!(imported.is_stored());
+        let attachments = Attachment::for_doc(imported.id, &copy).await.unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].role, AttachmentRole::Slides);
+        assert_eq!(attachments[0].filename, attachment.filename);
+        assert_eq!(attachments[0].content_hash, attachment.content_hash);
+        assert!(attachments[0].stored_path().is_ok());
+
+        std::fs::remove_file(&output).unwrap();
+        if bundle {
+            std::fs::remove_dir_all(bundle_dir(&output)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn toml_export_round_trips_into_empty_library() {
+        export_round_trip("export-toml", LibraryFormat::Toml, true).await;
+    }
+
+    #[tokio::test]
+    async fn json_export_round_trips_into_empty_library() {
+        export_round_trip("export-json", LibraryFormat::Json, false).await;
+    }
+
+    #[tokio::test]
+    async fn export_fails_before_writing_when_a_file_is_missing() {
+        let pool = library("export-missing").await;
+        let (dbd, _) = stored_document("export-missing", &pool).await;
+        std::fs::remove_file(dbd.stored_file()).unwrap();
+        let output = test_path("export-missing-export", "toml");
+        let result = export(LibraryFormat::Toml, Some(&output), true, &pool).await;
+        let message = result.unwrap_err().to_string();
+        assert!(
+            message.contains(&format!("Document {} ", dbd.id)),
+            "{}",
+            message
+        );
+        assert!(!output.exists());
+        assert!(!bundle_dir(&output).exists());
+    }
+
+    /// Copy the backup archive at `src` to `dest`, replacing the contents of each file by
+    /// `edit` and leaving it out when `edit` returns `None`.
+    fn rewrite_backup(src: &Path, dest: &Path, edit: impl Fn(&str, Vec<u8>) -> Option<Vec<u8>>) {
+        let mut entries =
+            tar::Archive::new(zstd::Decoder::new(std::fs::File::open(src).unwrap()).unwrap());
+        let file = std::fs::File::create(dest).unwrap();
+        let mut archive = tar::Builder::new(zstd::Encoder::new(file, 0).unwrap());
+        for entry in entries.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().to_string();
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut contents).unwrap();
+            if let Some(contents) = edit(&name, contents) {
+                let mut header = entry.header().clone();
+                header.set_size(contents.len() as u64);
+                header.set_cksum();
+                archive
+                    .append_data(&mut header, &name, contents.as_slice())
+                    .unwrap();
+            }
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+    }
+
+    /// A backup of a library with one stored document and attachment.
+    async fn backup_of(name: &str) -> (PathBuf, DatabaseDoc, Attachment) {
+        let pool = library(name).await;
+        let (dbd, attachment) = stored_document(name, &pool).await;
+        let output = test_path(name, "tar.zst");
+        let _ = std::fs::remove_file(&output);
+        assert_eq!(backup(&output, &pool).await.unwrap(), 1);
+        return (output, dbd, attachment);
+    }
+
+    fn restore_target(name: &str) -> PathBuf {
+        let target = test_path(&format!("{}-restored", name), "d");
+        let _ = std::fs::remove_dir_all(&target);
+        return target;
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        return std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+    }
+
+    #[tokio::test]
+    async fn backup_restores_only_files_of_the_snapshot() {
+        let (output, dbd, attachment) = backup_of("backup").await;
+        let target = restore_target("backup");
+        restore(&output, &target).unwrap();
+
+        let options = SqliteConnectOptions::new().filename(target.join(DATABASE_NAME));
+        let restored = SqlitePool::connect_with(options).await.unwrap();
+        let docs = DocList::get_all(&restored).await.unwrap();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs.0[0].content_hash, dbd.content_hash);
+        restored.close().await;
+        // The other tests share the store, but only this library's files are in the backup
+        let mut stored = file_names(&target.join("documents"));
+        stored.sort();
+        let mut expected = vec![dbd.stored_name(), attachment.stored_name()];
+        expected.sort();
+        assert_eq!(stored, expected);
+        assert_eq!(
+            std::fs::read(target.join("documents").join(dbd.stored_name())).unwrap(),
+            std::fs::read(dbd.stored_file()).unwrap()
+        );
+
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn backup_fails_when_a_stored_file_is_missing() {
+        let pool = library("backup-missing").await;
+        let (_, attachment) = stored_document("backup-missing", &pool).await;
+        std::fs::remove_file(attachment.stored_path().unwrap()).unwrap();
+        let output = test_path("backup-missing", "tar.zst");
+        let message = backup(&output, &pool).await.unwrap_err().to_string();
+        assert!(
+            message.contains(&format!("attachment {}", attachment.id)),
+            "{}",
+            message
+        );
+        assert!(!output.exists());
+        assert!(!partial_path(&output).exists());
+    }
+
+    #[tokio::test]
+    async fn restore_refuses_file_that_does_not_match_manifest() {
+        let (output, dbd, _) = backup_of("backup-changed").await;
+        let changed = test_path("backup-changed-edit", "tar.zst");
+        let stored = format!("{}{}", STORE_PREFIX, dbd.stored_name());
+        rewrite_backup(&output, &changed, |name, contents| match name == stored {
+            true => Some(b"Changed contents".to_vec()),
+            false => Some(contents),
+        });
+        let target = restore_target("backup-changed");
+        let message = restore(&changed, &target).unwrap_err().to_string();
+        assert!(
+            message.contains(&format!("{} does not match its hash", stored)),
+            "{}",
+            message
+        );
+        assert!(!target.exists());
+        assert!(!partial_path(&target).exists());
+
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&changed).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_refuses_partial_archive() {
+        let (output, _, attachment) = backup_of("backup-partial").await;
+        let partial = test_path("backup-partial-edit", "tar.zst");
+        let stored = format!("{}{}", STORE_PREFIX, attachment.stored_name());
+        rewrite_backup(&output, &partial, |name, contents| match name == stored {
+            true => None,
+            false => Some(contents),
+        });
+        let target = restore_target("backup-partial");
+        let message = restore(&partial, &target).unwrap_err().to_string();
+        assert!(
+            message.contains(&format!("{} is missing", stored)),
+            "{}",
+            message
+        );
+        assert!(!target.exists());
+
+        // An archive cut off part way through is refused as well
+        let contents = std::fs::read(&output).unwrap();
+        std::fs::write(&partial, &contents[..contents.len() / 2]).unwrap();
+        assert!(restore(&partial, &target).is_err());
+        assert!(!target.exists());
+        assert!(!partial_path(&target).exists());
+
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&partial).unwrap();
+    }
+
+    /// A file in the store that no library refers to.
+    fn orphan_file(name: &str) -> PathBuf {
+        let path = Config::get().stored_file(&format!("{}.md", Uuid::new_v4()));
+        std::fs::write(&path, format!("# Orphan\n\nLeft behind by {}.\n", name)).unwrap();
+        return path;
+    }
+
+    // The tests share the store, so `check` is only run without repairing, which would add
+    // the files of every other test as orphans
+
+    #[tokio::test]
+    async fn check_reports_problems_without_changing_library() {
+        let pool = library("check").await;
+        let (dbd, attachment) = stored_document("check", &pool).await;
+        let (changed, _) = stored_document("check-changed", &pool).await;
+        std::fs::remove_file(attachment.stored_path().unwrap()).unwrap();
+        std::fs::write(changed.stored_file(), "Changed contents").unwrap();
+        sqlx::query("INSERT INTO tags (value) VALUES ('check-unused')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let orphan = orphan_file("check");
+        std::fs::create_dir_all(Config::get().trash_dir()).unwrap();
+        let trashed_orphan = Config::get().trashed_file(&format!("{}.txt", Uuid::new_v4()));
+        std::fs::write(&trashed_orphan, "Left behind in the trash").unwrap();
+
+        let report = check(false, &pool).await.unwrap();
+        let found = |expected: &dyn Fn(&Problem) -> bool| report.problems.iter().any(expected);
+        assert!(found(
+            &|p| matches!(p, Problem::OrphanFile(path) if *path == orphan)
+        ));
+        assert!(found(
+            &|p| matches!(p, Problem::OrphanFile(path) if *path == trashed_orphan)
+        ));
+        assert!(found(&|p| matches!(
+            p,
+            Problem::MissingFile { owner, .. }
+                if *owner == format!("attachment {} of document {}", attachment.id, dbd.id)
+        )));
+        assert!(found(&|p| matches!(
+            p,
+            Problem::HashMismatch { path, .. } if *path == changed.stored_file()
+        )));
+        assert!(found(
+            &|p| matches!(p, Problem::UnusedTag(v) if v == "check-unused")
+        ));
+        assert!(!found(&|p| matches!(
+            p,
+            Problem::MissingFile { path, .. } if *path == dbd.stored_file()
+        )));
+        assert_eq!(report.remaining, report.problems.len());
+        assert!(report.repairs.is_empty());
+        assert!(orphan.exists());
+        assert_eq!(DocList::get_all(&pool).await.unwrap().len(), 2);
+
+        std::fs::remove_file(&orphan).unwrap();
+        std::fs::remove_file(&trashed_orphan).unwrap();
+    }
+
+    #[tokio::test]
+    async fn repairing_orphan_adds_document_with_placeholder_title() {
+        let pool = library("check-orphan").await;
+        let orphan = orphan_file("check-orphan");
+        let contents = std::fs::read(&orphan).unwrap();
+        let mut report = CheckReport::default();
+        let repair = repair_problem(
+            &Problem::OrphanFile(orphan.clone()),
+            &mut report.added,
+            &pool,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let docs = DocList::get_all(&pool).await.unwrap();
+        assert_eq!(docs.len(), 1);
+        let doc = &docs.0[0];
+        let filename = orphan.file_name().unwrap().to_string_lossy();
+        assert_eq!(doc.title, format!("missing title {}", filename));
+        assert_eq!(report.added, vec![doc.id]);
+        assert!(
+            repair.contains(&format!("document {}", doc.id)),
+            "{}",
+            repair
+        );
+        assert_eq!(std::fs::read(doc.stored_file()).unwrap(), contents);
+        assert!(!orphan.exists());
+
+        report.repairs.push(repair);
+        let shown = report.to_string();
+        assert!(
+            shown.contains(&format!("new documents:   {};", doc.id)),
+            "{}",
+            shown
+        );
+    }
+
+    #[tokio::test]
+    async fn repairing_unused_tag_removes_it() {
+        let pool = library("check-tag").await;
+        sqlx::query("INSERT INTO tags (value) VALUES ('check-unused')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let problem = Problem::UnusedTag("check-unused".to_string());
+        let mut added = Vec::new();
+        assert!(repair_problem(&problem, &mut added, &pool)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(DatabaseTag::from_value("check-unused", &pool)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(added.is_empty());
+    }
+
+    /// Two new documents, the first with an attachment.
+    fn import_batch(name: &str) -> TomlDocuments {
+        let mut first = document(&format!("{}-first", name));
+        let slides = test_path(&format!("{}-slides", name), "txt");
+        std::fs::write(&slides, format!("Slides of {}", name)).unwrap();
+        first.attachments.push(AttachmentFile {
+            role: AttachmentRole::Slides,
+            filename: String::new(),
+            path: slides,
+        });
+        return TomlDocuments {
+            documents: vec![first, document(&format!("{}-second", name))],
+        };
+    }
+
+    async fn import_atomic_fails_at(failpoint: &'static str) {
+        let name = format!("import-{}", failpoint);
+        let pool = library(&name).await;
+        let docs = import_batch(&name);
+        let mut paths = docs
+            .documents
+            .iter()
+            .map(|doc| doc.path.clone())
+            .collect::<Vec<PathBuf>>();
+        paths.push(docs.documents[0].attachments[0].path.clone());
+        failpoint::arm(failpoint);
+        let result = import_atomic(docs, DuplicatePolicy::Reject, &pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        for table in TABLES.iter() {
+            assert_eq!(count(table, &pool).await, 0, "rows left in {}", table);
+        }
+        for path in paths.iter() {
+            assert_eq!(stored_copies(path), Vec::<PathBuf>::new());
+        }
+    }
+
+    #[tokio::test]
+    async fn import_atomic_adds_every_document() {
+        let pool = library("import-atomic").await;
+        let report = import_atomic(
+            import_batch("import-atomic"),
+            DuplicatePolicy::Reject,
+            &pool,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.failed(), 0);
+        assert!(report
+            .entries
+            .iter()
+            .all(|e| matches!(e.status, ImportStatus::Added(_))));
+        assert_eq!(count("documents", &pool).await, 2);
+        assert_eq!(count("attachments", &pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn import_atomic_rolls_back_when_row_fails() {
+        import_atomic_fails_at("insert:row").await;
+    }
+
+    #[tokio::test]
+    async fn import_atomic_rolls_back_when_rename_fails() {
+        import_atomic_fails_at("store:persist").await;
+    }
+
+    #[tokio::test]
+    async fn import_atomic_rolls_back_when_commit_fails() {
+        import_atomic_fails_at("import:commit").await;
+    }
+
+    #[tokio::test]
+    async fn import_atomic_adds_nothing_when_an_entry_fails_its_checks() {
+        let pool = library("import-checks").await;
+        let mut docs = import_batch("import-checks");
+        let missing = document("import-checks-missing");
+        std::fs::remove_file(&missing.path).unwrap();
+        docs.documents.push(missing);
+        let report = import_atomic(docs, DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        assert_eq!(report.failed(), 1);
+        assert!(matches!(report.entries[2].status, ImportStatus::Failed(_)));
+        for table in TABLES.iter() {
+            assert_eq!(count(table, &pool).await, 0, "rows left in {}", table);
+        }
+    }
+
+    #[tokio::test]
+    async fn import_each_reports_every_entry() {
+        let pool = library("import-each").await;
+        let (existing, _) = stored_document("import-each-existing", &pool).await;
+        let added = document("import-each-added");
+        let mut same_title = document("import-each-title");
+        same_title.title = existing.title.clone();
+        let missing = document("import-each-missing");
+        std::fs::remove_file(&missing.path).unwrap();
+        let mut same_contents = document("import-each-contents");
+        same_contents.path = added.path.clone();
+        let docs = TomlDocuments {
+            documents: vec![added, same_title, missing, same_contents],
+        };
+
+        let report = import_each(docs, DuplicatePolicy::Reject, &pool)
+            .await
+            .unwrap();
+        let statuses = report
+            .entries
+            .iter()
+            .map(|e| &e.status)
+            .collect::<Vec<&ImportStatus>>();
+        let added_id = match statuses[0] {
+            ImportStatus::Added(id) => *id,
+            status => panic!("first entry was not added: {:?}", status),
+        };
+        assert!(
+            matches!(statuses[1], ImportStatus::Skipped(reason)
+                if *reason == format!("already in the library as document {}", existing.id)),
+            "{:?}",
+            statuses[1]
+        );
+        assert!(matches!(statuses[2], ImportStatus::Failed(_)));
+        assert!(
+            matches!(statuses[3], ImportStatus::Failed(reason)
+                if reason.contains(&format!("same contents as document {}", added_id))),
+            "{:?}",
+            statuses[3]
+        );
+        assert_eq!(report.failed(), 2);
+        assert!(report
+            .to_string()
+            .ends_with("1 added, 1 skipped, 2 failed\n"));
+        assert_eq!(count("documents", &pool).await, 2);
+    }
+}
@@ -1,43 +1,134 @@
+pub mod attachment;
+pub mod author;
+pub mod bibtex;
+pub mod cite;
 pub mod cli;
+pub mod config;
+pub mod csljson;
+pub mod doctype;
 pub mod document;
+pub mod dryrun;
+pub mod failpoint;
+pub mod filetype;
+pub mod filter;
+pub mod fulltext;
+pub mod library;
+pub mod migration;
+pub mod pdfmeta;
+pub mod query;
+pub mod ris;
+pub mod store;
 pub mod tag;
+pub mod trash;
 
+use attachment::Attachment;
+use author::DatabaseAuthor;
 use cli::*;
+use config::*;
 use document::*;
+use migration::*;
 use tag::*;
 
 //use serde::Deserialize;
 use clap::Parser;
-use sqlx::{migrate::MigrateDatabase, sqlite::SqliteQueryResult, Sqlite, SqlitePool};
-use std::convert::From;
-
-const DB_URL: &str = "sqlite://odinsource.db";
+use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
-    let db = setup().await?;
     let args = Cli::parse();
+    // Reporting migration status must not apply the migrations it reports on
+    let auto_migrate = !matches!(
+        args.entity_type,
+        EntityType::Db(DbCmd {
+            command: DbSubCmd::Migrate(MigrateDb { status: true })
+        })
+    );
+    if args.dry_run {
+        if !supports_dry_run(&args.entity_type) {
+            return Err(anyhow::anyhow!("This command does not support --dry-run"));
+        }
+        dryrun::enable();
+    }
+    let config = Config::init(Config::load(args.config.as_deref())?);
+    // Restoring writes a new library and must not create or migrate the configured one
+    if let EntityType::Library(LibraryCmd {
+        command: LibrarySubCmd::Restore(cmd),
+    }) = &args.entity_type
+    {
+        return library::restore(&cmd.archive, &cmd.target);
+    }
+    let db = setup(config, auto_migrate).await?;
     match args.entity_type {
+        EntityType::Db(cmd) => match cmd.command {
+            DbSubCmd::Migrate(cmd) => {
+                if cmd.status {
+                    print!("{}", MigrationStatus::from_db(&db).await?);
+                } else {
+                    print!("{}", migrate(&db).await?);
+                }
+            }
+        },
+        EntityType::Library(cmd) => match cmd.command {
+            LibrarySubCmd::Export(cmd) => {
+                library::export(cmd.format, cmd.output.as_deref(), cmd.bundle, &db).await?;
+            }
+            LibrarySubCmd::Backup(cmd) => {
+                library::backup(&cmd.output, &db).await?;
+            }
+            LibrarySubCmd::Check(cmd) => {
+                let report = library::check(cmd.repair, &db).await?;
+                print!("{}", report);
+                if report.remaining > 0 {
+                    return Err(anyhow::anyhow!(
+                        "Library check found {} problems",
+                        report.remaining
+                    ));
+                }
+            }
+            LibrarySubCmd::Restore(_) => unreachable!("restore runs before the library is opened"),
+        },
+        EntityType::Author(cmd) => match cmd.command {
+            AuthorSubCmd::List => {
+                print_authors(&db).await?;
+            }
+            AuthorSubCmd::Merge(cmd) => {
+                let into = match DatabaseAuthor::from_id(cmd.into, &db).await? {
+                    Some(author) => author,
+                    None => return Err(anyhow::anyhow!("Author {} does not exist", cmd.into)),
+                };
+                for id in cmd.ids.iter() {
+                    match DatabaseAuthor::from_id(*id, &db).await? {
+                        Some(author) => author.merge(&into, &db).await?,
+                        None => return Err(anyhow::anyhow!("Author {} does not exist", id)),
+                    }
+                }
+                print_authors(&db).await?;
+            }
+        },
         EntityType::Tag(cmd) => match cmd.command {
             TagSubCmd::Add(cmd) => {
                 Tag::new(&cmd.value).insert(&db).await?;
                 print_tags(&db).await?;
             }
             TagSubCmd::Modify(cmd) => {
-                let tag_values = match cmd.method {
+                let (db_tag, new_value) = match cmd.method {
                     ModifyTagSubCmd::ById(input) => {
-                        (Tag::from_id(input.id, &db).await?.value, input.new_value)
+                        (DatabaseTag::from_id(input.id, &db).await?, input.new_value)
                     }
-                    ModifyTagSubCmd::ByValue(input) => {
-                        (input.old_value, input.new_value)
+                    ModifyTagSubCmd::ByValue(input) => (
+                        DatabaseTag::from_value(&input.old_value.to_lowercase(), &db).await?,
+                        input.new_value,
+                    ),
+                };
+                match db_tag {
+                    Some(dbt) => {
+                        dbt.rename(&new_value, &db).await?;
                     }
+                    None => return Err(anyhow::anyhow!("Tag does not exist")),
                 };
-                DocList::get_all(&db)
-                    .await?
-                    .modify_tag(&tag_values.0, &tag_values.1, &db)
-                    .await?;
-            },
+                print_tags(&db).await?;
+            }
             TagSubCmd::Delete(cmd) => {
                 if let Some(name) = cmd.value {
                     Tag::new(&name).delete(&db).await?;
@@ -53,22 +144,78 @@ async fn main() -> anyhow::Result<()> {
         EntityType::Document(cmd) => match cmd.command {
             DocSubCmd::Add(cmd) => match cmd.source {
                 AddDocSubCmd::Single(doc) => {
-                    let doc: Document = doc.into();
-                    doc.insert(&db).await?;
-                    print_docs(&db).await?;
+                    let doc = doc.into_document()?;
+                    doc.insert(cmd.on_duplicate, &db).await?;
+                    print_docs(&db, config.list_format).await?;
                 }
                 AddDocSubCmd::FromToml(toml) => {
-                    if toml.path.is_file()
-                        && toml.path.extension() == Some(&std::ffi::OsStr::new("toml"))
-                    {
-                        let toml_str = std::fs::read_to_string(toml.path)?;
-                        let docs: TomlDocuments = toml::from_str(&toml_str)?;
-                        docs.add_to_db(&db).await?;
-                        print_docs(&db).await?;
+                    let docs = library::read_documents(&toml.path)?;
+                    if toml.atomic || toml.continue_on_error {
+                        let count = docs.documents.len();
+                        let report = if toml.atomic {
+                            library::import_atomic(docs, cmd.on_duplicate, &db).await?
+                        } else {
+                            library::import_each(docs, cmd.on_duplicate, &db).await?
+                        };
+                        print!("{}", report);
+                        match report.failed() {
+                            0 => {}
+                            failed if toml.atomic => {
+                                return Err(anyhow::anyhow!(
+                                    "{} of {} entries failed; no documents were added",
+                                    failed,
+                                    count
+                                ))
+                            }
+                            failed => {
+                                return Err(anyhow::anyhow!(
+                                    "{} of {} entries failed",
+                                    failed,
+                                    count
+                                ))
+                            }
+                        }
                     } else {
-                        return Err(anyhow::anyhow!("Invalid document file: {:?}", toml.path));
+                        docs.add_to_db(cmd.on_duplicate, &db).await?;
+                        print_docs(&db, config.list_format).await?;
                     }
                 }
+                AddDocSubCmd::FromBibtex(bib) => {
+                    library::read_entries(
+                        "BibTeX",
+                        &bib.path,
+                        bib.files_dir,
+                        bibtex::parse,
+                        bibtex::BibtexEntry::to_document,
+                    )?
+                    .add_to_db(cmd.on_duplicate, &db)
+                    .await?;
+                    print_docs(&db, config.list_format).await?;
+                }
+                AddDocSubCmd::FromRis(ris_path) => {
+                    library::read_entries(
+                        "RIS",
+                        &ris_path.path,
+                        ris_path.files_dir,
+                        ris::parse,
+                        ris::RisEntry::to_document,
+                    )?
+                    .add_to_db(cmd.on_duplicate, &db)
+                    .await?;
+                    print_docs(&db, config.list_format).await?;
+                }
+                AddDocSubCmd::FromCslJson(json_path) => {
+                    library::read_entries(
+                        "CSL-JSON",
+                        &json_path.path,
+                        json_path.files_dir,
+                        csljson::parse,
+                        csljson::CslItem::to_document,
+                    )?
+                    .add_to_db(cmd.on_duplicate, &db)
+                    .await?;
+                    print_docs(&db, config.list_format).await?;
+                }
             },
             DocSubCmd::Modify(cmd) => match cmd.method {
                 ModifyDocSubCmd::ById(input) => {
@@ -80,95 +227,207 @@ async fn main() -> anyhow::Result<()> {
             },
             DocSubCmd::Delete(cmd) => {
                 if let Some(id) = cmd.id {
-                    Document::from_id(id, &db).await?.delete(&db).await?;
+                    match DatabaseDoc::from_id(id, &db).await? {
+                        Some(dbd) => dbd.trash(&db).await?,
+                        None => {
+                            return Err(anyhow::anyhow!("Document with id {} does not exist", id))
+                        }
+                    }
                 } else if let Some(title) = cmd.title {
-                    Document::delete_from_title(&title, &db).await?;
+                    Document::trash_from_title(&title, &db).await?;
                 }
-                print_docs(&db).await?;
+                print_docs(&db, config.list_format).await?;
             }
             DocSubCmd::List(cmd) => {
-                if let Some(value) = cmd.tag {
-                    log::debug!("Search tag: {}", value);
-                    let doc_list = DocList::from_tag(&value, &db).await?;
-                    print_doc_list(doc_list).await?;
-                } else {
-                    print_docs(&db).await?;
+                let format = cmd.format.unwrap_or(config.list_format);
+                let doc_list = cmd.selection.resolve(&db).await?;
+                print_doc_list(doc_list, format).await?;
+            }
+            DocSubCmd::Export(cmd) => {
+                let doc_list = cmd.selection.resolve(&db).await?;
+                let exported = match cmd.format {
+                    ExportFormat::Bibtex => bibtex::to_bibtex(&doc_list, &db).await?,
+                    ExportFormat::Ris => ris::to_ris(&doc_list, &db).await?,
+                    ExportFormat::CslJson => csljson::to_csl_json(&doc_list, &db).await?,
                 };
+                match cmd.output {
+                    Some(path) => {
+                        std::fs::write(&path, exported)?;
+                        log::info!("Exported {} documents to {:?}", doc_list.len(), path);
+                    }
+                    None => print!("{}", exported),
+                }
+            }
+            DocSubCmd::Cite(cmd) => {
+                let style = cite::CitationStyle::load(&cmd.style)?;
+                for citation in cite::cite(&cmd.ids, &style, cmd.format, &db).await? {
+                    println!("{}", citation);
+                }
+            }
+            DocSubCmd::Search(cmd) => {
+                let hits = fulltext::search(&cmd.phrase, cmd.raw, cmd.limit, &db).await?;
+                let sep = "=".repeat(80);
+                println!("{}", sep);
+                println!("Search results: {}\n{}", hits.len(), sep);
+                for hit in hits.iter() {
+                    println!("{}", hit);
+                }
+                println!("{}", sep);
+            }
+            DocSubCmd::Reindex(cmd) => {
+                let count = fulltext::reindex(cmd.all, &db).await?;
+                println!("Indexed {} documents", count);
             }
             DocSubCmd::Open(cmd) => {
-                let doc = match cmd {
-                    OpenDoc { id: Some(id), .. } => Document::from_id(id, &db).await?,
-                    OpenDoc {
-                        title: Some(title), ..
-                    } => Document::from_title(&title, &db).await?,
-                    _ => Err(anyhow::anyhow!("Must provide ID or TITLE"))?,
+                let doc = cmd.doc.resolve(&db).await?;
+                let path = match cmd.attachment {
+                    Some(selector) => Attachment::select(&doc, &selector, &db)
+                        .await?
+                        .stored_path()?,
+                    None => doc.stored_path()?,
                 };
-                let path = doc.stored_path(&db).await?;
-                std::process::Command::new("xdg-open")
-                    .arg(path)
-                    .spawn()
-                    .expect("Failed to open document");
+                open_path(config, &path)?;
             }
+            DocSubCmd::Trash(cmd) => match cmd.command {
+                TrashSubCmd::List => {
+                    print_trash(&db).await?;
+                }
+                TrashSubCmd::Restore(cmd) => match DatabaseDoc::from_id(cmd.id, &db).await? {
+                    Some(dbd) => {
+                        dbd.restore(&db).await?;
+                        print_trash(&db).await?;
+                    }
+                    None => {
+                        return Err(anyhow::anyhow!(
+                            "Document with id {} does not exist",
+                            cmd.id
+                        ))
+                    }
+                },
+                TrashSubCmd::Empty(cmd) => {
+                    let count = trash::empty(cmd.older_than, &db).await?;
+                    if dryrun::enabled() {
+                        println!("Would delete {} documents from the trash", count);
+                    } else {
+                        println!("Deleted {} documents from the trash", count);
+                    }
+                }
+            },
+            DocSubCmd::Attach(cmd) => match cmd.command {
+                AttachSubCmd::Add(cmd) => {
+                    let doc = cmd.doc.resolve(&db).await?;
+                    Attachment::from_insert(&doc, &cmd.path, cmd.role, &db).await?;
+                    print_attachments(&doc, &db).await?;
+                }
+                AttachSubCmd::List(cmd) => {
+                    let doc = cmd.doc.resolve(&db).await?;
+                    print_attachments(&doc, &db).await?;
+                }
+                AttachSubCmd::Remove(cmd) => match Attachment::from_id(cmd.id, &db).await? {
+                    Some(attachment) => attachment.delete(&db).await?,
+                    None => return Err(anyhow::anyhow!("Attachment does not exist")),
+                },
+                AttachSubCmd::Open(cmd) => match Attachment::from_id(cmd.id, &db).await? {
+                    Some(attachment) => {
+                        attachment.ensure_not_trashed(&db).await?;
+                        open_path(config, &attachment.stored_path()?)?;
+                    }
+                    None => return Err(anyhow::anyhow!("Attachment does not exist")),
+                },
+            },
         },
     }
     return Ok(());
 }
 
-async fn setup() -> anyhow::Result<SqlitePool> {
+/// Whether a command can run with `--dry-run`: commands that only read the library, and the
+/// mutating commands that can report their changes instead of making them.
+fn supports_dry_run(command: &EntityType) -> bool {
+    return match command {
+        EntityType::Document(cmd) => !matches!(cmd.command, DocSubCmd::Reindex(_)),
+        EntityType::Tag(_) => true,
+        EntityType::Author(_) => true,
+        EntityType::Db(cmd) => matches!(cmd.command, DbSubCmd::Migrate(MigrateDb { status: true })),
+        EntityType::Library(cmd) => matches!(
+            cmd.command,
+            LibrarySubCmd::Export(_) | LibrarySubCmd::Check(CheckLibrary { repair: false })
+        ),
+    };
+}
+
+async fn setup(config: &Config, auto_migrate: bool) -> anyhow::Result<SqlitePool> {
+    // A dry run works on an existing, up to date library only
+    if dryrun::enabled() {
+        if !config.db_path.exists() {
+            return Err(anyhow::anyhow!(
+                "No library database at {:?}",
+                config.db_path
+            ));
+        }
+        let db = dryrun::connect(SqliteConnectOptions::new().filename(&config.db_path)).await?;
+        let pending = MigrationStatus::from_db(&db).await?.pending().len();
+        if auto_migrate && pending > 0 {
+            return Err(anyhow::anyhow!(
+                "The library has {} pending migrations; run `db migrate` first",
+                pending
+            ));
+        }
+        return Ok(db);
+    }
     // Ensure the document storage directory exists
-    let doc_store_url = std::path::PathBuf::from(std::env!("DOC_STORE_URL"));
-    if !doc_store_url.exists() {
-        std::fs::create_dir(doc_store_url)?;
+    if !config.store_dir.exists() {
+        log::info!("Creating document store {:?}", config.store_dir);
+        std::fs::create_dir_all(&config.store_dir)?;
     }
     // Ensure the database exists
-    if !Sqlite::database_exists(DB_URL).await.unwrap_or(false) {
-        log::info!("Creating database {}", DB_URL);
-        match Sqlite::create_database(DB_URL).await {
-            Ok(_) => log::info!("Database creation successful."),
-            Err(e) => panic!("error: {}", e),
+    if !config.db_path.exists() {
+        log::info!("Creating database {:?}", config.db_path);
+        if let Some(parent) = config.db_path.parent() {
+            std::fs::create_dir_all(parent)?;
         }
-        let db = SqlitePool::connect(DB_URL).await?;
-        let _doc_table_result = initialize_doc_table(&db).await?;
-        let _tag_table_result = initialize_tag_table(&db).await?;
-        return Ok(db);
-    } else {
-        return Ok(SqlitePool::connect(DB_URL).await?);
-    };
+    }
+    let options = SqliteConnectOptions::new()
+        .filename(&config.db_path)
+        .create_if_missing(true);
+    let db = SqlitePool::connect_with(options).await?;
+    if auto_migrate {
+        let _status = migrate(&db).await?;
+    }
+    return Ok(db);
 }
 
-async fn initialize_doc_table(pool: &SqlitePool) -> anyhow::Result<SqliteQueryResult> {
-    return Ok(sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS documents
-        (
-            id          INTEGER PRIMARY KEY,
-            title       TEXT NOT NULL UNIQUE,
-            author      TEXT DEFAULT '',
-            publication TEXT DEFAULT '',
-            volume      INTEGER DEFAULT 0,
-            year        INTEGER DEFAULT 0,
-            uuid        TEXT NOT NULL,
-            tags        TEXT DEFAULT '',
-            doi         TEXT DEFAULT ''
-        );
-        "#,
-    )
-    .execute(pool)
-    .await?);
+fn open_path(config: &Config, path: &std::path::Path) -> anyhow::Result<()> {
+    config
+        .opener_command(path)?
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to open document {:?}: {}", path, e))?;
+    return Ok(());
 }
 
-async fn initialize_tag_table(pool: &SqlitePool) -> anyhow::Result<SqliteQueryResult> {
-    return Ok(sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS tags
-        (
-            id INTEGER PRIMARY KEY,
-            value TEXT NOT NULL UNIQUE
+async fn print_attachments(doc: &DatabaseDoc, pool: &SqlitePool) -> anyhow::Result<()> {
+    let sep = "=".repeat(80);
+    println!("{}", sep);
+    println!("Attachments of {}:\n{}", doc.summary().trim_start(), sep);
+    for attachment in Attachment::for_doc(doc.id, pool).await?.iter() {
+        println!("{}", attachment);
+    }
+    println!("{}", sep);
+    return Ok(());
+}
+
+async fn print_trash(pool: &SqlitePool) -> anyhow::Result<()> {
+    let sep = "=".repeat(80);
+    println!("{}", sep);
+    println!("Trash:\n{}", sep);
+    for doc in DocList::trashed(pool).await?.iter() {
+        println!(
+            "{}  deleted {}",
+            doc.summary(),
+            doc.deleted_at.as_deref().unwrap_or_default()
         );
-        "#,
-    )
-    .execute(pool)
-    .await?);
+    }
+    println!("{}", sep);
+    return Ok(());
 }
 
 async fn get_tags(pool: &SqlitePool) -> anyhow::Result<Vec<DatabaseTag>> {
@@ -182,42 +441,35 @@ async fn get_tags(pool: &SqlitePool) -> anyhow::Result<Vec<DatabaseTag>> {
     .await?);
 }
 
-async fn print_tags(pool: &SqlitePool) -> anyhow::Result<()> {
-    println!("Tags:\n{}", TagList(get_tags(pool).await?));
+async fn print_authors(pool: &SqlitePool) -> anyhow::Result<()> {
+    println!("Authors:");
+    for author in DatabaseAuthor::get_all(pool).await?.iter() {
+        println!("{}", author);
+    }
     return Ok(());
 }
 
-async fn get_docs(pool: &SqlitePool) -> anyhow::Result<Vec<DatabaseDoc>> {
-    return Ok(sqlx::query_as::<_, DatabaseDoc>(
-        r#"
-        SELECT *
-        FROM documents
-        "#,
-    )
-    .fetch_all(pool)
-    .await?);
+async fn print_tags(pool: &SqlitePool) -> anyhow::Result<()> {
+    println!("Tags:\n{}", TagList(get_tags(pool).await?));
+    return Ok(());
 }
 
-async fn print_docs(pool: &SqlitePool) -> anyhow::Result<()> {
-    let sep = "=".repeat(80);
-    println!("{}", sep);
-    println!(
-        "Documents:\n{}\n{}{}",
-        sep,
-        DocList(get_docs(pool).await?),
-        sep
-    );
-    return Ok(());
+async fn print_docs(pool: &SqlitePool, format: ListFormat) -> anyhow::Result<()> {
+    return print_doc_list(DocList::get_all(pool).await?, format).await;
 }
 
-async fn print_doc_list(doc_list: DocList) -> anyhow::Result<()> {
+async fn print_doc_list(doc_list: DocList, format: ListFormat) -> anyhow::Result<()> {
     let sep = "=".repeat(80);
     println!("{}", sep);
-    println!(
-        "Documents:\n{}\n{}{}",
-        sep,
-        doc_list,
-        sep
-    );
+    match format {
+        ListFormat::Full => println!("Documents:\n{}\n{}{}", sep, doc_list, sep),
+        ListFormat::Brief => {
+            println!("Documents:\n{}", sep);
+            for doc in doc_list.iter() {
+                println!("{}", doc.summary());
+            }
+            println!("{}", sep);
+        }
+    }
     return Ok(());
 }
@@ -0,0 +1,512 @@
+use sqlx::{Executor, FromRow, SqlitePool};
+
+/// A numbered schema change.  Migrations are applied in order of `version` and each one is
+/// recorded in the `schema_version` table once it has been applied.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every schema change in the life of the library, oldest first.  Never edit a migration that
+/// has been released; add a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create documents and tags tables",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS documents
+        (
+            id          INTEGER PRIMARY KEY,
+            title       TEXT NOT NULL UNIQUE,
+            author      TEXT DEFAULT '',
+            publication TEXT DEFAULT '',
+            volume      INTEGER DEFAULT 0,
+            year        INTEGER DEFAULT 0,
+            uuid        TEXT NOT NULL,
+            tags        TEXT DEFAULT '',
+            doi         TEXT DEFAULT ''
+        );
+        CREATE TABLE IF NOT EXISTS tags
+        (
+            id INTEGER PRIMARY KEY,
+            value TEXT NOT NULL UNIQUE
+        );
+        "#,
+    },
+    Migration {
+        version: 2,
+        description: "move document tags into the document_tags join table",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS document_tags
+        (
+            doc_id INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+            tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+            PRIMARY KEY (doc_id, tag_id)
+        );
+        CREATE TEMP TABLE legacy_tags AS
+        WITH RECURSIVE split(doc_id, value, rest) AS (
+            SELECT id, '', tags || ',' FROM documents
+            WHERE tags IS NOT NULL AND tags <> ''
+            UNION ALL
+            SELECT
+                doc_id,
+                lower(trim(substr(rest, 1, instr(rest, ',') - 1))),
+                substr(rest, instr(rest, ',') + 1)
+            FROM split
+            WHERE rest <> ''
+        )
+        SELECT DISTINCT doc_id, value FROM split
+        WHERE value <> '';
+        INSERT OR IGNORE INTO tags (value)
+        SELECT DISTINCT value FROM legacy_tags;
+        INSERT OR IGNORE INTO document_tags (doc_id, tag_id)
+        SELECT legacy_tags.doc_id, tags.id FROM legacy_tags
+        JOIN tags ON tags.value = legacy_tags.value;
+        DROP TABLE legacy_tags;
+        ALTER TABLE documents DROP COLUMN tags;
+        "#,
+    },
+    Migration {
+        version: 3,
+        description: "add full-text index of document contents",
+        sql: r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS document_text
+        USING fts5(content, tokenize = 'porter unicode61');
+        CREATE TRIGGER IF NOT EXISTS document_text_delete
+        AFTER DELETE ON documents
+        BEGIN
+            DELETE FROM document_text WHERE rowid = old.id;
+        END;
+        "#,
+    },
+    Migration {
+        version: 4,
+        description: "add content hashes of stored files",
+        sql: r#"
+        ALTER TABLE documents ADD COLUMN content_hash TEXT;
+        CREATE INDEX IF NOT EXISTS documents_content_hash ON documents (content_hash);
+        "#,
+    },
+    Migration {
+        version: 5,
+        description: "add file types of stored files",
+        sql: r#"
+        ALTER TABLE documents ADD COLUMN file_type TEXT NOT NULL DEFAULT 'pdf';
+        "#,
+    },
+    Migration {
+        version: 6,
+        description: "add attachments table",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS attachments
+        (
+            id           INTEGER PRIMARY KEY,
+            doc_id       INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+            role         TEXT NOT NULL DEFAULT 'supplement',
+            uuid         TEXT NOT NULL UNIQUE,
+            filename     TEXT NOT NULL,
+            mime         TEXT NOT NULL DEFAULT 'application/octet-stream',
+            content_hash TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS attachments_doc_id ON attachments (doc_id);
+        "#,
+    },
+    Migration {
+        version: 7,
+        description: "add authors and document_authors tables",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS authors
+        (
+            id     INTEGER PRIMARY KEY,
+            first  TEXT NOT NULL DEFAULT '',
+            last   TEXT NOT NULL,
+            suffix TEXT NOT NULL DEFAULT '',
+            UNIQUE (last, first, suffix)
+        );
+        CREATE TABLE IF NOT EXISTS document_authors
+        (
+            doc_id    INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+            author_id INTEGER NOT NULL REFERENCES authors(id) ON DELETE CASCADE,
+            position  INTEGER NOT NULL,
+            PRIMARY KEY (doc_id, position),
+            UNIQUE (doc_id, author_id)
+        );
+        CREATE INDEX IF NOT EXISTS document_authors_author_id
+        ON document_authors (author_id);
+        "#,
+    },
+    Migration {
+        version: 8,
+        description: "drop free-text author column in favour of document_authors",
+        sql: r#"
+        ALTER TABLE documents DROP COLUMN author;
+        "#,
+    },
+    Migration {
+        version: 9,
+        description: "add normalized keys of titles and author names",
+        sql: r#"
+        ALTER TABLE documents ADD COLUMN title_key TEXT;
+        CREATE INDEX IF NOT EXISTS documents_title_key ON documents (title_key);
+        ALTER TABLE authors ADD COLUMN name_key TEXT;
+        CREATE INDEX IF NOT EXISTS authors_name_key ON authors (name_key);
+        "#,
+    },
+    Migration {
+        version: 10,
+        description: "add document types and type-specific fields",
+        sql: r#"
+        ALTER TABLE documents ADD COLUMN doc_type TEXT NOT NULL DEFAULT 'article';
+        ALTER TABLE documents ADD COLUMN issue TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN pages TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN publisher TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN edition TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN isbn TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN url TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN institution TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN editor TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN booktitle TEXT NOT NULL DEFAULT '';
+        ALTER TABLE documents ADD COLUMN month INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE documents ADD COLUMN abstract TEXT NOT NULL DEFAULT '';
+        UPDATE documents SET doc_type = 'misc' WHERE COALESCE(publication, '') = '';
+        "#,
+    },
+    Migration {
+        version: 11,
+        description: "add original filenames of documents",
+        sql: r#"
+        ALTER TABLE documents ADD COLUMN filename TEXT NOT NULL DEFAULT '';
+        "#,
+    },
+    Migration {
+        version: 12,
+        description: "add trash times of deleted documents",
+        sql: r#"
+        ALTER TABLE documents ADD COLUMN deleted_at TEXT;
+        CREATE INDEX IF NOT EXISTS documents_deleted_at ON documents (deleted_at);
+        "#,
+    },
+    Migration {
+        version: 13,
+        description: "add original extensions of stored files",
+        sql: r#"
+        ALTER TABLE documents ADD COLUMN extension TEXT NOT NULL DEFAULT '';
+        "#,
+    },
+    Migration {
+        version: 14,
+        description: "make title keys unique",
+        sql: r#"
+        -- backfill_title_keys renames the later documents with a title key that is taken
+        UPDATE documents SET title_key = NULL
+        WHERE EXISTS (
+            SELECT 1 FROM documents AS earlier
+            WHERE earlier.title_key = documents.title_key AND earlier.id < documents.id
+        );
+        DROP INDEX IF EXISTS documents_title_key;
+        CREATE UNIQUE INDEX documents_title_key ON documents (title_key);
+        "#,
+    },
+    Migration {
+        version: 15,
+        description: "add genres of documents",
+        sql: r#"
+        ALTER TABLE documents ADD COLUMN genre TEXT NOT NULL DEFAULT '';
+        "#,
+    },
+];
+
+/// A row of the `schema_version` table.
+#[derive(FromRow, Debug)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub description: String,
+    pub applied_at: String,
+}
+
+/// Where a library stands relative to the migrations known to this build.
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub applied: Vec<AppliedMigration>,
+}
+
+impl MigrationStatus {
+    pub async fn from_db(pool: &SqlitePool) -> anyhow::Result<Self> {
+        if !table_exists("schema_version", pool).await? {
+            let baseline = detect_baseline(pool).await?;
+            return Ok(Self {
+                applied: MIGRATIONS
+                    .iter()
+                    .filter(|m| m.version <= baseline)
+                    .map(|m| AppliedMigration {
+                        version: m.version,
+                        description: m.description.to_string(),
+                        applied_at: "(unversioned)".to_string(),
+                    })
+                    .collect(),
+            });
+        }
+        return Ok(Self {
+            applied: sqlx::query_as::<_, AppliedMigration>(
+                r#"
+                SELECT version, description, applied_at
+                FROM schema_version
+                ORDER BY version ASC
+                "#,
+            )
+            .fetch_all(pool)
+            .await?,
+        });
+    }
+
+    pub fn current_version(&self) -> u32 {
+        return self.applied.iter().map(|m| m.version).max().unwrap_or(0);
+    }
+
+    pub fn latest_version() -> u32 {
+        return MIGRATIONS.iter().map(|m| m.version).max().unwrap_or(0);
+    }
+
+    pub fn pending(&self) -> Vec<&'static Migration> {
+        let current = self.current_version();
+        return MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    }
+}
+
+impl std::fmt::Display for MigrationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:12} {} of {}",
+            "schema:",
+            self.current_version(),
+            Self::latest_version()
+        )?;
+        for migration in MIGRATIONS.iter() {
+            match self.applied.iter().find(|m| m.version == migration.version) {
+                Some(applied) => writeln!(
+                    f,
+                    "{:>4}  applied {:19}  {}",
+                    migration.version, applied.applied_at, migration.description
+                )?,
+                None => writeln!(
+                    f,
+                    "{:>4}  pending {:19}  {}",
+                    migration.version, "", migration.description
+                )?,
+            }
+        }
+        if self.current_version() > Self::latest_version() {
+            writeln!(
+                f,
+                "The library was created by a newer version of odinsource."
+            )?;
+        }
+        return Ok(());
+    }
+}
+
+/// Data changes that SQL cannot express, run before the migration of the same version.  Each
+/// step must be safe to repeat, since a failed migration is retried from the start.
+async fn prepare(version: u32, pool: &SqlitePool) -> anyhow::Result<()> {
+    // Author names are parsed from the free-text column before it is dropped
+    if version == 8 {
+        crate::author::backfill_authors(pool).await?;
+    }
+    return Ok(());
+}
+
+/// Bring the library schema up to date, applying every pending migration in its own
+/// transaction.
+pub async fn migrate(pool: &SqlitePool) -> anyhow::Result<MigrationStatus> {
+    initialize_version_table(pool).await?;
+    let status = MigrationStatus::from_db(pool).await?;
+    if status.current_version() > MigrationStatus::latest_version() {
+        return Err(anyhow::anyhow!(
+            "Library schema version {} is newer than this build supports ({})",
+            status.current_version(),
+            MigrationStatus::latest_version()
+        ));
+    }
+    for migration in status.pending() {
+        log::info!(
+            "Applying migration {}: {}",
+            migration.version,
+            migration.description
+        );
+        prepare(migration.version, pool).await?;
+        let mut tx = pool.begin().await?;
+        tx.execute(migration.sql)
+            .await
+            .map_err(|e| anyhow::anyhow!("Migration {} failed: {}", migration.version, e))?;
+        sqlx::query(
+            r#"
+            INSERT INTO schema_version (version, description)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+    }
+    // Values computed in Rust for rows written before their columns existed
+    crate::document::backfill_content_hashes(pool).await?;
+    crate::document::backfill_title_keys(pool).await?;
+    crate::author::backfill_name_keys(pool).await?;
+    return MigrationStatus::from_db(pool).await;
+}
+
+/// Create the `schema_version` table.  Libraries created before migrations were versioned
+/// are stamped with the version their tables already match.
+async fn initialize_version_table(pool: &SqlitePool) -> anyhow::Result<()> {
+    if table_exists("schema_version", pool).await? {
+        return Ok(());
+    }
+    let baseline = detect_baseline(pool).await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_version
+        (
+            version     INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at  TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    for migration in MIGRATIONS.iter().filter(|m| m.version <= baseline) {
+        log::info!(
+            "Existing library already at migration {}: {}",
+            migration.version,
+            migration.description
+        );
+        sqlx::query(
+            r#"
+            INSERT INTO schema_version (version, description)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .execute(pool)
+        .await?;
+    }
+    return Ok(());
+}
+
+/// The migration an unversioned library's tables already match.
+async fn detect_baseline(pool: &SqlitePool) -> anyhow::Result<u32> {
+    if table_exists("document_tags", pool).await? {
+        return Ok(2);
+    } else if table_exists("documents", pool).await? {
+        return Ok(1);
+    }
+    return Ok(0);
+}
+
+pub async fn table_exists(name: &str, pool: &SqlitePool) -> anyhow::Result<bool> {
+    let count: i64 = sqlx::query_scalar(
+        r#"
+        SELECT COUNT(*) FROM sqlite_master
+        WHERE type='table' AND name=?1
+        "#,
+    )
+    .bind(name)
+    .fetch_one(pool)
+    .await?;
+    return Ok(count > 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use sqlx::sqlite::SqliteConnectOptions;
+
+    /// A library in the unversioned layout of the first release.
+    async fn baseline_library(name: &str) -> SqlitePool {
+        std::fs::create_dir_all(&Config::get().store_dir).unwrap();
+        let db_path = std::env::temp_dir().join(format!(
+            "odinsource-test-{}-{}.db",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&db_path);
+        let options = SqliteConnectOptions::new()
+            .filename(&db_path)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        pool.execute(MIGRATIONS[0].sql).await.unwrap();
+        return pool;
+    }
+
+    #[tokio::test]
+    async fn migrates_baseline_library_to_latest_version() {
+        let pool = baseline_library("baseline").await;
+        pool.execute(
+            r#"
+            INSERT INTO documents (title, author, publication, volume, year, uuid, tags, doi)
+            VALUES
+                ('Cats', 'Doe, Jane and Smith, John', 'Nature', 1, 2020, 'a', 'pets, Cats', ''),
+                ('CATS', 'doe, jane', '', 0, 2021, 'b', 'pets', ''),
+                ('Dogs', '', '', 0, 2022, 'c', '', '');
+            "#,
+        )
+        .await
+        .unwrap();
+
+        let status = migrate(&pool).await.unwrap();
+        assert_eq!(status.current_version(), MigrationStatus::latest_version());
+        assert!(status.pending().is_empty());
+
+        let titles: Vec<(String, String)> =
+            sqlx::query_as("SELECT title, title_key FROM documents ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            titles,
+            vec![
+                ("Cats".to_string(), "cats".to_string()),
+                ("CATS (2)".to_string(), "cats (2)".to_string()),
+                ("Dogs".to_string(), "dogs".to_string()),
+            ]
+        );
+
+        // Authors differing only in case are merged once name keys exist
+        let authors: Vec<(u32, u32, String)> = sqlx::query_as(
+            r#"
+            SELECT doc_id, position, authors.last FROM document_authors
+            JOIN authors ON authors.id = document_authors.author_id
+            ORDER BY doc_id, position
+            "#,
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            authors,
+            vec![
+                (1, 0, "Doe".to_string()),
+                (1, 1, "Smith".to_string()),
+                (2, 0, "Doe".to_string()),
+            ]
+        );
+        let tags: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM document_tags")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(tags, 3);
+
+        // Title keys are unique from now on
+        let duplicate = sqlx::query("UPDATE documents SET title_key = 'cats' WHERE id = 3")
+            .execute(&pool)
+            .await;
+        assert!(duplicate.is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use regex::Regex;
+use std::path::Path;
+
+/// Number of leading pages searched for DOI and arXiv identifiers.
+const IDENTIFIER_PAGES: usize = 2;
+
+/// Bibliographic information found in a PDF.  Every field is optional since most PDFs only
+/// carry some of it.
+#[derive(Debug, Default, PartialEq)]
+pub struct PdfMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Vec<String>,
+    pub year: Option<u16>,
+    pub publication: Option<String>,
+    pub volume: Option<u16>,
+    pub doi: Option<String>,
+    pub arxiv: Option<String>,
+}
+
+impl PdfMetadata {
+    /// Read the Info dictionary and XMP packet of the PDF at `path`, and scan its first
+    /// pages for identifiers.  XMP values take precedence over the Info dictionary.
+    pub fn extract(path: &Path) -> anyhow::Result<Self> {
+        let pdf = lopdf::Document::load(path)
+            .map_err(|e| anyhow::anyhow!("Could not read PDF {:?}: {}", path, e))?;
+        let mut meta = Self::default();
+        if pdf.is_encrypted() {
+            log::warn!("Skipping metadata of encrypted PDF {:?}", path);
+            return Ok(meta);
+        }
+        meta.read_info(&pdf);
+        meta.read_xmp(&pdf);
+        meta.read_identifiers(path);
+        log::debug!("Metadata extracted from {:?}: {:?}", path, meta);
+        return Ok(meta);
+    }
+
+    /// The DOI, or the arXiv DOI if only an arXiv identifier was found.
+    pub fn doi_or_arxiv(&self) -> Option<String> {
+        return self.doi.clone().or_else(|| {
+            self.arxiv
+                .as_ref()
+                .map(|id| format!("10.48550/arXiv.{}", strip_arxiv_version(id)))
+        });
+    }
+
+    fn read_info(&mut self, pdf: &lopdf::Document) {
+        let info = match pdf
+            .trailer
+            .get(b"Info")
+            .and_then(|o| pdf.dereference(o))
+            .and_then(|(_, o)| o.as_dict())
+        {
+            Ok(info) => info,
+            Err(_) => return,
+        };
+        let text = |key: &[u8]| -> Option<String> {
+            let object = info.get(key).ok()?;
+            let (_, object) = pdf.dereference(object).ok()?;
+            return non_empty(decode_pdf_string(object.as_str().ok()?));
+        };
+        self.title = text(b"Title");
+        self.author = text(b"Author");
+        self.subject = text(b"Subject");
+        if let Some(keywords) = text(b"Keywords") {
+            self.keywords = split_keywords(&keywords);
+        }
+        self.year = text(b"CreationDate").and_then(|d| parse_year(d.trim_start_matches("D:")));
+    }
+
+    fn read_xmp(&mut self, pdf: &lopdf::Document) {
+        let stream = match pdf
+            .catalog()
+            .and_then(|c| c.get(b"Metadata"))
+            .and_then(|o| pdf.dereference(o))
+            .and_then(|(_, o)| o.as_stream())
+        {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let content = match stream.dict.has(b"Filter") {
+            true => match stream.decompressed_content() {
+                Ok(content) => content,
+                Err(_) => return,
+            },
+            false => stream.content.clone(),
+        };
+        let xmp = String::from_utf8_lossy(&content);
+        if let Some(title) = xmp_value(&xmp, "dc:title") {
+            self.title = Some(title);
+        }
+        let creators = xmp_items(&xmp, "dc:creator");
+        if !creators.is_empty() {
+            self.author = Some(creators.join(" and "));
+        }
+        if let Some(subject) = xmp_value(&xmp, "dc:description") {
+            self.subject = Some(subject);
+        }
+        if let Some(keywords) = xmp_value(&xmp, "pdf:Keywords") {
+            self.keywords = split_keywords(&keywords);
+        } else {
+            let subjects = xmp_items(&xmp, "dc:subject");
+            if !subjects.is_empty() {
+                self.keywords = subjects;
+            }
+        }
+        if let Some(year) = xmp_value(&xmp, "prism:coverDate")
+            .or_else(|| xmp_value(&xmp, "prism:publicationDate"))
+            .or_else(|| xmp_value(&xmp, "xmp:CreateDate"))
+            .and_then(|d| parse_year(&d))
+        {
+            self.year = Some(year);
+        }
+        if let Some(publication) = xmp_value(&xmp, "prism:publicationName") {
+            self.publication = Some(publication);
+        }
+        if let Some(volume) = xmp_value(&xmp, "prism:volume").and_then(|v| v.parse().ok()) {
+            self.volume = Some(volume);
+        }
+        if let Some(doi) = xmp_value(&xmp, "prism:doi").and_then(|d| find_doi(&d)) {
+            self.doi = Some(doi);
+        }
+    }
+
+    fn read_identifiers(&mut self, path: &Path) {
+        let owned = path.to_path_buf();
+        let pages =
+            match std::panic::catch_unwind(move || pdf_extract::extract_text_by_pages(owned)) {
+                Ok(Ok(pages)) => pages,
+                _ => {
+                    log::debug!("No text found for identifiers in {:?}", path);
+                    return;
+                }
+            };
+        let text = pages
+            .into_iter()
+            .take(IDENTIFIER_PAGES)
+            .collect::<Vec<String>>()
+            .join("\n");
+        if self.doi.is_none() {
+            self.doi = find_doi(&text);
+        }
+        self.arxiv = find_arxiv(&text);
+    }
+}
+
+/// The first DOI in `text`, without trailing punctuation.
+pub fn find_doi(text: &str) -> Option<String> {
+    let re = Regex::new(r#"(?i)\b(10\.\d{4,9}/[^\s"<>]+)"#).expect("valid DOI pattern");
+    return re
+        .captures(text)
+        .map(|c| c[1].trim_end_matches(['.', ',', ';', ')', ']']).to_string());
+}
+
+/// The first arXiv identifier in `text`, e.g. `2101.00001v2` from `arXiv:2101.00001v2`.
+pub fn find_arxiv(text: &str) -> Option<String> {
+    let re = Regex::new(r"(?i)arxiv:\s*(\d{4}\.\d{4,5}(v\d+)?)").expect("valid arXiv pattern");
+    return re.captures(text).map(|c| c[1].to_string());
+}
+
+fn strip_arxiv_version(id: &str) -> &str {
+    return match id.rfind('v') {
+        Some(i) if id[i + 1..].chars().all(|c| c.is_ascii_digit()) => &id[..i],
+        _ => id,
+    };
+}
+
+/// Decode a PDF text string: UTF-16BE when it starts with a byte order mark, otherwise
+/// PDFDocEncoding, which matches Latin-1 for printable characters.
+fn decode_pdf_string(bytes: &[u8]) -> String {
+    if bytes.starts_with(&[0xFE, 0xFF]) {
+        let units: Vec<u16> = bytes[2..]
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        return String::from_utf16_lossy(&units);
+    }
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        return String::from_utf8_lossy(&bytes[3..]).to_string();
+    }
+    return bytes.iter().map(|b| *b as char).collect();
+}
+
+/// Text of the first `rdf:li` in `tag`, or of `tag` itself when it has no list.
+fn xmp_value(xmp: &str, tag: &str) -> Option<String> {
+    return xmp_items(xmp, tag).into_iter().next().or_else(|| {
+        let element = xmp_element(xmp, tag)?;
+        non_empty(xml_unescape(&strip_tags(element)))
+    });
+}
+
+fn xmp_items(xmp: &str, tag: &str) -> Vec<String> {
+    let element = match xmp_element(xmp, tag) {
+        Some(element) => element,
+        None => return Vec::new(),
+    };
+    let re = Regex::new(r"(?s)<rdf:li[^>]*>(.*?)</rdf:li>").expect("valid rdf:li pattern");
+    return re
+        .captures_iter(element)
+        .filter_map(|c| non_empty(xml_unescape(&c[1])))
+        .collect();
+}
+
+/// Content of `<tag>...</tag>`, or the value of a `tag="..."` attribute.  Only whole names
+/// match, so `dc:title` does not find `<dc:titles>`.
+fn xmp_element<'a>(xmp: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}", tag);
+    let element = xmp.match_indices(&open).map(|(i, _)| i).find(|i| {
+        xmp[i + open.len()..].starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace())
+    });
+    if let Some(start) = element {
+        let body_start = start + xmp[start..].find('>')? + 1;
+        if xmp[..body_start].ends_with("/>") {
+            return None;
+        }
+        let close = format!("</{}>", tag);
+        let body_end = body_start + xmp[body_start..].find(&close)?;
+        return Some(&xmp[body_start..body_end]);
+    }
+    let attribute = format!("{}=\"", tag);
+    let start = xmp
+        .match_indices(&attribute)
+        .map(|(i, _)| i)
+        .find(|i| xmp[..*i].ends_with(char::is_whitespace))?
+        + attribute.len();
+    let end = start + xmp[start..].find('"')?;
+    return Some(&xmp[start..end]);
+}
+
+fn strip_tags(value: &str) -> String {
+    let re = Regex::new(r"<[^>]*>").expect("valid tag pattern");
+    return re.replace_all(value, "").to_string();
+}
+
+fn xml_unescape(value: &str) -> String {
+    return value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+        .trim()
+        .to_string();
+}
+
+fn split_keywords(keywords: &str) -> Vec<String> {
+    return keywords
+        .split([',', ';'])
+        .map(|k| k.trim().to_string())
+        .filter(|k| !k.is_empty())
+        .collect();
+}
+
+/// Year at the start of a PDF (`20200131...`) or ISO 8601 (`2020-01-31`) date.
+fn parse_year(date: &str) -> Option<u16> {
+    let digits: String = date.trim().chars().take(4).collect();
+    return digits
+        .parse::<u16>()
+        .ok()
+        .filter(|y| (1000..=9999).contains(y));
+}
+
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim().to_string();
+    return match value.is_empty() {
+        true => None,
+        false => Some(value),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Object, Stream};
+
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description rdf:about="" prism:volume="12" prism:doi="https://doi.org/10.1000/xyz.123">
+<dc:titles><rdf:Alt><rdf:li xml:lang="x-default">Not the title</rdf:li></rdf:Alt></dc:titles>
+<dc:title><rdf:Alt><rdf:li xml:lang="x-default">Cats &amp; Dogs</rdf:li></rdf:Alt></dc:title>
+<dc:creator><rdf:Seq><rdf:li>Jane Doe</rdf:li><rdf:li>John Roe</rdf:li></rdf:Seq></dc:creator>
+<dc:subject><rdf:Bag><rdf:li>cats</rdf:li><rdf:li>dogs</rdf:li></rdf:Bag></dc:subject>
+<dc:description/>
+<prism:publicationName>Journal of Feline Studies</prism:publicationName>
+<prism:coverDate>2020-03-01</prism:coverDate>
+</rdf:Description>
+</rdf:RDF>
+</x:xmpmeta>"#;
+
+    #[test]
+    fn finds_doi_without_trailing_punctuation() {
+        assert_eq!(
+            find_doi("See doi:10.1000/xyz.123)."),
+            Some("10.1000/xyz.123".to_string())
+        );
+        assert_eq!(
+            find_doi("https://doi.org/10.48550/arXiv.2101.00001, 2021"),
+            Some("10.48550/arXiv.2101.00001".to_string())
+        );
+        assert_eq!(find_doi("10.12/too-short registrant"), None);
+        assert_eq!(find_doi("no identifier here"), None);
+    }
+
+    #[test]
+    fn finds_arxiv_and_strips_version() {
+        assert_eq!(
+            find_arxiv("Preprint arXiv: 2101.00001v2 [cs.LG]"),
+            Some("2101.00001v2".to_string())
+        );
+        assert_eq!(find_arxiv("ARXIV:1501.0001"), Some("1501.0001".to_string()));
+        assert_eq!(find_arxiv("arXiv:cs/0101001"), None);
+        assert_eq!(strip_arxiv_version("2101.00001v2"), "2101.00001");
+        assert_eq!(strip_arxiv_version("2101.00001"), "2101.00001");
+        assert_eq!(strip_arxiv_version("2101.00001v"), "2101.00001");
+
+        let meta = PdfMetadata {
+            arxiv: Some("2101.00001v2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            meta.doi_or_arxiv(),
+            Some("10.48550/arXiv.2101.00001".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_pdf_and_iso_years() {
+        assert_eq!(parse_year("20200131120000Z"), Some(2020));
+        assert_eq!(parse_year(" 2020-01-31"), Some(2020));
+        assert_eq!(parse_year("0999"), None);
+        assert_eq!(parse_year("20"), None);
+        assert_eq!(parse_year("Jan 2020"), None);
+    }
+
+    #[test]
+    fn decodes_pdf_strings() {
+        assert_eq!(decode_pdf_string(b"Caf\xe9"), "Café");
+        assert_eq!(decode_pdf_string(b"\xef\xbb\xbfCaf\xc3\xa9"), "Café");
+        assert_eq!(
+            decode_pdf_string(&[0xFE, 0xFF, 0x00, 0x43, 0x00, 0xE9, 0xD8, 0x3D, 0xDC, 0x31]),
+            "Cé🐱"
+        );
+    }
+
+    #[test]
+    fn reads_xmp_values_lists_and_attributes() {
+        assert_eq!(xmp_value(XMP, "dc:title"), Some("Cats & Dogs".to_string()));
+        assert_eq!(xmp_items(XMP, "dc:creator"), vec!["Jane Doe", "John Roe"]);
+        assert_eq!(
+            xmp_value(XMP, "prism:publicationName"),
+            Some("Journal of Feline Studies".to_string())
+        );
+        assert_eq!(xmp_value(XMP, "prism:volume"), Some("12".to_string()));
+        assert_eq!(xmp_value(XMP, "dc:description"), None);
+        assert_eq!(xmp_value(XMP, "prism:publication"), None);
+        assert_eq!(xmp_value(XMP, "doi"), None);
+        assert_eq!(xmp_items(XMP, "pdf:Keywords"), Vec::<String>::new());
+        assert_eq!(
+            split_keywords("cats; dogs,,birds "),
+            vec!["cats", "dogs", "birds"]
+        );
+    }
+
+    #[test]
+    fn xmp_takes_precedence_over_info() {
+        let mut pdf = lopdf::Document::with_version("1.5");
+        let metadata = pdf.add_object(Stream::new(
+            dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+            XMP.as_bytes().to_vec(),
+        ));
+        let catalog = pdf.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Metadata" => metadata,
+        });
+        let info = pdf.add_object(dictionary! {
+            "Title" => Object::string_literal("Info title"),
+            "Author" => Object::string_literal("Info author"),
+            "Subject" => Object::string_literal("Info subject"),
+            "Keywords" => Object::string_literal("info, keywords"),
+            "CreationDate" => Object::string_literal("D:19990101000000Z"),
+        });
+        pdf.trailer.set("Root", catalog);
+        pdf.trailer.set("Info", info);
+
+        let mut meta = PdfMetadata::default();
+        meta.read_info(&pdf);
+        assert_eq!(meta.title, Some("Info title".to_string()));
+        assert_eq!(meta.keywords, vec!["info", "keywords"]);
+        assert_eq!(meta.year, Some(1999));
+        meta.read_xmp(&pdf);
+        assert_eq!(
+            meta,
+            PdfMetadata {
+                title: Some("Cats & Dogs".to_string()),
+                author: Some("Jane Doe and John Roe".to_string()),
+                subject: Some("Info subject".to_string()),
+                keywords: vec!["cats".to_string(), "dogs".to_string()],
+                year: Some(2020),
+                publication: Some("Journal of Feline Studies".to_string()),
+                volume: Some(12),
+                doi: Some("10.1000/xyz.123".to_string()),
+                arxiv: None,
+            }
+        );
+    }
+}
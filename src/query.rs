@@ -0,0 +1,337 @@
+/// A parsed boolean tag query such as `ml AND (survey OR review) AND NOT draft`.
+///
+/// `NOT` binds tighter than `AND`, which binds tighter than `OR`.  Operators are case
+/// insensitive, and tags containing spaces or parentheses can be quoted: `"deep learning"`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TagQuery {
+    Tag(String),
+    Not(Box<TagQuery>),
+    And(Box<TagQuery>, Box<TagQuery>),
+    Or(Box<TagQuery>, Box<TagQuery>),
+}
+
+/// A query that could not be parsed, with the character position of the problem.
+#[derive(Debug, PartialEq)]
+pub struct QueryError {
+    pub query: String,
+    pub position: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} at position {}", self.message, self.position)?;
+        writeln!(f, "  {}", self.query)?;
+        return write!(f, "  {}^", " ".repeat(self.position));
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Tag(String),
+}
+
+impl TagQuery {
+    pub fn parse(query: &str) -> Result<Self, QueryError> {
+        let tokens = tokenize(query)?;
+        let mut parser = QueryParser {
+            query,
+            tokens,
+            pos: 0,
+        };
+        let ast = parser.or()?;
+        if let Some((_, position)) = parser.tokens.get(parser.pos) {
+            return Err(parser.error(*position, "expected AND or OR"));
+        }
+        return Ok(ast);
+    }
+
+    /// Compile the query to a SQL condition on `documents.id`.  Tag values are returned as
+    /// parameters, in the order their `?` placeholders appear.
+    pub fn to_sql(&self) -> (String, Vec<String>) {
+        let mut params = Vec::new();
+        let sql = self.sql_condition(&mut params);
+        return (sql, params);
+    }
+
+    fn sql_condition(&self, params: &mut Vec<String>) -> String {
+        return match self {
+            Self::Tag(value) => {
+                params.push(value.clone());
+                r#"EXISTS (
+                    SELECT 1 FROM document_tags
+                    JOIN tags ON tags.id = document_tags.tag_id
+                    WHERE document_tags.doc_id = documents.id AND tags.value = ?
+                )"#
+                .to_string()
+            }
+            Self::Not(inner) => format!("NOT ({})", inner.sql_condition(params)),
+            Self::And(lhs, rhs) => format!(
+                "({} AND {})",
+                lhs.sql_condition(params),
+                rhs.sql_condition(params)
+            ),
+            Self::Or(lhs, rhs) => format!(
+                "({} OR {})",
+                lhs.sql_condition(params),
+                rhs.sql_condition(params)
+            ),
+        };
+    }
+}
+
+impl std::fmt::Display for TagQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Self::Tag(value) if value.contains([' ', '(', ')']) => write!(f, "\"{}\"", value),
+            Self::Tag(value) => write!(f, "{}", value),
+            Self::Not(inner) => write!(f, "NOT {}", inner),
+            Self::And(lhs, rhs) => write!(f, "({} AND {})", lhs, rhs),
+            Self::Or(lhs, rhs) => write!(f, "({} OR {})", lhs, rhs),
+        };
+    }
+}
+
+impl std::str::FromStr for TagQuery {
+    type Err = QueryError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return Self::parse(s);
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<(Token, usize)>, QueryError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push((Token::Open, i));
+            i += 1;
+        } else if c == ')' {
+            tokens.push((Token::Close, i));
+            i += 1;
+        } else if c == '"' {
+            let start = i;
+            i += 1;
+            let mut value = String::new();
+            while i < chars.len() && chars[i] != '"' {
+                value.push(chars[i]);
+                i += 1;
+            }
+            if i == chars.len() {
+                return Err(QueryError {
+                    query: query.to_string(),
+                    position: start,
+                    message: "unterminated quote".to_string(),
+                });
+            }
+            i += 1;
+            tokens.push((Token::Tag(value.trim().to_lowercase()), start));
+        } else {
+            let start = i;
+            let mut word = String::new();
+            while i < chars.len() && !chars[i].is_whitespace() && !"()\"".contains(chars[i]) {
+                word.push(chars[i]);
+                i += 1;
+            }
+            let token = match word.to_uppercase().as_str() {
+                "AND" => Token::And,
+                "OR" => Token::Or,
+                "NOT" => Token::Not,
+                _ => Token::Tag(word.to_lowercase()),
+            };
+            tokens.push((token, start));
+        }
+    }
+    return Ok(tokens);
+}
+
+struct QueryParser<'a> {
+    query: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl QueryParser<'_> {
+    fn or(&mut self) -> Result<TagQuery, QueryError> {
+        let mut lhs = self.and()?;
+        while self.consume(&Token::Or) {
+            let rhs = self.and()?;
+            lhs = TagQuery::Or(Box::new(lhs), Box::new(rhs));
+        }
+        return Ok(lhs);
+    }
+
+    fn and(&mut self) -> Result<TagQuery, QueryError> {
+        let mut lhs = self.not()?;
+        while self.consume(&Token::And) {
+            let rhs = self.not()?;
+            lhs = TagQuery::And(Box::new(lhs), Box::new(rhs));
+        }
+        return Ok(lhs);
+    }
+
+    fn not(&mut self) -> Result<TagQuery, QueryError> {
+        if self.consume(&Token::Not) {
+            return Ok(TagQuery::Not(Box::new(self.not()?)));
+        }
+        return self.atom();
+    }
+
+    fn atom(&mut self) -> Result<TagQuery, QueryError> {
+        let (token, position) = match self.tokens.get(self.pos) {
+            Some((token, position)) => (token.clone(), *position),
+            None => {
+                return Err(self.error(self.query.chars().count(), "expected a tag"));
+            }
+        };
+        self.pos += 1;
+        return match token {
+            Token::Tag(value) if value.is_empty() => Err(self.error(position, "empty tag")),
+            Token::Tag(value) => Ok(TagQuery::Tag(value)),
+            Token::Open => {
+                let inner = self.or()?;
+                match self.tokens.get(self.pos) {
+                    Some((Token::Close, _)) => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    Some((_, position)) => Err(self.error(*position, "expected ')'")),
+                    None => Err(self.error(position, "unclosed '('")),
+                }
+            }
+            Token::Close => Err(self.error(position, "unexpected ')'")),
+            _ => Err(self.error(position, "expected a tag")),
+        };
+    }
+
+    fn consume(&mut self, expected: &Token) -> bool {
+        if self.tokens.get(self.pos).map(|(t, _)| t) == Some(expected) {
+            self.pos += 1;
+            return true;
+        }
+        return false;
+    }
+
+    fn error(&self, position: usize, message: &str) -> QueryError {
+        return QueryError {
+            query: self.query.to_string(),
+            position,
+            message: message.to_string(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Executor, SqlitePool};
+
+    fn tag(value: &str) -> Box<TagQuery> {
+        return Box::new(TagQuery::Tag(value.to_string()));
+    }
+
+    fn parse_error(query: &str) -> (usize, String) {
+        let e = TagQuery::parse(query).unwrap_err();
+        return (e.position, e.message);
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_and_and_tighter_than_or() {
+        assert_eq!(
+            TagQuery::parse("a OR b AND NOT c").unwrap(),
+            TagQuery::Or(
+                tag("a"),
+                Box::new(TagQuery::And(tag("b"), Box::new(TagQuery::Not(tag("c")))))
+            )
+        );
+        assert_eq!(
+            TagQuery::parse("(a or b) and c").unwrap(),
+            TagQuery::And(Box::new(TagQuery::Or(tag("a"), tag("b"))), tag("c"))
+        );
+        assert_eq!(
+            TagQuery::parse("NOT NOT a").unwrap(),
+            TagQuery::Not(Box::new(TagQuery::Not(tag("a"))))
+        );
+    }
+
+    #[test]
+    fn tags_are_lowercased_and_may_be_quoted() {
+        assert_eq!(
+            TagQuery::parse(r#"ML AND "Deep Learning (2)""#).unwrap(),
+            TagQuery::And(tag("ml"), tag("deep learning (2)"))
+        );
+        assert_eq!(
+            TagQuery::parse(r#"ml AND "deep learning""#)
+                .unwrap()
+                .to_string(),
+            r#"(ml AND "deep learning")"#
+        );
+    }
+
+    #[test]
+    fn errors_report_the_position_of_the_problem() {
+        assert_eq!(parse_error("ml AND"), (6, "expected a tag".to_string()));
+        assert_eq!(
+            parse_error("ml survey"),
+            (3, "expected AND or OR".to_string())
+        );
+        assert_eq!(
+            parse_error("(ml OR survey"),
+            (0, "unclosed '('".to_string())
+        );
+        assert_eq!(parse_error("(ml survey)"), (4, "expected ')'".to_string()));
+        assert_eq!(parse_error("ml AND )"), (7, "unexpected ')'".to_string()));
+        assert_eq!(
+            parse_error(r#"ml OR "deep"#),
+            (6, "unterminated quote".to_string())
+        );
+        assert_eq!(parse_error(r#"ml OR """#), (6, "empty tag".to_string()));
+        assert_eq!(parse_error("AND ml"), (0, "expected a tag".to_string()));
+    }
+
+    #[test]
+    fn error_message_points_at_the_position() {
+        let e = TagQuery::parse("ml AND )").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "unexpected ')' at position 7\n  ml AND )\n         ^"
+        );
+    }
+
+    #[tokio::test]
+    async fn sql_matches_documents_by_exact_tag() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        pool.execute(
+            r#"
+            CREATE TABLE documents (id INTEGER PRIMARY KEY);
+            CREATE TABLE tags (id INTEGER PRIMARY KEY, value TEXT NOT NULL UNIQUE);
+            CREATE TABLE document_tags (doc_id INTEGER, tag_id INTEGER);
+            INSERT INTO documents (id) VALUES (1), (2), (3), (4);
+            INSERT INTO tags (id, value) VALUES (1, 'ml'), (2, 'html'), (3, 'survey'), (4, 'draft');
+            INSERT INTO document_tags (doc_id, tag_id)
+            VALUES (1, 1), (1, 3), (2, 2), (2, 3), (3, 1), (3, 3), (3, 4), (4, 1);
+            "#,
+        )
+        .await
+        .unwrap();
+        let query = TagQuery::parse("ml AND (survey OR review) AND NOT draft").unwrap();
+        let (condition, params) = query.to_sql();
+        let sql = format!("SELECT id FROM documents WHERE {} ORDER BY id", condition);
+        let mut ids = sqlx::query_scalar::<_, i64>(&sql);
+        for param in params.iter() {
+            ids = ids.bind(param);
+        }
+        assert_eq!(ids.fetch_all(&pool).await.unwrap(), vec![1]);
+    }
+}
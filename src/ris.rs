@@ -0,0 +1,509 @@
+use crate::author::{DatabaseAuthor, Name};
+use crate::bibtex::citation_keys;
+use crate::config::Config;
+use crate::doctype::{parse_month, DocType, ExtraFields};
+use crate::document::{DatabaseDoc, DocList, Document, DocumentBuilder};
+use crate::filetype::FILE_TYPES;
+use crate::tag::TagInputList;
+use regex::Regex;
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+
+/// A single RIS record, from its `TY` line to its `ER` line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RisEntry {
+    /// Tags and values in file order.  Repeatable tags such as `AU` and `KW` appear once per
+    /// value.
+    pub fields: Vec<(String, String)>,
+}
+
+impl RisEntry {
+    /// Record for a document, with `file` written as its `L1` link.
+    pub fn from_doc(doc: &DatabaseDoc, authors: &[Name], key: &str, file: &Path) -> Self {
+        let mut entry = Self { fields: Vec::new() };
+        let extra = &doc.extra;
+        entry.push("TY", ris_type(doc.doc_type));
+        entry.push("ID", key);
+        entry.push("TI", &doc.title);
+        for author in authors.iter() {
+            entry.push("AU", &ris_name(author));
+        }
+        for editor in Name::parse_list(&extra.editor).iter() {
+            entry.push("A2", &ris_name(editor));
+        }
+        if doc.year != 0 {
+            entry.push("PY", &doc.year.to_string());
+        }
+        if extra.month != 0 {
+            let year = match doc.year {
+                0 => String::new(),
+                year => year.to_string(),
+            };
+            entry.push("DA", &format!("{}/{:02}//", year, extra.month));
+        }
+        entry.push("JO", &doc.publication);
+        entry.push("T2", &extra.booktitle);
+        if doc.volume != 0 {
+            entry.push("VL", &doc.volume.to_string());
+        }
+        entry.push("IS", &extra.issue);
+        match extra.pages.split_once('-') {
+            Some((start, end)) => {
+                entry.push("SP", start.trim());
+                entry.push("EP", end.trim_start_matches('-').trim());
+            }
+            None => entry.push("SP", &extra.pages),
+        }
+        // Readers file the university of a thesis and the issuer of a report under PB
+        let institution_is_publisher = matches!(
+            doc.doc_type,
+            DocType::Thesis | DocType::Report | DocType::Standard
+        ) && extra.publisher.is_empty();
+        if institution_is_publisher {
+            entry.push("PB", &extra.institution);
+        } else {
+            entry.push("PB", &extra.publisher);
+            entry.push("AD", &extra.institution);
+        }
+        entry.push("M3", &extra.genre);
+        entry.push("ET", &extra.edition);
+        entry.push("SN", &extra.isbn);
+        entry.push("DO", &doc.doi);
+        entry.push("UR", &extra.url);
+        entry.push("AB", &extra.abstract_);
+        for tag in TagInputList::from(doc.tags.as_str()).tag_values() {
+            entry.push("KW", tag);
+        }
+        entry.push("L1", &file.to_string_lossy());
+        return entry;
+    }
+
+    /// Append a field, skipping empty values.
+    fn push(&mut self, tag: &str, value: &str) {
+        if !value.is_empty() {
+            self.fields.push((tag.to_string(), value.to_string()));
+        }
+    }
+
+    /// First value of a tag.
+    pub fn field(&self, tag: &str) -> Option<&str> {
+        return self
+            .fields
+            .iter()
+            .find(|(t, v)| t == tag && !v.is_empty())
+            .map(|(_, v)| v.as_str());
+    }
+
+    /// Every value of a repeatable tag, in order.
+    pub fn values(&self, tag: &str) -> Vec<&str> {
+        return self
+            .fields
+            .iter()
+            .filter(|(t, v)| t == tag && !v.is_empty())
+            .map(|(_, v)| v.as_str())
+            .collect();
+    }
+
+    fn describe(&self) -> String {
+        return self
+            .field("ID")
+            .or_else(|| self.field("TI"))
+            .or_else(|| self.field("T1"))
+            .unwrap_or("without title")
+            .to_string();
+    }
+
+    /// Convert the record to a document for insertion.  The document file is taken from the
+    /// `L1` links, or else looked up as `<ID>.<ext>` in `files_dir` for each supported file
+    /// type.
+    pub fn to_document(&self, files_dir: &Path) -> anyhow::Result<Document> {
+        let title = match self.field("TI").or_else(|| self.field("T1")) {
+            Some(title) => title,
+            None => return Err(anyhow::anyhow!("Record {} has no title", self.describe())),
+        };
+        let path = self.find_file(files_dir)?;
+        let date = self.field("DA").or_else(|| self.field("Y1"));
+        let year = self.field("PY").or(date).map(leading_number).unwrap_or(0);
+        let doc_type = doc_type(self.field("TY").unwrap_or(""));
+        let authors = self
+            .values("AU")
+            .into_iter()
+            .chain(self.values("A1"))
+            .map(join_name)
+            .collect::<Vec<String>>()
+            .join(" and ");
+        let tags = self
+            .values("KW")
+            .iter()
+            .flat_map(|k| k.split([',', ';']))
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<&str>>()
+            .join(",");
+        let mut publication = self
+            .field("JO")
+            .or_else(|| self.field("JF"))
+            .or_else(|| self.field("JA"))
+            .unwrap_or("");
+        let mut extra = self.extra_fields(date);
+        // Journal records from some sources carry the journal name in T2 only
+        if doc_type == DocType::Article && publication.is_empty() {
+            publication = self.field("T2").unwrap_or("");
+            extra.booktitle.clear();
+        }
+        if matches!(
+            doc_type,
+            DocType::Thesis | DocType::Report | DocType::Standard
+        ) && extra.institution.is_empty()
+        {
+            extra.institution = std::mem::take(&mut extra.publisher);
+        }
+        return DocumentBuilder::new(title, &path.to_string_lossy())
+            .author(&authors)
+            .publication(publication)
+            .volume(self.field("VL").map(leading_number).unwrap_or(0))
+            .year(year)
+            .doi(self.field("DO").unwrap_or(""))
+            .tags(&tags)
+            .doc_type(doc_type)
+            .extra(extra)
+            .build()
+            .map_err(|e| anyhow::anyhow!("Record {}: {}", self.describe(), e));
+    }
+
+    fn extra_fields(&self, date: Option<&str>) -> ExtraFields {
+        let text = |tag: &str| self.field(tag).unwrap_or("").to_string();
+        let pages = match (self.field("SP"), self.field("EP")) {
+            (Some(start), Some(end)) => format!("{}--{}", start, end),
+            (Some(start), None) => start.to_string(),
+            _ => String::new(),
+        };
+        let month = match date
+            .and_then(|d| d.split('/').nth(1))
+            .filter(|m| !m.is_empty())
+        {
+            Some(month) => match parse_month(month) {
+                Ok(month) => month,
+                Err(e) => {
+                    log::warn!("Record {}: {}", self.describe(), e);
+                    0
+                }
+            },
+            None => 0,
+        };
+        let editor = self
+            .values("A2")
+            .into_iter()
+            .chain(self.values("ED"))
+            .map(join_name)
+            .collect::<Vec<String>>()
+            .join(" and ");
+        return ExtraFields {
+            issue: text("IS"),
+            pages,
+            publisher: text("PB"),
+            edition: text("ET"),
+            isbn: text("SN"),
+            url: text("UR"),
+            institution: text("AD"),
+            genre: text("M3"),
+            editor,
+            booktitle: text("T2"),
+            month,
+            abstract_: self
+                .field("AB")
+                .or_else(|| self.field("N2"))
+                .unwrap_or("")
+                .to_string(),
+        };
+    }
+
+    fn find_file(&self, files_dir: &Path) -> anyhow::Result<PathBuf> {
+        for link in self.values("L1").into_iter().chain(self.values("L4")) {
+            let candidate = PathBuf::from(link.strip_prefix("file://").unwrap_or(link));
+            let path = if candidate.is_absolute() {
+                candidate
+            } else {
+                files_dir.join(candidate)
+            };
+            if path.is_file() {
+                return Ok(path);
+            }
+            log::debug!("Record {}: file {:?} not found", self.describe(), path);
+        }
+        if let Some(id) = self.field("ID") {
+            for file_type in FILE_TYPES.iter() {
+                for ext in file_type.extensions() {
+                    let path = files_dir.join(format!("{}.{}", id, ext));
+                    if path.is_file() {
+                        return Ok(path);
+                    }
+                }
+            }
+        }
+        return Err(anyhow::anyhow!(
+            "Record {}: no document file found from its L1 links or ID",
+            self.describe()
+        ));
+    }
+}
+
+impl std::fmt::Display for RisEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (tag, value) in self.fields.iter() {
+            // Line breaks are folded, except between paragraphs, which are written as
+            // continuation lines after a blank line
+            let value = value
+                .split("\n\n")
+                .map(|p| p.split_whitespace().collect::<Vec<&str>>().join(" "))
+                .filter(|p| !p.is_empty())
+                .collect::<Vec<String>>()
+                .join("\r\n\r\n");
+            write!(f, "{}  - {}\r\n", tag, value)?;
+        }
+        return write!(f, "ER  - \r\n");
+    }
+}
+
+/// RIS type written for a document type.
+fn ris_type(doc_type: DocType) -> &'static str {
+    return match doc_type {
+        DocType::Article => "JOUR",
+        DocType::Book => "BOOK",
+        DocType::Thesis => "THES",
+        DocType::Conference => "CONF",
+        DocType::Standard => "STAND",
+        DocType::Report => "RPRT",
+        DocType::Webpage => "ELEC",
+        DocType::Misc => "GEN",
+    };
+}
+
+/// Document type of a RIS type.
+fn doc_type(ris_type: &str) -> DocType {
+    return match ris_type.trim().to_uppercase().as_str() {
+        "JOUR" | "JFULL" | "MGZN" | "EJOUR" => DocType::Article,
+        "BOOK" | "EBOOK" | "CHAP" | "ECHAP" | "EDBOOK" => DocType::Book,
+        "THES" => DocType::Thesis,
+        "CONF" | "CPAPER" => DocType::Conference,
+        "STAND" => DocType::Standard,
+        "RPRT" => DocType::Report,
+        "ELEC" | "WEB" | "BLOG" => DocType::Webpage,
+        _ => DocType::Misc,
+    };
+}
+
+/// A name as written in `AU` lines: `Last, First, Suffix`.  Corporate names end with a comma
+/// so they are not split into first and last names when read back.
+fn ris_name(name: &Name) -> String {
+    let mut value = name.last.clone();
+    value.push(',');
+    if !name.first.is_empty() {
+        value.push(' ');
+        value.push_str(&name.first);
+    }
+    if !name.suffix.is_empty() {
+        value.push_str(", ");
+        value.push_str(&name.suffix);
+    }
+    return value;
+}
+
+/// Convert an `AU` value to the `Last, Suffix, First` order of BibTeX author fields.
+fn join_name(value: &str) -> String {
+    let parts = value.split(',').map(|p| p.trim()).collect::<Vec<&str>>();
+    return match parts.as_slice() {
+        [last, ""] => format!("{{{}}}", last),
+        [last, first, suffix] if !suffix.is_empty() => format!("{}, {}, {}", last, suffix, first),
+        _ => value.to_string(),
+    };
+}
+
+fn leading_number(value: &str) -> u16 {
+    return value
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .unwrap_or(0);
+}
+
+/// Render `docs` as RIS records.
+pub async fn to_ris(docs: &DocList, pool: &SqlitePool) -> anyhow::Result<String> {
+    let mut records = Vec::new();
+    for (doc, key) in docs.iter().zip(citation_keys(docs, pool).await?) {
+        let authors = DatabaseAuthor::for_doc(doc.id, pool)
+            .await?
+            .iter()
+            .map(|a| a.name())
+            .collect::<Vec<Name>>();
+        let file = Config::get().stored_file(&doc.stored_name());
+        records.push(RisEntry::from_doc(doc, &authors, &key, &file).to_string());
+    }
+    return Ok(records.join("\r\n"));
+}
+
+/// Parse the records of a RIS file.  Lines without a tag continue the value of the line
+/// before them.
+pub fn parse(input: &str) -> anyhow::Result<Vec<RisEntry>> {
+    let line_re = Regex::new(r"^([A-Z][A-Z0-9])\s{1,2}-(?: (.*))?$")?;
+    let mut entries = Vec::new();
+    let mut current: Option<RisEntry> = None;
+    // A blank line inside a record starts a new paragraph of a continued value
+    let mut paragraph = false;
+    for (number, line) in input.trim_start_matches('\u{feff}').lines().enumerate() {
+        let line = line.trim_end();
+        let (tag, value) = match line_re.captures(line) {
+            Some(captures) => (
+                captures[1].to_string(),
+                captures
+                    .get(2)
+                    .map_or("", |v| v.as_str())
+                    .trim()
+                    .to_string(),
+            ),
+            None => {
+                match current.as_mut().and_then(|e| e.fields.last_mut()) {
+                    Some(_) if line.trim().is_empty() => paragraph = true,
+                    Some((_, value)) => {
+                        value.push_str(if paragraph { "\n\n" } else { " " });
+                        value.push_str(line.trim());
+                        paragraph = false;
+                    }
+                    None => {}
+                }
+                continue;
+            }
+        };
+        paragraph = false;
+        match tag.as_str() {
+            "TY" => {
+                if current.is_some() {
+                    return Err(anyhow::anyhow!(
+                        "RIS line {}: record starts before the previous one ended with ER",
+                        number + 1
+                    ));
+                }
+                current = Some(RisEntry {
+                    fields: vec![(tag, value)],
+                });
+            }
+            "ER" => match current.take() {
+                Some(entry) => entries.push(entry),
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "RIS line {}: ER without a record",
+                        number + 1
+                    ))
+                }
+            },
+            _ => match current.as_mut() {
+                Some(entry) => entry.fields.push((tag, value)),
+                None => log::debug!("RIS line {}: ignoring {} outside a record", number + 1, tag),
+            },
+        }
+    }
+    if let Some(entry) = current {
+        log::warn!("RIS record {} is not terminated by ER", entry.describe());
+        entries.push(entry);
+    }
+    return Ok(entries);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{assert_same_document, authors, database_doc, PdfFile};
+
+    /// Export `doc` to RIS text and read it back.
+    fn round_trip(doc: &DatabaseDoc, authors: &[Name], file: &Path) -> Document {
+        let text = RisEntry::from_doc(doc, authors, "doe2020deep", file).to_string();
+        let entries = parse(&text).unwrap();
+        assert_eq!(entries.len(), 1, "{}", text);
+        return entries[0].to_document(Path::new("/nonexistent")).unwrap();
+    }
+
+    #[test]
+    fn article_round_trip_keeps_every_field() {
+        let file = PdfFile::new("ris-article");
+        let doc = database_doc(DocType::Article);
+        let imported = round_trip(&doc, &authors(), &file);
+        assert_same_document(&doc, &authors(), &imported, &file);
+    }
+
+    #[test]
+    fn book_round_trip_keeps_every_field() {
+        let file = PdfFile::new("ris-book");
+        let mut doc = database_doc(DocType::Book);
+        doc.publication = String::new();
+        doc.volume = 0;
+        doc.extra.edition = "2".to_string();
+        doc.extra.isbn = "978-0-201-89683-1".to_string();
+        doc.extra.booktitle = "Collected Cats".to_string();
+        doc.extra.pages = "7".to_string();
+        let imported = round_trip(&doc, &authors(), &file);
+        assert_same_document(&doc, &authors(), &imported, &file);
+    }
+
+    #[test]
+    fn thesis_institution_round_trips_through_publisher() {
+        let file = PdfFile::new("ris-thesis");
+        let mut doc = database_doc(DocType::Thesis);
+        doc.extra.publisher = String::new();
+        let text = RisEntry::from_doc(&doc, &authors(), "key", &file).to_string();
+        assert!(text.contains("PB  - University of Cats\r\n"));
+        let imported = round_trip(&doc, &authors(), &file);
+        assert_same_document(&doc, &authors(), &imported, &file);
+    }
+
+    #[test]
+    fn round_trip_without_optional_fields() {
+        let file = PdfFile::new("ris-minimal");
+        let mut doc = database_doc(DocType::Misc);
+        doc.year = 0;
+        doc.publication = String::new();
+        doc.volume = 0;
+        doc.tags = String::new();
+        doc.doi = String::new();
+        doc.extra = ExtraFields::default();
+        let imported = round_trip(&doc, &[], &file);
+        assert_same_document(&doc, &[], &imported, &file);
+    }
+
+    #[test]
+    fn parses_records_from_other_tools() {
+        let file = PdfFile::new("ris-foreign");
+        let text = format!(
+            "\u{feff}TY  - JOUR\n\
+             T1  - A Title\n\
+             A1  - Doe, Jane\n\
+             T2  - Some Journal\n\
+             Y1  - 2019/11/02/\n\
+             KW  - One; Two\n\
+             N2  - First line\n\
+             continued here\n\
+             L1  - file://{}\n\
+             ER  -\n",
+            file.display()
+        );
+        let entries = parse(&text).unwrap();
+        assert_eq!(entries.len(), 1);
+        let doc = entries[0].to_document(Path::new("/nonexistent")).unwrap();
+        assert_eq!(doc.title, "A Title");
+        assert_eq!(doc.author, "Doe, Jane");
+        assert_eq!(doc.publication, "Some Journal");
+        assert_eq!(doc.year, 2019);
+        assert_eq!(doc.extra.month, 11);
+        assert_eq!(doc.tags, "one,two");
+        assert_eq!(doc.extra.abstract_, "First line continued here");
+        assert_eq!(doc.extra.booktitle, "");
+        assert_eq!(doc.path, *file);
+    }
+
+    #[test]
+    fn rejects_unterminated_record_before_next() {
+        assert!(parse("TY  - JOUR\nTI  - A\nTY  - JOUR\nER  - \n").is_err());
+        assert!(parse("ER  - \n").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{dryrun, failpoint};
+use std::path::{Path, PathBuf};
+
+/// Hidden name next to `path` used while a change to it is not yet committed.  The
+/// extension is kept so the file can be read by format in the meantime.
+fn pending_path(path: &Path, prefix: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    return path.with_file_name(format!(".{}-{}", prefix, name));
+}
+
+/// A file copied into the store under a temporary name, so that a document record and its
+/// stored file can be committed together.  `persist` moves it to its final name right
+/// before the database transaction commits; dropping it without persisting removes the
+/// copy.  In a dry run nothing is copied and persisting only reports the copy.
+#[derive(Debug)]
+pub struct StagedFile {
+    temp: PathBuf,
+    dest: PathBuf,
+    persisted: bool,
+    dry_run: bool,
+}
+
+impl StagedFile {
+    pub fn copy(src: &Path, dest: &Path) -> anyhow::Result<Self> {
+        if dryrun::enabled() {
+            return Ok(Self {
+                temp: src.to_path_buf(),
+                dest: dest.to_path_buf(),
+                persisted: false,
+                dry_run: true,
+            });
+        }
+        let temp = pending_path(dest, "tmp");
+        failpoint::check("store:copy")?;
+        std::fs::copy(src, &temp)
+            .map_err(|e| anyhow::anyhow!("Could not store {:?} as {:?}: {}", src, temp, e))?;
+        return Ok(Self {
+            temp,
+            dest: dest.to_path_buf(),
+            persisted: false,
+            dry_run: false,
+        });
+    }
+
+    /// Where the copy is until it is persisted.
+    pub fn path(&self) -> &Path {
+        return &self.temp;
+    }
+
+    /// Move the copy to its final name, which is returned.
+    pub fn persist(mut self) -> anyhow::Result<PathBuf> {
+        if self.dry_run {
+            println!("would copy {:?} to {:?}", self.temp, self.dest);
+            return Ok(self.dest.clone());
+        }
+        failpoint::check("store:persist")?;
+        std::fs::rename(&self.temp, &self.dest)?;
+        self.persisted = true;
+        return Ok(self.dest.clone());
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.persisted && !self.dry_run && std::fs::remove_file(&self.temp).is_err() {
+            log::warn!("Could not remove {:?}", self.temp);
+        }
+    }
+}
+
+/// A stored file set aside under a temporary name while the records referring to it are
+/// deleted.  `finish` removes it once the deletion has committed; dropping it without
+/// finishing moves it back.  In a dry run the file stays in place and finishing only
+/// reports the removal.
+#[derive(Debug)]
+pub struct StagedRemoval {
+    path: PathBuf,
+    temp: PathBuf,
+    finished: bool,
+    dry_run: bool,
+}
+
+impl StagedRemoval {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        if dryrun::enabled() {
+            return Ok(Self {
+                path: path.to_path_buf(),
+                temp: path.to_path_buf(),
+                finished: false,
+                dry_run: true,
+            });
+        }
+        let temp = pending_path(path, "del");
+        failpoint::check("store:remove")?;
+        std::fs::rename(path, &temp)
+            .map_err(|e| anyhow::anyhow!("Could not remove {:?}: {}", path, e))?;
+        return Ok(Self {
+            path: path.to_path_buf(),
+            temp,
+            finished: false,
+            dry_run: false,
+        });
+    }
+
+    pub fn finish(mut self) {
+        self.finished = true;
+        if self.dry_run {
+            println!("would remove {:?}", self.path);
+        } else if std::fs::remove_file(&self.temp).is_err() {
+            log::warn!("Could not delete {:?}", self.temp);
+        } else {
+            log::info!("Deleted {:?}", self.path);
+        }
+    }
+}
+
+impl Drop for StagedRemoval {
+    fn drop(&mut self) {
+        if !self.finished && !self.dry_run && std::fs::rename(&self.temp, &self.path).is_err() {
+            log::warn!("Could not restore {:?} from {:?}", self.path, self.temp);
+        }
+    }
+}
+
+/// A stored file moved to another place in the store, such as the trash, while the records
+/// referring to it are changed.  `finish` keeps it there once the change has committed;
+/// dropping it without finishing moves it back.  In a dry run the file stays in place and
+/// finishing only reports the move.
+#[derive(Debug)]
+pub struct StagedMove {
+    src: PathBuf,
+    dest: PathBuf,
+    finished: bool,
+    dry_run: bool,
+}
+
+impl StagedMove {
+    pub fn new(src: &Path, dest: &Path) -> anyhow::Result<Self> {
+        let staged = Self {
+            src: src.to_path_buf(),
+            dest: dest.to_path_buf(),
+            finished: false,
+            dry_run: dryrun::enabled(),
+        };
+        if staged.dry_run {
+            return Ok(staged);
+        }
+        if dest.exists() {
+            return Err(anyhow::anyhow!(
+                "Could not move {:?}: {:?} exists",
+                src,
+                dest
+            ));
+        }
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        failpoint::check("store:move")?;
+        std::fs::rename(src, dest)
+            .map_err(|e| anyhow::anyhow!("Could not move {:?} to {:?}: {}", src, dest, e))?;
+        return Ok(staged);
+    }
+
+    pub fn finish(mut self) {
+        self.finished = true;
+        if self.dry_run {
+            println!("would move {:?} to {:?}", self.src, self.dest);
+        } else {
+            log::info!("Moved {:?} to {:?}", self.src, self.dest);
+        }
+    }
+}
+
+impl Drop for StagedMove {
+    fn drop(&mut self) {
+        if !self.finished && !self.dry_run && std::fs::rename(&self.dest, &self.src).is_err() {
+            log::warn!("Could not move {:?} back from {:?}", self.src, self.dest);
+        }
+    }
+}
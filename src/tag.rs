@@ -1,4 +1,6 @@
-use sqlx::{FromRow, SqlitePool};
+use crate::document::commit;
+use crate::failpoint;
+use sqlx::{Executor, FromRow, Sqlite, SqliteConnection, SqlitePool};
 
 /// A tag struct for representing query results.
 /// Guaranteed to be complete and represent a valid row
@@ -36,7 +38,10 @@ impl DatabaseTag {
         .await?);
     }
 
-    pub async fn from_value(value: &str, pool: &SqlitePool) -> anyhow::Result<Option<Self>> {
+    pub async fn from_value<'e, E>(value: &str, executor: E) -> anyhow::Result<Option<Self>>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
         return Ok(sqlx::query_as::<_, Self>(
             r#"
             SELECT * FROM tags
@@ -44,7 +49,7 @@ impl DatabaseTag {
             "#,
         )
         .bind(value)
-        .fetch_optional(pool)
+        .fetch_optional(executor)
         .await?);
     }
 
@@ -55,6 +60,7 @@ impl DatabaseTag {
                 return Ok(dbt);
             }
             None => {
+                let mut tx = pool.begin().await?;
                 sqlx::query(
                     r#"
                     INSERT INTO tags (value)
@@ -62,20 +68,36 @@ impl DatabaseTag {
                     "#,
                 )
                 .bind(&tag.value)
-                .execute(pool)
+                .execute(&mut *tx)
                 .await?;
-                return match Self::from_value(&tag.value, pool).await? {
-                    Some(dbt) => Ok(dbt),
-                    None => Err(anyhow::anyhow!(
-                        "Failed to insert tag with value {}",
-                        &tag.value
-                    )),
+                let dbt = match Self::from_value(&tag.value, &mut *tx).await? {
+                    Some(dbt) => dbt,
+                    None => {
+                        return Err(anyhow::anyhow!(
+                            "Failed to insert tag with value {}",
+                            &tag.value
+                        ))
+                    }
                 };
+                commit(tx, "tag:commit", pool).await?;
+                return Ok(dbt);
             }
         };
     }
 
     pub async fn delete(self, pool: &SqlitePool) -> anyhow::Result<()> {
+        // Remove the tag from every document before removing the tag itself
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM document_tags
+            WHERE tag_id=?1
+            "#,
+        )
+        .bind(self.id)
+        .execute(&mut *tx)
+        .await?;
+        failpoint::check("untag:row")?;
         sqlx::query(
             r#"
             DELETE FROM tags
@@ -83,13 +105,77 @@ impl DatabaseTag {
             "#,
         )
         .bind(&self.value)
-        .execute(pool)
+        .execute(&mut *tx)
         .await?;
+        commit(tx, "untag:commit", pool).await?;
         return Ok(());
     }
 
-    pub async fn from_tag(tag: Tag, pool: &SqlitePool) -> anyhow::Result<Self> {
-        return match Self::from_value(&tag.value, pool).await? {
+    /// Rename the tag.  Documents reference tags by id, so this is a single row update unless
+    /// `new_value` already exists, in which case the two tags are merged.
+    pub async fn rename(self, new_value: &str, pool: &SqlitePool) -> anyhow::Result<Self> {
+        let new_value = new_value.trim().to_lowercase();
+        let mut tx = pool.begin().await?;
+        let renamed = match Self::from_value(&new_value, &mut *tx).await? {
+            Some(existing) if existing.id == self.id => return Ok(existing),
+            Some(existing) => {
+                log::info!("Merging tag {:?} into {:?}", self.value, existing.value);
+                sqlx::query(
+                    r#"
+                    UPDATE OR IGNORE document_tags
+                    SET tag_id=?2
+                    WHERE tag_id=?1
+                    "#,
+                )
+                .bind(self.id)
+                .bind(existing.id)
+                .execute(&mut *tx)
+                .await?;
+                // Links left over belong to documents that already had both tags
+                sqlx::query(
+                    r#"
+                    DELETE FROM document_tags
+                    WHERE tag_id=?1
+                    "#,
+                )
+                .bind(self.id)
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query(
+                    r#"
+                    DELETE FROM tags
+                    WHERE id=?1
+                    "#,
+                )
+                .bind(self.id)
+                .execute(&mut *tx)
+                .await?;
+                existing
+            }
+            None => {
+                sqlx::query(
+                    r#"
+                    UPDATE tags
+                    SET value=?2
+                    WHERE id=?1
+                    "#,
+                )
+                .bind(self.id)
+                .bind(&new_value)
+                .execute(&mut *tx)
+                .await?;
+                Self {
+                    id: self.id,
+                    value: new_value,
+                }
+            }
+        };
+        commit(tx, "rename:commit", pool).await?;
+        return Ok(renamed);
+    }
+
+    pub async fn from_tag(tag: Tag, conn: &mut SqliteConnection) -> anyhow::Result<Self> {
+        return match Self::from_value(&tag.value, &mut *conn).await? {
             Some(dbt) => Ok(dbt),
             None => {
                 // Add entry to database
@@ -100,9 +186,9 @@ impl DatabaseTag {
                         "#,
                 )
                 .bind(&tag.value)
-                .execute(pool)
+                .execute(&mut *conn)
                 .await?;
-                return match Self::from_value(&tag.value, pool).await? {
+                return match Self::from_value(&tag.value, &mut *conn).await? {
                     Some(dbt) => Ok(dbt),
                     None => Err(anyhow::anyhow!(
                         "Failed to add tag with value: {}",
@@ -169,9 +255,7 @@ pub struct TagList(pub Vec<DatabaseTag>);
 
 impl std::fmt::Display for TagList {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        return self.0.iter().fold(Ok(()), |result, tag| {
-            result.and_then(|_| writeln!(f, "{}", tag))
-        });
+        return self.0.iter().try_fold((), |_, tag| writeln!(f, "{}", tag));
     }
 }
 
@@ -218,7 +302,7 @@ impl std::convert::From<&str> for TagInputList {
     fn from(value: &str) -> Self {
         let tags = value
             .split(',')
-            .filter(|v| *v != "")
+            .filter(|v| !v.is_empty())
             .map(|v| v.trim().to_lowercase().to_string())
             .collect();
         return Self(tags);
@@ -250,8 +334,18 @@ impl TagInputList {
     }
 
     pub async fn delete_from_db(&self, pool: &SqlitePool) -> anyhow::Result<()> {
+        let mut tx = pool.begin().await?;
         for value in self.0.iter() {
             log::info!("Value to be deleted: {:?}", value);
+            sqlx::query(
+                r#"
+            DELETE FROM document_tags
+            WHERE tag_id IN (SELECT id FROM tags WHERE value=?)
+            "#,
+            )
+            .bind(value)
+            .execute(&mut *tx)
+            .await?;
             sqlx::query(
                 r#"
             DELETE FROM tags
@@ -259,9 +353,90 @@ impl TagInputList {
             "#,
             )
             .bind(value)
-            .execute(pool)
+            .execute(&mut *tx)
             .await?;
         }
+        commit(tx, "untag:commit", pool).await?;
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{count, document, library};
+    use crate::document::{DatabaseDoc, DuplicatePolicy};
+
+    async fn tagged(name: &str, tags: &str, pool: &SqlitePool) -> DatabaseDoc {
+        let mut doc = document(name);
+        doc.tags = tags.to_string();
+        return DatabaseDoc::from_insert(doc, DuplicatePolicy::Reject, pool)
+            .await
+            .unwrap();
+    }
+
+    async fn tags_of(doc: &DatabaseDoc, pool: &SqlitePool) -> String {
+        return DatabaseDoc::from_id(doc.id, pool)
+            .await
+            .unwrap()
+            .unwrap()
+            .tags;
+    }
+
+    #[tokio::test]
+    async fn rename_into_existing_tag_leaves_a_single_link() {
+        let pool = library("tag-rename").await;
+        let both = tagged("tag-rename-both", "one,two", &pool).await;
+        let one = tagged("tag-rename-one", "one", &pool).await;
+        let dbt = DatabaseTag::from_value("one", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        let renamed = dbt.rename(" TWO ", &pool).await.unwrap();
+        assert_eq!(renamed.value, "two");
+        assert_eq!(count("tags", &pool).await, 1);
+        assert_eq!(count("document_tags", &pool).await, 2);
+        assert_eq!(tags_of(&both, &pool).await, "two");
+        assert_eq!(tags_of(&one, &pool).await, "two");
+    }
+
+    #[tokio::test]
+    async fn delete_removes_tag_from_documents() {
+        let pool = library("tag-delete").await;
+        let doc = tagged("tag-delete", "one,two", &pool).await;
+        Tag::new("one").delete(&pool).await.unwrap();
+        assert_eq!(count("tags", &pool).await, 1);
+        assert_eq!(tags_of(&doc, &pool).await, "two");
+        TagInputList::from("two")
+            .delete_from_db(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count("tags", &pool).await, 0);
+        assert_eq!(count("document_tags", &pool).await, 0);
+    }
+
+    async fn delete_fails_at(failpoint: &'static str) {
+        let pool = library(&format!("tag-{}", failpoint)).await;
+        tagged(&format!("tag-{}", failpoint), "one,two", &pool).await;
+        let dbt = DatabaseTag::from_value("one", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        failpoint::arm(failpoint);
+        let result = dbt.delete(&pool).await;
+        failpoint::disarm();
+        assert!(result.is_err());
+        assert_eq!(count("tags", &pool).await, 2);
+        assert_eq!(count("document_tags", &pool).await, 2);
+    }
+
+    #[tokio::test]
+    async fn delete_rolls_back_when_row_fails() {
+        delete_fails_at("untag:row").await;
+    }
+
+    #[tokio::test]
+    async fn delete_rolls_back_when_commit_fails() {
+        delete_fails_at("untag:commit").await;
+    }
+}
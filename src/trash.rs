@@ -0,0 +1,98 @@
+use crate::document::{DatabaseDoc, DocList, DOC_SELECT};
+use sqlx::SqlitePool;
+
+/// How long a document has been in the trash, written as a number and a unit: `12h`, `30d`
+/// or `2w`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Age {
+    pub seconds: u64,
+}
+
+impl std::str::FromStr for Age {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let unit = match s.chars().last() {
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            Some('w') => 7 * 24 * 60 * 60,
+            _ => return Err(anyhow::anyhow!("Age must end in h, d or w: {:?}", s)),
+        };
+        let count = s[..s.len() - 1]
+            .parse::<u64>()
+            .map_err(|_| anyhow::anyhow!("Invalid age: {:?}", s))?;
+        return match count.checked_mul(unit) {
+            Some(seconds) => Ok(Self { seconds }),
+            None => Err(anyhow::anyhow!("Age is too long: {:?}", s)),
+        };
+    }
+}
+
+/// Delete the documents in the trash for good, or only those that were moved there at least
+/// `older_than` ago.  Returns the number of documents deleted.
+pub async fn empty(older_than: Option<Age>, pool: &SqlitePool) -> anyhow::Result<usize> {
+    let docs = match older_than {
+        Some(age) => DocList(
+            sqlx::query_as::<_, DatabaseDoc>(&format!(
+                "{} WHERE documents.deleted_at <= datetime('now', ?1) ORDER BY documents.id",
+                DOC_SELECT
+            ))
+            .bind(format!("-{} seconds", age.seconds))
+            .fetch_all(pool)
+            .await?,
+        ),
+        None => DocList::trashed(pool).await?,
+    };
+    let count = docs.len();
+    for doc in docs.0.into_iter() {
+        doc.delete(pool).await?;
+    }
+    return Ok(count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::{library, stored_document};
+
+    #[tokio::test]
+    async fn empty_only_deletes_documents_older_than_age() {
+        let pool = library("trash-empty").await;
+        let (old, _) = stored_document("trash-empty-old", &pool).await;
+        let (new, _) = stored_document("trash-empty-new", &pool).await;
+        let (old_id, new_id) = (old.id, new.id);
+        old.trash(&pool).await.unwrap();
+        new.trash(&pool).await.unwrap();
+        sqlx::query("UPDATE documents SET deleted_at = datetime('now', '-10 days') WHERE id=?1")
+            .bind(old_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let old = DatabaseDoc::from_id(old_id, &pool).await.unwrap().unwrap();
+        let old_path = old.stored_file();
+
+        let age = "1w".parse::<Age>().unwrap();
+        assert_eq!(empty(Some(age), &pool).await.unwrap(), 1);
+        assert!(DatabaseDoc::from_id(old_id, &pool).await.unwrap().is_none());
+        assert!(!old_path.exists());
+        let trashed = DocList::trashed(&pool).await.unwrap();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed.0[0].id, new_id);
+        assert!(trashed.0[0].is_stored());
+
+        assert_eq!(empty(None, &pool).await.unwrap(), 1);
+        assert_eq!(DocList::trashed(&pool).await.unwrap().len(), 0);
+    }
+
+    #[test]
+    fn age_parses_hours_days_and_weeks() {
+        let seconds = |s: &str| s.parse::<Age>().unwrap().seconds;
+        assert_eq!(seconds("12h"), 12 * 60 * 60);
+        assert_eq!(seconds(" 30d "), 30 * 24 * 60 * 60);
+        assert_eq!(seconds("2w"), 2 * 7 * 24 * 60 * 60);
+        assert_eq!(seconds("0d"), 0);
+        for invalid in ["", "30", "d", "-1d", "1.5d", "3m", "99999999999999999w"] {
+            assert!(invalid.parse::<Age>().is_err(), "{:?}", invalid);
+        }
+    }
+}